@@ -0,0 +1,15 @@
+#![no_main]
+
+use http_client::fuzz;
+use libfuzzer_sys::fuzz_target;
+
+// The parsers every byte off the network goes through: a response read with
+// read_response, with the multipart body in it if any, a chunked body and a
+// multipart body on their own, and an upload's final reply. Errors are fine;
+// a panic is a crash.
+fuzz_target!(|data: &[u8]| {
+    fuzz::response(data);
+    fuzz::chunked_body(data);
+    fuzz::multipart_body(data);
+    fuzz::final_response(data);
+});
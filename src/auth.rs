@@ -0,0 +1,331 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use md5::Md5;
+use sha2::{Digest, Sha256, Sha512_256};
+
+use crate::netrc::Netrc;
+use crate::url::Url;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub user: String,
+    pub password: String,
+}
+
+// Authentication state shared by every clone of a Client: the credentials and
+// the last challenge answered, so chunk requests authenticate up front instead
+// of each collecting a 401 first.
+#[derive(Clone, Default)]
+pub struct Auth {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    credentials: Option<Credentials>,
+    // Looked up by host when no credentials were given.
+    netrc: Option<Netrc>,
+    // Sent unprompted on every request to `origin`.
+    bearer: Option<String>,
+    // Origin that sent the challenge, or that the bearer token is for;
+    // credentials never go anywhere else.
+    origin: String,
+    // The challenge being answered and the credentials answering it.
+    challenge: Option<(Challenge, Credentials)>,
+    // Digest nonce count, one per request made with the cached nonce.
+    nonce_count: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Challenge {
+    Basic,
+    Digest(DigestChallenge),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DigestChallenge {
+    realm: String,
+    nonce: String,
+    opaque: Option<String>,
+    algorithm: Algorithm,
+    session: bool,
+    qop: Option<Qop>,
+    userhash: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Algorithm {
+    Md5,
+    Sha256,
+    Sha512_256,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Qop {
+    Auth,
+    AuthInt,
+}
+
+impl Auth {
+    pub fn new(credentials: Option<Credentials>, netrc: Option<Netrc>) -> Auth {
+        Auth { state: Arc::new(Mutex::new(State { credentials, netrc, ..State::default() })) }
+    }
+
+    // A bearer token for `url`'s origin. Redirects elsewhere, e.g. to a
+    // presigned object store URL, go out without it.
+    pub fn bearer(token: String, url: &Url) -> Auth {
+        Auth { state: Arc::new(Mutex::new(State { bearer: Some(token), origin: url.origin(), ..State::default() })) }
+    }
+
+    // Takes the WWW-Authenticate values of a 401 from `url` and keeps the
+    // strongest challenge this client can answer. Returns true when the
+    // request should be sent again.
+    pub fn challenge(&self, url: &Url, www_authenticate: &[impl AsRef<str>]) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let credentials = match (&state.credentials, &state.netrc) {
+            (Some(credentials), _) => credentials.clone(),
+            (None, Some(netrc)) => match netrc.lookup(&url.host) {
+                Some(credentials) => credentials,
+                None => return false,
+            },
+            (None, None) => return false,
+        };
+        let challenge = www_authenticate
+            .iter()
+            .flat_map(|value| parse_challenges(value.as_ref()))
+            .filter_map(|(scheme, params)| Challenge::from_params(&scheme, &params))
+            .max_by_key(Challenge::strength);
+        match challenge {
+            Some(challenge) => {
+                state.origin = url.origin();
+                state.challenge = Some((challenge, credentials));
+                state.nonce_count = 0;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Authorization header value for a request to `url`, once a challenge
+    // from its origin has been seen.
+    pub fn authorization(&self, method: &str, url: &Url) -> Option<String> {
+        self.authorization_with_cnonce(method, url, &cnonce())
+    }
+
+    // As authorization, with the client nonce given by the caller.
+    pub fn authorization_with_cnonce(&self, method: &str, url: &Url, cnonce: &str) -> Option<String> {
+        let mut state = self.state.lock().unwrap();
+        if state.origin != url.origin() {
+            return None;
+        }
+        if let Some(token) = &state.bearer {
+            return Some(format!("Bearer {}", token));
+        }
+        let (challenge, credentials) = state.challenge.clone()?;
+        match challenge {
+            Challenge::Basic => {
+                Some(format!("Basic {}", BASE64.encode(format!("{}:{}", credentials.user, credentials.password))))
+            }
+            Challenge::Digest(digest) => {
+                state.nonce_count += 1;
+                Some(digest.respond(&credentials, method, &url.request_target(), state.nonce_count, cnonce))
+            }
+        }
+    }
+}
+
+impl Challenge {
+    fn from_params(scheme: &str, params: &[(String, String)]) -> Option<Challenge> {
+        let param = |name: &str| params.iter().find(|(key, _)| key == name).map(|(_, value)| value.clone());
+        if scheme.eq_ignore_ascii_case("basic") {
+            return Some(Challenge::Basic);
+        }
+        if !scheme.eq_ignore_ascii_case("digest") {
+            return None;
+        }
+        let algorithm = param("algorithm").unwrap_or_else(|| "MD5".to_string()).to_uppercase();
+        let (name, session) = match algorithm.strip_suffix("-SESS") {
+            Some(name) => (name, true),
+            None => (algorithm.as_str(), false),
+        };
+        let algorithm = match name {
+            "MD5" => Algorithm::Md5,
+            "SHA-256" => Algorithm::Sha256,
+            "SHA-512-256" => Algorithm::Sha512_256,
+            _ => return None,
+        };
+        // Only "auth" and "auth-int" exist; without qop the RFC 2069 form is used.
+        let qop = match param("qop") {
+            Some(options) => {
+                let options: Vec<String> = options.split(',').map(|option| option.trim().to_lowercase()).collect();
+                if options.iter().any(|option| option == "auth") {
+                    Some(Qop::Auth)
+                } else if options.iter().any(|option| option == "auth-int") {
+                    Some(Qop::AuthInt)
+                } else {
+                    return None;
+                }
+            }
+            None => None,
+        };
+        Some(Challenge::Digest(DigestChallenge {
+            realm: param("realm").unwrap_or_default(),
+            nonce: param("nonce")?,
+            opaque: param("opaque"),
+            algorithm,
+            session,
+            qop,
+            userhash: param("userhash").is_some_and(|value| value.eq_ignore_ascii_case("true")),
+        }))
+    }
+
+    fn strength(&self) -> Option<Algorithm> {
+        match self {
+            Challenge::Basic => None,
+            Challenge::Digest(digest) => Some(digest.algorithm),
+        }
+    }
+}
+
+impl DigestChallenge {
+    // Builds the Authorization value of RFC 7616 section 3.4.
+    fn respond(&self, credentials: &Credentials, method: &str, uri: &str, nonce_count: u32, cnonce: &str) -> String {
+        let h = |data: String| self.algorithm.hash(data.as_bytes());
+        let mut ha1 = h(format!("{}:{}:{}", credentials.user, self.realm, credentials.password));
+        if self.session {
+            ha1 = h(format!("{}:{}:{}", ha1, self.nonce, cnonce));
+        }
+        // Requests never carry a body, so auth-int hashes the empty string.
+        let ha2 = match self.qop {
+            Some(Qop::AuthInt) => h(format!("{}:{}:{}", method, uri, h(String::new()))),
+            _ => h(format!("{}:{}", method, uri)),
+        };
+        let nc = format!("{:08x}", nonce_count);
+        let response = match self.qop {
+            Some(qop) => h(format!("{}:{}:{}:{}:{}:{}", ha1, self.nonce, nc, cnonce, qop.name(), ha2)),
+            None => h(format!("{}:{}:{}", ha1, self.nonce, ha2)),
+        };
+        let username = if self.userhash {
+            h(format!("{}:{}", credentials.user, self.realm))
+        } else {
+            credentials.user.clone()
+        };
+
+        let mut header = format!(
+            "Digest username={}, realm={}, uri={}, algorithm={}{}, nonce={}",
+            quote(&username),
+            quote(&self.realm),
+            quote(uri),
+            self.algorithm.name(),
+            if self.session { "-sess" } else { "" },
+            quote(&self.nonce)
+        );
+        if let Some(qop) = self.qop {
+            header.push_str(&format!(", qop={}, nc={}, cnonce={}", qop.name(), nc, quote(cnonce)));
+        }
+        header.push_str(&format!(", response={}", quote(&response)));
+        if let Some(opaque) = &self.opaque {
+            header.push_str(&format!(", opaque={}", quote(opaque)));
+        }
+        if self.userhash {
+            header.push_str(", userhash=true");
+        }
+        header
+    }
+}
+
+impl Algorithm {
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "MD5",
+            Algorithm::Sha256 => "SHA-256",
+            Algorithm::Sha512_256 => "SHA-512-256",
+        }
+    }
+
+    fn hash(self, data: &[u8]) -> String {
+        let digest = match self {
+            Algorithm::Md5 => Md5::digest(data).to_vec(),
+            Algorithm::Sha256 => Sha256::digest(data).to_vec(),
+            Algorithm::Sha512_256 => Sha512_256::digest(data).to_vec(),
+        };
+        digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+impl Qop {
+    fn name(self) -> &'static str {
+        match self {
+            Qop::Auth => "auth",
+            Qop::AuthInt => "auth-int",
+        }
+    }
+}
+
+// Splits a WWW-Authenticate value into challenges: a scheme followed by
+// comma-separated name=value parameters whose values may be quoted.
+// Parameter names are lowercased.
+fn parse_challenges(value: &str) -> Vec<(String, Vec<(String, String)>)> {
+    let is_tchar = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+    let mut challenges: Vec<(String, Vec<(String, String)>)> = Vec::new();
+    let mut rest = value;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+        let end = rest.find(|c: char| !is_tchar(c)).unwrap_or(rest.len());
+        if end == 0 {
+            return challenges;
+        }
+        let token = &rest[..end];
+        let after = rest[end..].trim_start();
+        match after.strip_prefix('=') {
+            Some(value) => {
+                let value = value.trim_start();
+                let (value, remaining) = match value.strip_prefix('"') {
+                    Some(quoted) => unquote(quoted),
+                    None => {
+                        let end = value.find(',').unwrap_or(value.len());
+                        (value[..end].trim().to_string(), &value[end..])
+                    }
+                };
+                if let Some((_, params)) = challenges.last_mut() {
+                    params.push((token.to_lowercase(), value));
+                }
+                rest = remaining;
+            }
+            None => {
+                challenges.push((token.to_string(), Vec::new()));
+                rest = after;
+            }
+        }
+    }
+}
+
+// Reads a quoted-string body (after the opening quote), returning its value
+// and what follows the closing quote.
+fn unquote(input: &str) -> (String, &str) {
+    let mut value = String::new();
+    let mut chars = input.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => value.extend(chars.next().map(|(_, escaped)| escaped)),
+            '"' => return (value, &input[i + 1..]),
+            c => value.push(c),
+        }
+    }
+    (value, "")
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// A client nonce that differs between requests and runs.
+fn cnonce() -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_nanos()));
+    format!("{:016x}", hasher.finish())
+}
@@ -0,0 +1,96 @@
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tracing::{info, info_span};
+
+use crate::cli::Bench;
+use crate::client::Client;
+use crate::config::DownloadConfig;
+use crate::progress;
+use crate::url::Url;
+use crate::{download, get_resource_info};
+
+// The downloads of one setting.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trial {
+    pub threads: usize,
+    pub chunk_size: usize,
+    pub times: Vec<Duration>,
+}
+
+impl Trial {
+    // Bytes per second over the average of the runs.
+    pub fn throughput(&self, total_size: usize) -> f64 {
+        let seconds = self.times.iter().map(Duration::as_secs_f64).sum::<f64>() / self.times.len().max(1) as f64;
+        if seconds > 0.0 {
+            total_size as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+}
+
+// Downloads the URL with every thread count and chunk size in turn, then
+// lists the throughput of each. Every run must give the same digest: one
+// that differs means the resource changed, and the times compare nothing.
+pub fn run(url: &Url, client: &Client, mut config: DownloadConfig, bench: &Bench) -> io::Result<()> {
+    let (_, resource) = get_resource_info(url, &config.client(client))?;
+    // Nothing is cached between runs, or later ones would not download.
+    let cache = Mutex::new(None);
+    let mut digest = None;
+    let mut trials = Vec::new();
+    for &threads in &bench.threads {
+        for &chunk_size in &bench.chunk_sizes {
+            let _trial = info_span!("bench", threads, chunk_size).entered();
+            config.num_threads = Some(threads);
+            config.chunk_size = chunk_size;
+            let mut times = Vec::new();
+            for _ in 0..bench.runs {
+                let started = Instant::now();
+                let hash = download(url, client, &config, &cache)?;
+                times.push(started.elapsed());
+                match &digest {
+                    Some(digest) if *digest != hash => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("Benchmark runs gave different digests ({} and {}); the resource changed", digest, hash),
+                        ));
+                    }
+                    Some(_) => {}
+                    None => digest = Some(hash),
+                }
+            }
+            trials.push(Trial { threads, chunk_size, times });
+        }
+    }
+    for line in report(url, resource.total_size, bench.runs, &trials) {
+        info!("{}", line);
+    }
+    Ok(())
+}
+
+pub fn report(url: &Url, total_size: usize, runs: usize, trials: &[Trial]) -> Vec<String> {
+    let mut lines = vec![
+        format!("Benchmark of {} ({} bytes, {} run(s) per setting):", url, total_size, runs),
+        format!("{:>9} {:>11} {:>13}", "threads", "chunk size", "throughput"),
+    ];
+    for trial in trials {
+        lines.push(format!(
+            "{:>9} {:>11} {:>13}",
+            trial.threads,
+            progress::bytes(trial.chunk_size as f64),
+            format!("{}/s", progress::bytes(trial.throughput(total_size)))
+        ));
+    }
+    let fastest = trials.iter().max_by(|a, b| a.throughput(total_size).total_cmp(&b.throughput(total_size)));
+    if let Some(trial) = fastest {
+        lines.push(format!(
+            "Fastest: {} threads with {} chunks, {}/s",
+            trial.threads,
+            progress::bytes(trial.chunk_size as f64),
+            progress::bytes(trial.throughput(total_size))
+        ));
+    }
+    lines
+}
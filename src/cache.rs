@@ -0,0 +1,75 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// A finished download remembered by --cache. `decoded` records whether the
+// hash is of the decoded or the raw bytes, since --no-decompress changes it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub url: String,
+    pub decoded: bool,
+    pub validator: String,
+    pub hash: String,
+}
+
+// Validators and hashes of earlier downloads, one tab-separated line each.
+pub struct Cache {
+    path: PathBuf,
+    entries: Vec<Entry>,
+}
+
+impl Cache {
+    // A missing file is an empty cache; it is created on the first save.
+    pub fn load(path: &Path) -> io::Result<Cache> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Cache { path: path.to_path_buf(), entries: text.lines().filter_map(parse_line).collect() })
+    }
+
+    pub fn get(&self, url: &str, decoded: bool) -> Option<&Entry> {
+        self.entries.iter().find(|entry| entry.url == url && entry.decoded == decoded)
+    }
+
+    pub fn insert(&mut self, entry: Entry) {
+        self.entries.retain(|old| old.url != entry.url || old.decoded != entry.decoded);
+        self.entries.push(entry);
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let text: String = self
+            .entries
+            .iter()
+            .map(|entry| {
+                let mode = if entry.decoded { "decoded" } else { "raw" };
+                format!("{}\t{}\t{}\t{}\n", entry.url, mode, entry.validator, entry.hash)
+            })
+            .collect();
+        fs::write(&self.path, text)
+    }
+}
+
+// The request header that turns the probe into a conditional request: ETags
+// go in If-None-Match, Last-Modified dates in If-Modified-Since.
+pub fn conditional_header(validator: &str) -> (&'static str, &str) {
+    if validator.starts_with('"') {
+        ("If-None-Match", validator)
+    } else {
+        ("If-Modified-Since", validator)
+    }
+}
+
+fn parse_line(line: &str) -> Option<Entry> {
+    let mut fields = line.split('\t');
+    let url = fields.next()?.to_string();
+    let decoded = match fields.next()? {
+        "decoded" => true,
+        "raw" => false,
+        _ => return None,
+    };
+    let validator = fields.next()?.to_string();
+    let hash = fields.next()?.to_string();
+    Some(Entry { url, decoded, validator, hash })
+}
@@ -0,0 +1,66 @@
+use std::io;
+use std::sync::Arc;
+
+type ProgressCallback = Arc<dyn Fn(usize, usize) + Send + Sync>;
+type ChunkCallback = Arc<dyn Fn((usize, usize)) + Send + Sync>;
+type RetryCallback = Arc<dyn Fn((usize, usize), &io::Error) + Send + Sync>;
+type CompleteCallback = Arc<dyn Fn(&str) + Send + Sync>;
+
+// What an embedding application is told about a download as it runs, to
+// drive its own UI. Ranges are inclusive byte offsets in the data, like the
+// queue's; chunk and retry callbacks run on the thread or task that fetched
+// them, progress and completion on the one hashing.
+#[derive(Clone, Default)]
+pub struct Callbacks {
+    progress: Option<ProgressCallback>,
+    chunk_complete: Option<ChunkCallback>,
+    retry: Option<RetryCallback>,
+    complete: Option<CompleteCallback>,
+}
+
+impl Callbacks {
+    pub fn on_progress(&mut self, callback: impl Fn(usize, usize) + Send + Sync + 'static) {
+        self.progress = Some(Arc::new(callback));
+    }
+
+    pub fn on_chunk_complete(&mut self, callback: impl Fn((usize, usize)) + Send + Sync + 'static) {
+        self.chunk_complete = Some(Arc::new(callback));
+    }
+
+    pub fn on_retry(&mut self, callback: impl Fn((usize, usize), &io::Error) + Send + Sync + 'static) {
+        self.retry = Some(Arc::new(callback));
+    }
+
+    pub fn on_complete(&mut self, callback: impl Fn(&str) + Send + Sync + 'static) {
+        self.complete = Some(Arc::new(callback));
+    }
+
+    // Bytes downloaded so far, counting those an interrupted run left, out
+    // of the total.
+    pub fn progress(&self, done: usize, total: usize) {
+        if let Some(callback) = &self.progress {
+            callback(done, total);
+        }
+    }
+
+    // `bytes` arrived at `start`.
+    pub fn chunk_complete(&self, start: usize, bytes: usize) {
+        if let Some(callback) = self.chunk_complete.as_ref().filter(|_| bytes > 0) {
+            callback((start, start + bytes - 1));
+        }
+    }
+
+    // A request for `range` failed with `error` and is asked for again.
+    pub fn retry(&self, range: (usize, usize), error: &io::Error) {
+        if let Some(callback) = &self.retry {
+            callback(range, error);
+        }
+    }
+
+    // The download is verified and saved, with the hex digest it returns.
+    pub fn complete(&self, hash: &str) {
+        if let Some(callback) = &self.complete {
+            callback(hash);
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+#[cfg(unix)]
+use std::sync::OnceLock;
+
+use crate::error::DownloadError;
+
+// Exit status of a run stopped by Ctrl-C, as a shell reports one killed by
+// SIGINT.
+pub const EXIT_CODE: u8 = 130;
+
+// Lets a host application stop a download from any thread. Clones share the
+// flag, which the download checks between requests, before each read and
+// while it waits for chunks; it then ends with DownloadError::Cancelled,
+// saving how far it got for --continue as --max-time does.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    // The error a cancelled download ends with.
+    pub fn cancelled(&self, reason: String) -> io::Error {
+        DownloadError::Cancelled(format!("Download cancelled: {}", reason)).into()
+    }
+
+    // Fails once cancelled, before starting on `what`.
+    pub fn check(&self, what: &str) -> io::Result<()> {
+        if self.is_cancelled() {
+            return Err(self.cancelled(format!("stopped before {}", what)));
+        }
+        Ok(())
+    }
+}
+
+pub fn is_cancelled(e: &io::Error) -> bool {
+    matches!(DownloadError::of(e), Some(DownloadError::Cancelled(_)))
+}
+
+#[cfg(unix)]
+static INTERRUPTED: OnceLock<CancellationToken> = OnceLock::new();
+
+// Cancels `token` on the first Ctrl-C, so the run stops cleanly; the handler
+// then steps aside and a second Ctrl-C kills the process as usual.
+#[cfg(unix)]
+pub fn cancel_on_interrupt(token: &CancellationToken) {
+    extern "C" fn interrupt(_: libc::c_int) {
+        if let Some(token) = INTERRUPTED.get() {
+            token.cancel();
+        }
+        // SAFETY: signal() is async-signal-safe.
+        unsafe {
+            libc::signal(libc::SIGINT, libc::SIG_DFL);
+        }
+    }
+    if INTERRUPTED.set(token.clone()).is_ok() {
+        // SAFETY: the handler only stores to an atomic and calls signal().
+        unsafe {
+            libc::signal(libc::SIGINT, interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t);
+        }
+    }
+}
@@ -0,0 +1,275 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::connector::Stream;
+use crate::error::DownloadError;
+use crate::url::Url;
+use crate::warc::Warc;
+
+// First line of every cassette file.
+const MAGIC: &str = "# http_client cassette v1";
+
+// The raw HTTP/1.1 exchanges of a run, for --record and --replay. Each
+// exchange is what a connection wrote up to its next read, then what it read
+// up to its next write, as a pair of records:
+//
+//     > <connection> <origin> <length>\n<request bytes>\n
+//     < <connection> <origin> <length>\n<response bytes>\n
+//
+// The bytes are those after TLS, so a cassette of an HTTPS download replays
+// without certificates or a network, and stays readable for bug reports.
+// The same exchanges can go to a WARC archive instead, for --warc.
+pub struct Cassette {
+    mode: Mode,
+    // Numbers the connections, to keep their exchanges apart.
+    connections: AtomicUsize,
+}
+
+enum Mode {
+    Record(Mutex<BufWriter<File>>),
+    Replay(Mutex<Vec<Exchange>>),
+    Warc(Mutex<Warc>),
+}
+
+struct Exchange {
+    connection: usize,
+    origin: String,
+    request: Vec<u8>,
+    response: Vec<u8>,
+    replayed: bool,
+}
+
+impl Cassette {
+    // Creates (or truncates) `path` to record into.
+    pub fn record(path: &Path) -> io::Result<Cassette> {
+        let mut file = BufWriter::new(
+            File::create(path).map_err(|e| io::Error::new(e.kind(), format!("Cannot create {}: {}", path.display(), e)))?,
+        );
+        writeln!(file, "{}", MAGIC)?;
+        Ok(Cassette { mode: Mode::Record(Mutex::new(file)), connections: AtomicUsize::new(0) })
+    }
+
+    // Loads the exchanges recorded in `path`.
+    pub fn replay(path: &Path) -> io::Result<Cassette> {
+        let file = File::open(path).map_err(|e| io::Error::new(e.kind(), format!("Cannot read {}: {}", path.display(), e)))?;
+        let exchanges = parse(&mut BufReader::new(file))
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        Ok(Cassette { mode: Mode::Replay(Mutex::new(exchanges)), connections: AtomicUsize::new(0) })
+    }
+
+    // Creates (or truncates) the WARC archive `path` to record into.
+    pub fn warc(path: &Path) -> io::Result<Cassette> {
+        Ok(Cassette { mode: Mode::Warc(Mutex::new(Warc::create(path)?)), connections: AtomicUsize::new(0) })
+    }
+
+    pub fn is_replay(&self) -> bool {
+        matches!(self.mode, Mode::Replay(_))
+    }
+
+    // A connection to `url`'s origin: `stream` with what passes through it
+    // recorded, or, replaying, one answered from the cassette, for which
+    // `stream` is not needed.
+    pub fn connection(self: &Arc<Cassette>, url: &Url, stream: Option<Stream>) -> CassetteStream {
+        CassetteStream {
+            cassette: Arc::clone(self),
+            id: self.connections.fetch_add(1, Ordering::Relaxed) + 1,
+            origin: url.origin(),
+            stream,
+            written: Vec::new(),
+            read: Vec::new(),
+            last: None,
+        }
+    }
+
+    fn save(&self, connection: usize, origin: &str, request: &[u8], response: &[u8]) -> io::Result<()> {
+        let file = match &self.mode {
+            Mode::Record(file) => file,
+            Mode::Warc(warc) => return warc.lock().unwrap().exchange(origin, request, response),
+            Mode::Replay(_) => return Ok(()),
+        };
+        let mut file = file.lock().unwrap();
+        for (direction, bytes) in [('>', request), ('<', response)] {
+            writeln!(file, "{} {} {} {}", direction, connection, origin, bytes.len())?;
+            file.write_all(bytes)?;
+            file.write_all(b"\n")?;
+        }
+        file.flush()
+    }
+
+    // The response recorded for `request` to `origin`: the first one not
+    // replayed yet for the same request lines and ranges, else the last one
+    // replayed. Bytes without a request line, such as a body sent after
+    // 100 Continue, get what followed `last` on its recorded connection.
+    fn answer(&self, origin: &str, request: &[u8], last: Option<usize>) -> io::Result<(usize, Vec<u8>)> {
+        let Mode::Replay(exchanges) = &self.mode else { unreachable!("answering while recording") };
+        let mut exchanges = exchanges.lock().unwrap();
+        let wanted = key(request);
+        let found = if wanted.is_empty() {
+            last.and_then(|last| {
+                let connection = exchanges[last].connection;
+                (last + 1..exchanges.len()).find(|&i| exchanges[i].connection == connection)
+            })
+        } else {
+            let matching: Vec<usize> = (0..exchanges.len())
+                .filter(|&i| exchanges[i].origin == origin && key(&exchanges[i].request) == wanted)
+                .collect();
+            matching.iter().copied().find(|&i| !exchanges[i].replayed).or(matching.last().copied())
+        };
+        let Some(i) = found else {
+            let request = if wanted.is_empty() { "the rest of a request".to_string() } else { wanted.join(", ") };
+            return Err(DownloadError::Connect(format!("No recorded response for {} from {}", request, origin)).into());
+        };
+        exchanges[i].replayed = true;
+        Ok((i, exchanges[i].response.clone()))
+    }
+}
+
+// A connection that records, or one that replays.
+pub struct CassetteStream {
+    cassette: Arc<Cassette>,
+    id: usize,
+    origin: String,
+    // The real connection when recording.
+    stream: Option<Stream>,
+    // Recording: the exchange so far. Replaying: the request not answered
+    // yet, and what is left of the response to it.
+    written: Vec<u8>,
+    read: Vec<u8>,
+    // Replaying: the exchange answered last.
+    last: Option<usize>,
+}
+
+impl CassetteStream {
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match &self.stream {
+            Some(stream) => stream.set_read_timeout(timeout),
+            None => Ok(()),
+        }
+    }
+
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        self.save()?;
+        match &mut self.stream {
+            Some(stream) => crate::transport::Connection::shutdown(stream),
+            None => Ok(()),
+        }
+    }
+
+    fn save(&mut self) -> io::Result<()> {
+        if self.stream.is_none() || (self.written.is_empty() && self.read.is_empty()) {
+            return Ok(());
+        }
+        let result = self.cassette.save(self.id, &self.origin, &self.written, &self.read);
+        self.written.clear();
+        self.read.clear();
+        result
+    }
+}
+
+impl Read for CassetteStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(stream) = &mut self.stream {
+            let n = stream.read(buf)?;
+            self.read.extend_from_slice(&buf[..n]);
+            return Ok(n);
+        }
+        if self.read.is_empty() && !self.written.is_empty() {
+            let (i, response) = self.cassette.answer(&self.origin, &self.written, self.last)?;
+            self.written.clear();
+            self.read = response;
+            self.last = Some(i);
+        }
+        let n = self.read.len().min(buf.len());
+        buf[..n].copy_from_slice(&self.read[..n]);
+        self.read.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Write for CassetteStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(stream) = &mut self.stream else {
+            self.written.extend_from_slice(buf);
+            return Ok(buf.len());
+        };
+        let n = stream.write(buf)?;
+        // Writing after reading starts the next exchange.
+        if !self.read.is_empty() {
+            self.save()?;
+        }
+        self.written.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.stream {
+            Some(stream) => stream.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for CassetteStream {
+    fn drop(&mut self) {
+        let _ = self.save();
+    }
+}
+
+// What a request is matched by: each request line, with the Range it asks
+// for, since the other fields (cookies, nonces, validators) may differ from
+// run to run.
+fn key(request: &[u8]) -> Vec<String> {
+    let mut key = Vec::new();
+    for line in String::from_utf8_lossy(request).split("\r\n") {
+        if line.ends_with(" HTTP/1.1") || line.ends_with(" HTTP/1.0") {
+            key.push(line.to_string());
+        } else if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("range") {
+                key.push(format!("Range: {}", value.trim()));
+            }
+        }
+    }
+    key
+}
+
+fn parse(reader: &mut impl BufRead) -> io::Result<Vec<Exchange>> {
+    let malformed = |what: &str| io::Error::from(DownloadError::Parse(format!("Malformed cassette: {}", what)));
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if line.trim_end() != MAGIC {
+        return Err(malformed("missing header line"));
+    }
+    let mut records = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [direction, connection, origin, len] = fields[..] else { return Err(malformed(line.trim_end())) };
+        let (Ok(connection), Ok(len)) = (connection.parse::<usize>(), len.parse::<usize>()) else {
+            return Err(malformed(line.trim_end()));
+        };
+        let mut bytes = vec![0; len + 1];
+        reader.read_exact(&mut bytes).map_err(|_| malformed("truncated record"))?;
+        if bytes.pop() != Some(b'\n') {
+            return Err(malformed("record longer than its length"));
+        }
+        records.push((direction.to_string(), connection, origin.to_string(), bytes));
+    }
+    let mut exchanges = Vec::new();
+    let mut records = records.into_iter();
+    while let Some((direction, connection, origin, request)) = records.next() {
+        match records.next() {
+            Some((answer, _, _, response)) if direction == ">" && answer == "<" => {
+                exchanges.push(Exchange { connection, origin, request, response, replayed: false })
+            }
+            _ => return Err(malformed("a request without its response")),
+        }
+    }
+    Ok(exchanges)
+}
@@ -0,0 +1,237 @@
+use std::io::{self, Read, Write};
+
+use crate::error::DownloadError;
+
+// Longest chunk-size or trailer line accepted before the response is
+// considered malformed.
+const MAX_LINE: usize = 8 * 1024;
+
+// How much is read at a time while looking for a line; chunk data itself is
+// read straight into the caller's buffer.
+const LINE_READ_SIZE: usize = 4096;
+
+// Largest chunk written by encode.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// Fields that may not arrive as trailers (RFC 9110 6.5.1): they would change
+// how the message was framed, decoded or authorized after the fact.
+const PROHIBITED_TRAILERS: &[&str] = &[
+    "transfer-encoding",
+    "content-length",
+    "content-encoding",
+    "content-type",
+    "content-range",
+    "trailer",
+    "host",
+    "authorization",
+    "www-authenticate",
+    "set-cookie",
+];
+
+// Decodes a `Transfer-Encoding: chunked` body as it is read. `buffered` holds
+// the bytes already read past the headers. A connection that closes early
+// ends the body where it stopped, the same way a truncated Content-Length
+// body does.
+pub struct Decoder<R> {
+    reader: Reader<R>,
+    // Data bytes left in the current chunk.
+    remaining: usize,
+    done: bool,
+    trailers: Vec<u8>,
+}
+
+impl<R: Read> Decoder<R> {
+    pub fn new(stream: R, buffered: Vec<u8>) -> Decoder<R> {
+        Decoder { reader: Reader { stream, buf: buffered, pos: 0 }, remaining: 0, done: false, trailers: Vec::new() }
+    }
+
+    // Once the body has been read: the trailer field lines (CRLF-terminated,
+    // prohibited fields dropped) and the bytes read past its end.
+    pub fn finish(mut self) -> (Vec<u8>, Vec<u8>) {
+        let excess = self.reader.buf.split_off(self.reader.pos);
+        (self.trailers, excess)
+    }
+
+    // Reads the next chunk-size line, and after the last chunk the trailer
+    // section, which runs up to the terminating empty line.
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let Some(line) = self.reader.line()? else {
+            self.done = true;
+            return Ok(());
+        };
+        self.remaining = parse_size(&line)?;
+        if self.remaining == 0 {
+            while let Some(line) = self.reader.line()? {
+                if line.is_empty() {
+                    break;
+                }
+                if is_allowed_trailer(&line) {
+                    self.trailers.extend_from_slice(&line);
+                    self.trailers.extend_from_slice(b"\r\n");
+                }
+            }
+            self.done = true;
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 && !self.done {
+            self.next_chunk()?;
+        }
+        if self.done || out.is_empty() {
+            return Ok(0);
+        }
+        let len = self.remaining.min(out.len());
+        let n = self.reader.read_data(&mut out[..len])?;
+        if n == 0 {
+            self.done = true;
+            return Ok(0);
+        }
+        self.remaining -= n;
+        if self.remaining == 0 {
+            match self.reader.line()? {
+                Some(line) if line.is_empty() => {}
+                Some(_) => return Err(malformed("Missing CRLF after chunk data".to_string())),
+                None => self.done = true,
+            }
+        }
+        Ok(n)
+    }
+}
+
+// How many bytes of `data` the chunked body at its start takes, or None while
+// its end has not arrived, for readers that cannot block inside decode.
+#[cfg(any(feature = "async", feature = "event-loop"))]
+pub fn body_len(data: &[u8]) -> io::Result<Option<usize>> {
+    let line_end = |from: usize| data[from..].windows(2).position(|w| w == b"\r\n").map(|i| from + i);
+    let mut pos = 0;
+    loop {
+        let Some(end) = line_end(pos) else { return Ok(None) };
+        let size = parse_size(&data[pos..end])?;
+        pos = end + 2;
+        if size == 0 {
+            // The trailer section runs up to the terminating empty line.
+            loop {
+                let Some(end) = line_end(pos) else { return Ok(None) };
+                let empty = end == pos;
+                pos = end + 2;
+                if empty {
+                    return Ok(Some(pos));
+                }
+            }
+        }
+        // A size that would not even fit in memory is not waited for.
+        pos = pos
+            .checked_add(size)
+            .and_then(|pos| pos.checked_add(2))
+            .ok_or_else(|| malformed(format!("Chunk size too large: {:x}", size)))?;
+        if pos > data.len() {
+            return Ok(None);
+        }
+    }
+}
+
+// Sends everything `body` yields as a chunked request body, ending with the
+// last-chunk and an empty trailer section. Returns the number of body bytes.
+pub fn encode<R: Read + ?Sized, W: Write>(body: &mut R, out: &mut W) -> io::Result<u64> {
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut total = 0;
+    loop {
+        let n = match body.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        // One write per chunk, so each becomes as few segments as possible.
+        let mut chunk = format!("{:x}\r\n", n).into_bytes();
+        chunk.extend_from_slice(&buffer[..n]);
+        chunk.extend_from_slice(b"\r\n");
+        out.write_all(&chunk)?;
+        total += n as u64;
+    }
+    out.write_all(b"0\r\n\r\n")?;
+    Ok(total)
+}
+
+fn is_allowed_trailer(line: &[u8]) -> bool {
+    let Some(colon) = line.iter().position(|&b| b == b':') else { return false };
+    let name = String::from_utf8_lossy(&line[..colon]).trim().to_lowercase();
+    !name.is_empty() && !PROHIBITED_TRAILERS.contains(&name.as_str())
+}
+
+fn parse_size(line: &[u8]) -> io::Result<usize> {
+    let line = std::str::from_utf8(line).map_err(|_| malformed("Invalid chunk size line".to_string()))?;
+    // Chunk extensions (";name=value") carry nothing we use.
+    let size = line.split(';').next().unwrap_or("").trim();
+    // from_str_radix would also take a sign.
+    usize::from_str_radix(size, 16)
+        .ok()
+        .filter(|_| size.bytes().all(|b| b.is_ascii_hexdigit()))
+        .ok_or_else(|| malformed(format!("Invalid chunk size: {:?}", size)))
+}
+
+fn malformed(message: String) -> io::Error {
+    DownloadError::Parse(message).into()
+}
+
+struct Reader<R> {
+    stream: R,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> Reader<R> {
+    // Reads more data from the stream, returning false at end of stream.
+    fn fill(&mut self) -> io::Result<bool> {
+        self.buf.drain(..self.pos);
+        self.pos = 0;
+        let len = self.buf.len();
+        self.buf.resize(len + LINE_READ_SIZE, 0);
+        let bytes_read = match self.stream.read(&mut self.buf[len..]) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => 0,
+            Err(e) => {
+                self.buf.truncate(len);
+                return Err(e);
+            }
+        };
+        self.buf.truncate(len + bytes_read);
+        Ok(bytes_read > 0)
+    }
+
+    // Returns the next CRLF-terminated line without its terminator.
+    fn line(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(end) = self.buf[self.pos..].windows(2).position(|w| w == b"\r\n") {
+                let line = self.buf[self.pos..self.pos + end].to_vec();
+                self.pos += end + 2;
+                return Ok(Some(line));
+            }
+            if self.buf.len() - self.pos > MAX_LINE {
+                return Err(malformed("Chunk header line too long".to_string()));
+            }
+            if !self.fill()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    // Reads chunk data from what is buffered, or straight from the stream
+    // once that is used up; 0 at end of stream.
+    fn read_data(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buf.len() {
+            return match self.stream.read(out) {
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+                result => result,
+            };
+        }
+        let n = (self.buf.len() - self.pos).min(out.len());
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
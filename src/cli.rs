@@ -0,0 +1,1113 @@
+use std::env;
+use std::io;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::{ArgAction, CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use tracing::level_filters::LevelFilter;
+
+use crate::auth::Credentials;
+use crate::client::{DEFAULT_MAX_REDIRECTS, DEFAULT_READ_SIZE, DEFAULT_USER_AGENT};
+use crate::connector::{IpFamily, SocketOptions, TlsOptions, CONNECT_TIMEOUT, READ_TIMEOUT};
+use crate::hash::{Algorithm, ChecksumStyle};
+use crate::netrc;
+use crate::queue::MAX_ATTEMPTS;
+use crate::request;
+use crate::store::{DEFAULT_BUFFER_LIMIT, PIECE_SIZE};
+use crate::url::Url;
+
+pub struct Args {
+    // Empty only when they all come from the input file.
+    pub urls: Vec<Url>,
+    // -i: more URLs, one per line, "-" for stdin.
+    pub input_file: Option<PathBuf>,
+    // Other sources of the same file.
+    pub mirrors: Vec<Url>,
+    // How many of several URLs are downloaded at once.
+    pub max_concurrent_downloads: usize,
+    // None picks the count automatically.
+    pub num_threads: Option<usize>,
+    pub tls: TlsOptions,
+    pub http2: bool,
+    // Download with tokio tasks instead of threads.
+    pub asynchronous: bool,
+    // Drive all connections from one thread with non-blocking sockets.
+    pub event_loop: bool,
+    pub decompress: bool,
+    pub compressed: bool,
+    pub max_redirects: usize,
+    pub cache: Option<PathBuf>,
+    pub pipeline_depth: usize,
+    pub headers: Vec<(String, String)>,
+    // None when disabled with an empty --user-agent.
+    pub user_agent: Option<String>,
+    pub credentials: Option<Credentials>,
+    pub bearer_token: Option<String>,
+    // None with --no-netrc or when no home directory is known.
+    pub netrc: Option<PathBuf>,
+    pub ip_family: IpFamily,
+    pub socket_options: SocketOptions,
+    pub connect_timeout: Duration,
+    pub read_timeout: Duration,
+    pub doh_url: Option<Url>,
+    pub unix_socket: Option<PathBuf>,
+    // Send one request and print the response instead of downloading.
+    pub request: Option<Request>,
+    pub want_repr_digest: bool,
+    // Where the downloaded data, or the response body in the request modes, is written.
+    pub output: Option<PathBuf>,
+    // -O: name the output file after Content-Disposition or the URL.
+    pub remote_name: bool,
+    // Pick up an interrupted download to the output file where it stopped.
+    pub continue_download: bool,
+    // Copy chunks into a memory mapping of the output file.
+    pub mmap: bool,
+    // fsync the output file and its directory before exiting.
+    pub sync: bool,
+    // Size of the pieces to list in a digest manifest.
+    pub piece_hashes: Option<u64>,
+    // How far past the hashing position downloaded chunks may be held in memory.
+    pub max_buffer: usize,
+    // How much of that may stay in memory before the rest goes to a temporary file.
+    pub spill: Option<usize>,
+    // Most bytes one range request asks for.
+    pub chunk_size: usize,
+    // Most bytes of a response body one read takes.
+    pub read_size: usize,
+    // Failed requests for the same bytes before they are given up on.
+    pub max_attempts: usize,
+    // Only download bytes first-last of the resource, or first to the end.
+    pub byte_range: Option<(usize, Option<usize>)>,
+    // Time downloads of the URL with each of these settings instead.
+    pub bench: Option<Bench>,
+    // `probe` mode: print what the size probe learns and download nothing.
+    pub probe: bool,
+    // `hash` mode: print a checksum line in this style for each URL.
+    pub checksums: Option<ChecksumStyle>,
+    // What the downloaded data is hashed with, all in the same pass; the
+    // first one is the digest kept for --continue and the batch summary.
+    pub hashes: Vec<Algorithm>,
+    // Most threads BLAKE3 hashes on; None uses every CPU.
+    pub hash_threads: Option<usize>,
+    // The digest the data must have, lowercase hex.
+    pub expect: Option<(Algorithm, String)>,
+    // Write a checksum file next to the output file for each algorithm.
+    pub write_checksum: Option<ChecksumStyle>,
+    // Fail when some bytes cannot be downloaded, rather than zero-fill them.
+    pub strict: bool,
+    // Stream the ordered data to stdout, besides the file with --tee or
+    // instead of one with "-o -".
+    pub stdout: bool,
+    // Give up on the transfer after this long, keeping what --continue needs.
+    pub max_time: Option<Duration>,
+    // Print what each connection did at the end of a download.
+    pub stats: bool,
+    // Write the same as JSON to this file.
+    pub stats_json: Option<PathBuf>,
+    // Print one JSON object with the result on stdout instead of messages.
+    pub json: bool,
+    // Where to serve Prometheus metrics over HTTP while the run lasts.
+    pub metrics_addr: Option<String>,
+    // Save every HTTP/1.1 exchange to this file, or answer requests from one
+    // instead of the network.
+    pub record: Option<PathBuf>,
+    pub replay: Option<PathBuf>,
+    // Archive every HTTP/1.1 exchange to this WARC file as well.
+    pub warc: Option<PathBuf>,
+    // Which messages are shown: warnings only with -q, the usual ones by
+    // default, per-range details with -v and per-chunk ones with -vv.
+    pub log_level: LevelFilter,
+}
+
+// `get`, `head`, `put`, `post` or `request` mode, or -T.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    pub body: Option<BodySource>,
+}
+
+// `bench` mode: every thread count is tried with every chunk size.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bench {
+    pub threads: Vec<usize>,
+    pub chunk_sizes: Vec<usize>,
+    // Downloads per setting, averaged.
+    pub runs: usize,
+}
+
+const BENCH_THREADS: [usize; 5] = [1, 2, 4, 8, 16];
+const BENCH_CHUNK_SIZES: [usize; 3] = [256 * 1024, 1024 * 1024, 4 * 1024 * 1024];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BodySource {
+    File(PathBuf),
+    Stdin,
+}
+
+// Where the bearer token comes from when --oauth2-bearer is not given, so it
+// stays out of the process list and shell history.
+const BEARER_TOKEN_ENV: &str = "HTTPC_OAUTH2_BEARER";
+
+// Options that can be set in the environment instead, for containers and CI
+// jobs where the command line is hard to change. The proxy is HTTPC_PROXY,
+// read with the other proxy variables.
+const ENV_OPTIONS: [(&str, &str); 13] = [
+    ("HTTPC_THREADS", "--threads"),
+    ("HTTPC_CHUNK_SIZE", "--chunk-size"),
+    ("HTTPC_MAX_ATTEMPTS", "--max-attempts"),
+    ("HTTPC_MAX_TIME", "--max-time"),
+    ("HTTPC_MAX_REDIRECTS", "--max-redirects"),
+    ("HTTPC_MAX_BUFFER", "--max-buffer"),
+    ("HTTPC_EXPECT", "--expect"),
+    ("HTTPC_HASH", "--hash"),
+    ("HTTPC_USER_AGENT", "--user-agent"),
+    ("HTTPC_CACERT", "--cacert"),
+    ("HTTPC_DOH_URL", "--doh-url"),
+    ("HTTPC_CONNECT_TIMEOUT", "--connect-timeout"),
+    ("HTTPC_READ_TIMEOUT", "--read-timeout"),
+];
+// Set to 1 (or true, yes) to turn the option on.
+const ENV_SWITCHES: [(&str, &str); 2] = [("HTTPC_INSECURE", "--insecure"), ("HTTPC_HTTP2", "--http2")];
+
+// The command line: a mode, each with its own options. Without a mode the
+// arguments are a download.
+#[derive(Parser)]
+#[command(
+    name = "http_client",
+    version,
+    about = "Downloads over HTTP in parallel ranges, hashing the data as it arrives",
+    subcommand_required = true,
+    arg_required_else_help = true,
+    args_override_self = true,
+    after_help = "A bare URL is the same as `download <URL>`.\n\
+                  HTTPC_THREADS, HTTPC_CHUNK_SIZE, HTTPC_MAX_TIME, HTTPC_EXPECT, HTTPC_PROXY and the other HTTPC_*\n\
+                  variables set the matching options; the command line wins over them."
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub mode: Mode,
+}
+
+#[derive(Subcommand)]
+pub enum Mode {
+    /// Download the URLs, hashing the data as it arrives (the default)
+    Download(DownloadArgs),
+    /// Download without saving and print "<hex>  <url>" lines, like sha256sum
+    Hash(HashArgs),
+    /// Send one GET and print the response body
+    Get(GetArgs),
+    /// Send a HEAD and print the response head
+    Head(HeadArgs),
+    /// Print the final URL, size, range support and validator a download would use
+    Probe(ProbeArgs),
+    /// Send the file (stdin when omitted or "-") with PUT and print the response
+    Put(UploadArgs),
+    /// Send the file (stdin when omitted or "-") with POST and print the response
+    Post(UploadArgs),
+    /// Send a GET, or the -X method, with the file as an optional body
+    Request(RequestArgs),
+    /// Download the URL with several thread counts and chunk sizes and compare their throughput
+    Bench(BenchArgs),
+    /// Print a script completing the subcommands and options, e.g. for ~/.bashrc
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+}
+
+#[derive(clap::Args)]
+#[command(override_usage = "http_client [download] [OPTIONS] <URL>... [NUM_THREADS]")]
+pub struct DownloadArgs {
+    /// URLs to download, then optionally the number of threads; without it,
+    /// connections are added while they speed up the download
+    #[arg(value_name = "URL")]
+    targets: Vec<String>,
+    /// Same as NUM_THREADS
+    #[arg(long, value_name = "N", value_parser = positive)]
+    threads: Option<usize>,
+    /// Also download the URLs listed in this file, one per line ("-" for stdin)
+    #[arg(short, long, value_name = "FILE")]
+    input_file: Option<PathBuf>,
+    /// Download up to n of several URLs at once
+    #[arg(long, value_name = "N", value_parser = positive, default_value_t = 1)]
+    max_concurrent_downloads: usize,
+    #[command(flatten)]
+    save: SaveArgs,
+    /// Resume an interrupted -o/-O download from its saved state
+    #[arg(short = 'c', long = "continue")]
+    continue_download: bool,
+    /// Also stream the data to stdout as it is hashed and saved; messages go to stderr
+    #[arg(long)]
+    tee: bool,
+    /// Write chunks through a memory mapping of the -o/-O file and hash it at the end
+    #[arg(long)]
+    mmap: bool,
+    /// Also write <file>.<algorithm> with "<hex>  <file>" for sha256sum -c and the like
+    #[arg(long)]
+    write_checksum: bool,
+    /// Write the checksum files in the BSD "SHA256 (<file>) = <hex>" format
+    #[arg(long, requires = "write_checksum")]
+    tag: bool,
+    /// Same as put <URL> <FILE>
+    #[arg(short = 'T', long, value_name = "FILE")]
+    upload_file: Option<String>,
+    /// Remember ETag/Last-Modified and the hash; skip the download on 304 Not Modified
+    #[arg(long, value_name = "FILE")]
+    cache: Option<PathBuf>,
+    /// Write the --stats statistics to this file as JSON
+    #[arg(long, value_name = "FILE")]
+    stats_json: Option<PathBuf>,
+    /// Print the result as one JSON object on stdout (bytes, speed, hashes, failed ranges); messages go to stderr
+    #[arg(long, conflicts_with = "tee")]
+    json: bool,
+    #[command(flatten)]
+    transfer: TransferArgs,
+    #[command(flatten)]
+    common: CommonArgs,
+}
+
+#[derive(clap::Args)]
+#[command(override_usage = "http_client hash [OPTIONS] <URL>... [NUM_THREADS]")]
+pub struct HashArgs {
+    /// URLs to hash, then optionally the number of threads
+    #[arg(value_name = "URL")]
+    targets: Vec<String>,
+    /// Same as NUM_THREADS
+    #[arg(long, value_name = "N", value_parser = positive)]
+    threads: Option<usize>,
+    /// Also hash the URLs listed in this file, one per line ("-" for stdin)
+    #[arg(short, long, value_name = "FILE")]
+    input_file: Option<PathBuf>,
+    /// Hash up to n of several URLs at once
+    #[arg(long, value_name = "N", value_parser = positive, default_value_t = 1)]
+    max_concurrent_downloads: usize,
+    /// Print BSD "SHA256 (<url>) = <hex>" lines
+    #[arg(long)]
+    tag: bool,
+    /// Remember ETag/Last-Modified and the hash; skip the download on 304 Not Modified
+    #[arg(long, value_name = "FILE")]
+    cache: Option<PathBuf>,
+    #[command(flatten)]
+    transfer: TransferArgs,
+    #[command(flatten)]
+    common: CommonArgs,
+}
+
+#[derive(clap::Args)]
+pub struct GetArgs {
+    #[arg(value_parser = Url::parse)]
+    url: Url,
+    #[command(flatten)]
+    save: SaveArgs,
+    #[command(flatten)]
+    common: CommonArgs,
+}
+
+#[derive(clap::Args)]
+pub struct HeadArgs {
+    #[arg(value_parser = Url::parse)]
+    url: Url,
+    #[command(flatten)]
+    save: SaveArgs,
+    #[command(flatten)]
+    common: CommonArgs,
+}
+
+#[derive(clap::Args)]
+pub struct ProbeArgs {
+    #[arg(value_parser = Url::parse)]
+    url: Url,
+    #[command(flatten)]
+    fetch: FetchArgs,
+    #[command(flatten)]
+    common: CommonArgs,
+}
+
+#[derive(clap::Args)]
+pub struct UploadArgs {
+    #[arg(value_parser = Url::parse)]
+    url: Url,
+    /// The body; stdin when omitted or "-"
+    file: Option<String>,
+    #[command(flatten)]
+    save: SaveArgs,
+    #[command(flatten)]
+    common: CommonArgs,
+}
+
+#[derive(clap::Args)]
+pub struct RequestArgs {
+    /// Method to send
+    #[arg(short = 'X', long, value_name = "METHOD", value_parser = parse_method, default_value = "GET")]
+    method: String,
+    #[arg(value_parser = Url::parse)]
+    url: Url,
+    /// An optional body, "-" for stdin
+    file: Option<String>,
+    #[command(flatten)]
+    save: SaveArgs,
+    #[command(flatten)]
+    common: CommonArgs,
+}
+
+#[derive(clap::Args)]
+pub struct BenchArgs {
+    #[arg(value_parser = Url::parse)]
+    url: Url,
+    /// Thread counts to try [default: 1,2,4,8,16]
+    #[arg(long, value_name = "N,...", value_delimiter = ',', value_parser = |value: &str| positive(value.trim()))]
+    bench_threads: Vec<usize>,
+    /// Chunk sizes to try [default: 256K,1M,4M]
+    #[arg(long, value_name = "SIZE,...", value_delimiter = ',', value_parser = |value: &str| memory_size(value.trim()))]
+    bench_chunk_sizes: Vec<usize>,
+    /// Downloads averaged for each setting
+    #[arg(long, value_name = "N", value_parser = positive, default_value_t = 1)]
+    bench_runs: usize,
+    #[command(flatten)]
+    transfer: TransferArgs,
+    #[command(flatten)]
+    common: CommonArgs,
+}
+
+// Where a download or a response body is saved.
+#[derive(clap::Args)]
+struct SaveArgs {
+    /// Write the data (or the response body) to this file, or "-" for stdout
+    #[arg(short, long, value_name = "FILE", conflicts_with = "remote_name")]
+    output: Option<PathBuf>,
+    /// Like -o, named by Content-Disposition or the URL path
+    #[arg(short = 'O', long)]
+    remote_name: bool,
+    /// fsync the -o/-O file, and its directory after the rename, before exiting
+    #[arg(long)]
+    sync: bool,
+}
+
+// How the download, hash and bench modes fetch and check the data.
+#[derive(clap::Args)]
+#[command(next_help_heading = "Transfer options")]
+struct TransferArgs {
+    /// Also fetch ranges of the file from this server (repeatable)
+    #[arg(long = "mirror", value_name = "URL", value_parser = Url::parse)]
+    mirrors: Vec<Url>,
+    /// Only download, hash and save these bytes of the file, e.g. 1000-2047
+    #[arg(short = 'r', long = "range", value_name = "FIRST-[LAST]", value_parser = parse_byte_range)]
+    byte_range: Option<(usize, Option<usize>)>,
+    /// Also list the SHA-256 of each piece of this size (e.g. 1M)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    piece_hashes: Option<u64>,
+    /// Hash the data with md5, sha1, sha256 (default), sha512, blake3 or crc32 (repeatable)
+    #[arg(long = "hash", value_name = "ALGORITHM", value_parser = Algorithm::parse)]
+    hashes: Vec<Algorithm>,
+    /// Hash BLAKE3 on up to n threads [default: one per CPU]
+    #[arg(long, value_name = "N", value_parser = positive)]
+    hash_threads: Option<usize>,
+    /// Fail, removing the partial file, unless the data has this digest
+    #[arg(long, value_name = "ALGORITHM:HEX", value_parser = parse_expect)]
+    expect: Option<(Algorithm, String)>,
+    /// Fail, naming the bytes, when some cannot be downloaded (default)
+    #[arg(long, overrides_with = "no_strict")]
+    strict: bool,
+    /// Fill bytes that cannot be downloaded with zeros and warn, instead of failing
+    #[arg(long, overrides_with = "strict")]
+    no_strict: bool,
+    /// Hash the raw bytes instead of decoding gzip/deflate Content-Encoding
+    #[arg(long)]
+    no_decompress: bool,
+    /// Ask for a SHA-256 Repr-Digest and verify the download against it
+    #[arg(long)]
+    want_repr_digest: bool,
+    /// Hold downloaded chunks at most this far past the hashed data in memory [default: 64M]
+    #[arg(long, value_name = "SIZE", value_parser = memory_size)]
+    max_buffer: Option<usize>,
+    /// Keep at most this much of those chunks in memory and the rest in a temporary file
+    #[arg(long, value_name = "SIZE", value_parser = memory_size)]
+    spill: Option<usize>,
+    /// Ask for at most this much in one range request [default: 1M]
+    #[arg(long, value_name = "SIZE", value_parser = memory_size)]
+    chunk_size: Option<usize>,
+    /// Try the same bytes up to n times before giving up on them
+    #[arg(long, value_name = "N", value_parser = positive, default_value_t = MAX_ATTEMPTS)]
+    max_attempts: usize,
+    /// Keep up to n range requests in flight per connection
+    #[arg(long, value_name = "N", value_parser = positive, default_value_t = 1)]
+    pipeline_depth: usize,
+    /// Multiplex each thread's range requests over one HTTP/2 connection
+    #[arg(long)]
+    http2: bool,
+    /// Fetch the ranges as tasks on a tokio runtime instead of threads (single URL)
+    #[arg(long = "async")]
+    asynchronous: bool,
+    /// Drive all connections from one thread with non-blocking sockets instead of a thread each
+    #[arg(long, conflicts_with_all = ["asynchronous", "http2"])]
+    event_loop: bool,
+    /// Stop after this many seconds, saving the state to resume with --continue, and exit with 28
+    #[arg(long, value_name = "SECS", value_parser = parse_seconds)]
+    max_time: Option<Duration>,
+    /// After the download, list each connection's bytes, requests, retries and speed
+    #[arg(long)]
+    stats: bool,
+    #[command(flatten)]
+    fetch: FetchArgs,
+}
+
+// How the size probe, and the downloads after it, ask for the resource.
+#[derive(clap::Args)]
+struct FetchArgs {
+    /// Follow at most n redirects; 0 disables
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_MAX_REDIRECTS)]
+    max_redirects: usize,
+    /// Request a compressed response (Accept-Encoding) and decode it
+    #[arg(long)]
+    compressed: bool,
+}
+
+// How every mode connects and logs in, and what it logs.
+#[derive(clap::Args)]
+#[command(next_help_heading = "Connection options")]
+struct CommonArgs {
+    /// Send "Name: value" on the size probe and every range request (repeatable)
+    #[arg(short = 'H', long = "header", value_name = "NAME: VALUE", value_parser = parse_header)]
+    headers: Vec<(String, String)>,
+    #[arg(
+        long,
+        value_name = "UA",
+        help = format!("User-Agent to send [default: {}; empty to omit]", DEFAULT_USER_AGENT)
+    )]
+    user_agent: Option<String>,
+    /// Answer Basic or Digest authentication challenges
+    #[arg(short = 'u', long = "user", value_name = "USER:PASSWORD", value_parser = parse_credentials)]
+    credentials: Option<Credentials>,
+    #[arg(
+        long,
+        value_name = "TOKEN",
+        conflicts_with = "credentials",
+        help = format!("Send \"Authorization: Bearer <TOKEN>\" [default: ${}]", BEARER_TOKEN_ENV)
+    )]
+    oauth2_bearer: Option<String>,
+    /// Read logins from this file instead of $NETRC or ~/.netrc
+    #[arg(long, value_name = "FILE")]
+    netrc_file: Option<PathBuf>,
+    /// Do not look up logins in a .netrc file
+    #[arg(long)]
+    no_netrc: bool,
+    /// Read responses up to this much at a time [default: 256K]
+    #[arg(long, value_name = "SIZE", value_parser = memory_size)]
+    read_buffer_size: Option<usize>,
+    /// Only connect to IPv4 addresses of the host
+    #[arg(short = '4', long, overrides_with = "ipv6")]
+    ipv4: bool,
+    /// Only connect to IPv6 addresses of the host
+    #[arg(short = '6', long, overrides_with = "ipv4")]
+    ipv6: bool,
+    /// Send small writes at once (TCP_NODELAY) on every connection
+    #[arg(long)]
+    tcp_nodelay: bool,
+    /// Socket receive buffer (SO_RCVBUF) of every connection, e.g. 4M for high-latency fast links
+    #[arg(long, value_name = "SIZE", value_parser = socket_buffer_size)]
+    recv_buffer_size: Option<usize>,
+    /// Socket send buffer (SO_SNDBUF) of every connection
+    #[arg(long, value_name = "SIZE", value_parser = socket_buffer_size)]
+    send_buffer_size: Option<usize>,
+    /// Try the next address of the host after this long without a connection [default: 10]
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+    connect_timeout: Option<Duration>,
+    /// Fail a request, retrying the range, after this long without data from the server [default: 60]
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+    read_timeout: Option<Duration>,
+    /// Connect to this Unix domain socket instead of the URL's host
+    #[arg(long, value_name = "PATH")]
+    unix_socket: Option<PathBuf>,
+    /// Resolve host names with DNS over HTTPS at this URL
+    #[arg(long, value_name = "URL", value_parser = Url::parse)]
+    doh_url: Option<Url>,
+    /// Trust the CA certificates in this PEM file instead of the built-in roots
+    #[arg(long, value_name = "FILE")]
+    cacert: Option<PathBuf>,
+    /// Skip TLS certificate verification (testing only)
+    #[arg(long)]
+    insecure: bool,
+    /// PEM client certificate chain for mutual TLS
+    #[arg(long, value_name = "FILE", requires = "key")]
+    cert: Option<PathBuf>,
+    /// PEM private key matching --cert
+    #[arg(long, value_name = "FILE", requires = "cert")]
+    key: Option<PathBuf>,
+    /// Require the server public key to have this SHA-256 SPKI hash (repeatable)
+    #[arg(long = "pin-sha256", value_name = "BASE64", value_parser = parse_pin)]
+    pinned_keys: Vec<[u8; 32]>,
+    /// Save every request and response to this file, to replay later
+    #[arg(long, value_name = "FILE")]
+    record: Option<PathBuf>,
+    /// Answer requests from a --record file instead of the network
+    #[arg(long, value_name = "FILE", conflicts_with = "record")]
+    replay: Option<PathBuf>,
+    /// Also archive every request and response in WARC format (gzipped if it ends in .gz)
+    #[arg(long, value_name = "FILE")]
+    warc: Option<PathBuf>,
+    /// Serve Prometheus metrics at http://<HOST:PORT>/metrics while running
+    #[arg(long, value_name = "HOST:PORT")]
+    metrics_addr: Option<String>,
+    /// Also show each range requested and completed, with its thread; -vv also each chunk
+    #[arg(short, long, action = ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+    /// Only show warnings and errors
+    #[arg(short, long)]
+    quiet: bool,
+}
+
+impl Mode {
+    // The options of a mode that transfers something, as `run` takes them.
+    pub fn resolve(self) -> io::Result<Args> {
+        match self {
+            Mode::Download(download) => download.resolve(),
+            Mode::Hash(hash) => hash.resolve(),
+            Mode::Get(get) => get.common.request(get.url, "GET", None, get.save),
+            Mode::Head(head) => head.common.request(head.url, "HEAD", None, head.save),
+            Mode::Put(upload) => {
+                let body = upload.file.as_deref().map_or(BodySource::Stdin, body_source);
+                upload.common.request(upload.url, "PUT", Some(body), upload.save)
+            }
+            Mode::Post(upload) => {
+                let body = upload.file.as_deref().map_or(BodySource::Stdin, body_source);
+                upload.common.request(upload.url, "POST", Some(body), upload.save)
+            }
+            Mode::Request(request) => {
+                let body = request.file.as_deref().map(body_source);
+                request.common.request(request.url, &request.method, body, request.save)
+            }
+            Mode::Probe(probe) => {
+                let mut args = probe.common.into_args(vec![probe.url])?;
+                probe.fetch.apply(&mut args);
+                args.probe = true;
+                Ok(args)
+            }
+            Mode::Bench(bench) => {
+                let mut args = bench.common.into_args(vec![bench.url])?;
+                bench.transfer.apply(&mut args)?;
+                if args.asynchronous {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, "--async cannot be combined with bench"));
+                }
+                let or = |list: Vec<usize>, default: &[usize]| if list.is_empty() { default.to_vec() } else { list };
+                args.bench = Some(Bench {
+                    threads: or(bench.bench_threads, &BENCH_THREADS),
+                    chunk_sizes: or(bench.bench_chunk_sizes, &BENCH_CHUNK_SIZES),
+                    runs: bench.bench_runs,
+                });
+                Ok(args)
+            }
+            Mode::Completions { .. } => {
+                Err(io::Error::new(io::ErrorKind::InvalidInput, "completions prints a script and transfers nothing"))
+            }
+        }
+    }
+}
+
+impl DownloadArgs {
+    fn resolve(self) -> io::Result<Args> {
+        let mut args = self.common.into_args(Vec::new())?;
+        // -T makes it an upload of the file to the one URL.
+        if let Some(file) = &self.upload_file {
+            let [url] = &self.targets[..] else {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "-T takes a single URL"));
+            };
+            args.urls.push(Url::parse(url)?);
+            args.request = Some(Request { method: "PUT".to_string(), body: Some(body_source(file)) });
+        } else {
+            (args.urls, args.num_threads) = urls_and_threads(&self.targets, self.threads)?;
+        }
+        args.input_file = self.input_file;
+        args.max_concurrent_downloads = self.max_concurrent_downloads;
+        args.cache = self.cache;
+        args.stats_json = self.stats_json;
+        args.json = self.json;
+        self.save.apply(&mut args);
+        self.transfer.apply(&mut args)?;
+
+        let saved = args.output.is_some() || args.remote_name;
+        for (given, option) in [
+            (self.continue_download, "--continue"),
+            (self.mmap, "--mmap"),
+            (args.sync, "--sync"),
+            (self.write_checksum, "--write-checksum"),
+            (self.tee, "--tee"),
+        ] {
+            if given && !saved {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} needs an output file (-o or -O)", option)));
+            }
+        }
+        args.continue_download = self.continue_download;
+        args.mmap = self.mmap;
+        let style = if self.tag { ChecksumStyle::Bsd } else { ChecksumStyle::Gnu };
+        args.write_checksum = self.write_checksum.then_some(style);
+
+        if args.request.is_some() {
+            if args.input_file.is_some() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "-i only applies to downloads"));
+            }
+            for (given, option) in [(args.max_time.is_some(), "--max-time"), (args.json, "--json")] {
+                if given {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} only applies to downloads", option)));
+                }
+            }
+        }
+        if args.urls.is_empty() && args.input_file.is_none() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid number of arguments"));
+        }
+        let several = args.urls.len() > 1 || args.input_file.is_some();
+        if (args.output.is_some() || args.stdout) && several {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "-o takes a single URL; use -O to download several"));
+        }
+        // Both would write to stdout.
+        if args.json && args.stdout {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "--json cannot be combined with -o -"));
+        }
+        args.stdout |= self.tee;
+        single_download(&args, &[(self.tee, "--tee"), (args.stats_json.is_some(), "--stats-json")])?;
+        Ok(args)
+    }
+}
+
+impl HashArgs {
+    fn resolve(self) -> io::Result<Args> {
+        let mut args = self.common.into_args(Vec::new())?;
+        (args.urls, args.num_threads) = urls_and_threads(&self.targets, self.threads)?;
+        if args.urls.is_empty() && self.input_file.is_none() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid number of arguments"));
+        }
+        args.input_file = self.input_file;
+        args.max_concurrent_downloads = self.max_concurrent_downloads;
+        args.cache = self.cache;
+        args.checksums = Some(if self.tag { ChecksumStyle::Bsd } else { ChecksumStyle::Gnu });
+        self.transfer.apply(&mut args)?;
+        single_download(&args, &[])?;
+        Ok(args)
+    }
+}
+
+impl SaveArgs {
+    fn apply(self, args: &mut Args) {
+        // "-o -" streams the data to stdout in order, with no file.
+        args.stdout = self.output.as_deref() == Some(Path::new("-"));
+        args.output = self.output.filter(|_| !args.stdout);
+        args.remote_name = self.remote_name;
+        args.sync = self.sync;
+    }
+}
+
+impl TransferArgs {
+    fn apply(self, args: &mut Args) -> io::Result<()> {
+        self.fetch.apply(args);
+        // HTTP/2, the async downloader and the event loop open their
+        // connections themselves.
+        let recorded = args.record.is_some() || args.replay.is_some() || args.warc.is_some();
+        if recorded && (self.http2 || self.asynchronous || self.event_loop) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--record, --replay and --warc cannot be combined with --http2, --async or --event-loop",
+            ));
+        }
+        // The manifest goes next to the file; stdout carries the data, the
+        // checksums or the JSON summary.
+        if self.piece_hashes.is_some() && args.output.is_none() && !args.remote_name {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "--piece-hashes needs an output file (-o or -O)"));
+        }
+        // --expect picks the algorithm unless --hash already did.
+        let mut hashes = Vec::new();
+        for algorithm in self.hashes {
+            if !hashes.contains(&algorithm) {
+                hashes.push(algorithm);
+            }
+        }
+        if let Some((algorithm, _)) = &self.expect {
+            if hashes.is_empty() {
+                hashes.push(*algorithm);
+            } else if !hashes.contains(algorithm) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("--expect gives a {} digest, which no --hash computes", algorithm.name()),
+                ));
+            }
+        }
+        if hashes.is_empty() {
+            hashes.push(Algorithm::Sha256);
+        }
+        args.hashes = hashes;
+        args.mirrors = self.mirrors;
+        args.byte_range = self.byte_range;
+        args.piece_hashes = self.piece_hashes;
+        args.hash_threads = self.hash_threads;
+        args.expect = self.expect;
+        args.strict = !self.no_strict;
+        args.decompress = !self.no_decompress;
+        args.want_repr_digest = self.want_repr_digest;
+        args.max_buffer = self.max_buffer.unwrap_or(DEFAULT_BUFFER_LIMIT);
+        args.spill = self.spill;
+        args.chunk_size = self.chunk_size.unwrap_or(PIECE_SIZE);
+        args.max_attempts = self.max_attempts;
+        args.pipeline_depth = self.pipeline_depth;
+        args.http2 = self.http2;
+        args.asynchronous = self.asynchronous;
+        args.event_loop = self.event_loop;
+        args.max_time = self.max_time;
+        args.stats = self.stats;
+        Ok(())
+    }
+}
+
+impl FetchArgs {
+    fn apply(self, args: &mut Args) {
+        args.max_redirects = self.max_redirects;
+        args.compressed = self.compressed;
+    }
+}
+
+impl CommonArgs {
+    // The arguments for `urls` with these settings, and every option of the
+    // other modes at its default.
+    fn into_args(self, urls: Vec<Url>) -> io::Result<Args> {
+        let user_agent = match self.user_agent.as_deref() {
+            None => Some(DEFAULT_USER_AGENT.to_string()),
+            Some("") => None,
+            Some(value) => Some(parse_header(&format!("User-Agent: {}", value))?.1),
+        };
+        let bearer_token =
+            self.oauth2_bearer.or_else(|| env::var(BEARER_TOKEN_ENV).ok().filter(|token| !token.is_empty()));
+        if let Some(token) = &bearer_token {
+            parse_header(&format!("Authorization: Bearer {}", token))?;
+            if self.credentials.is_some() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "--user and --oauth2-bearer cannot be combined"));
+            }
+        }
+        let ip_family = match (self.ipv4, self.ipv6) {
+            (true, _) => IpFamily::V4,
+            (_, true) => IpFamily::V6,
+            _ => IpFamily::Any,
+        };
+        Ok(Args {
+            urls,
+            input_file: None,
+            mirrors: Vec::new(),
+            max_concurrent_downloads: 1,
+            num_threads: None,
+            tls: TlsOptions {
+                ca_file: self.cacert,
+                insecure: self.insecure,
+                cert_file: self.cert,
+                key_file: self.key,
+                pinned_keys: self.pinned_keys,
+            },
+            http2: false,
+            asynchronous: false,
+            event_loop: false,
+            decompress: true,
+            compressed: false,
+            max_redirects: DEFAULT_MAX_REDIRECTS,
+            cache: None,
+            pipeline_depth: 1,
+            headers: self.headers,
+            user_agent,
+            credentials: self.credentials,
+            bearer_token,
+            netrc: if self.no_netrc { None } else { self.netrc_file.or_else(netrc::default_path) },
+            ip_family,
+            socket_options: SocketOptions {
+                nodelay: self.tcp_nodelay,
+                recv_buffer_size: self.recv_buffer_size,
+                send_buffer_size: self.send_buffer_size,
+            },
+            connect_timeout: self.connect_timeout.unwrap_or(CONNECT_TIMEOUT),
+            read_timeout: self.read_timeout.unwrap_or(READ_TIMEOUT),
+            doh_url: self.doh_url,
+            unix_socket: self.unix_socket,
+            request: None,
+            want_repr_digest: false,
+            output: None,
+            remote_name: false,
+            continue_download: false,
+            mmap: false,
+            sync: false,
+            piece_hashes: None,
+            max_buffer: DEFAULT_BUFFER_LIMIT,
+            spill: None,
+            chunk_size: PIECE_SIZE,
+            read_size: self.read_buffer_size.unwrap_or(DEFAULT_READ_SIZE),
+            max_attempts: MAX_ATTEMPTS,
+            byte_range: None,
+            bench: None,
+            probe: false,
+            checksums: None,
+            hashes: vec![Algorithm::Sha256],
+            hash_threads: None,
+            expect: None,
+            write_checksum: None,
+            strict: true,
+            stdout: false,
+            max_time: None,
+            stats: false,
+            stats_json: None,
+            json: false,
+            metrics_addr: self.metrics_addr,
+            record: self.record,
+            replay: self.replay,
+            warc: self.warc,
+            log_level: match self.verbose {
+                _ if self.quiet => LevelFilter::WARN,
+                0 => LevelFilter::INFO,
+                1 => LevelFilter::DEBUG,
+                _ => LevelFilter::TRACE,
+            },
+        })
+    }
+
+    // `get`, `head`, `put`, `post` and `request`: one request with `method`.
+    fn request(self, url: Url, method: &str, body: Option<BodySource>, save: SaveArgs) -> io::Result<Args> {
+        let mut args = self.into_args(vec![url])?;
+        args.request = Some(Request { method: method.to_string(), body });
+        save.apply(&mut args);
+        Ok(args)
+    }
+}
+
+// The URLs of a download or hash, then an optional thread count; --threads
+// is the same, for HTTPC_THREADS.
+fn urls_and_threads(targets: &[String], threads: Option<usize>) -> io::Result<(Vec<Url>, Option<usize>)> {
+    let (urls, num_threads) = match targets {
+        [urls @ .., last] if !last.is_empty() && last.bytes().all(|b| b.is_ascii_digit()) => {
+            let num_threads = last.parse::<usize>().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "Number of threads must be a positive integer")
+            })?;
+            if num_threads == 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Number of threads must be at least 1"));
+            }
+            (urls, Some(num_threads))
+        }
+        urls => (urls, threads),
+    };
+    let urls = urls.iter().map(|url| Url::parse(url)).collect::<io::Result<_>>()?;
+    Ok((urls, num_threads))
+}
+
+// Fails on the first option in `options`, or --expect, --mirror, --range
+// or --async, that is given for anything but a single URL to download.
+fn single_download(args: &Args, options: &[(bool, &str)]) -> io::Result<()> {
+    let single = args.request.is_none() && args.urls.len() == 1 && args.input_file.is_none();
+    let shared = [
+        (args.expect.is_some(), "--expect"),
+        (!args.mirrors.is_empty(), "--mirror"),
+        (args.byte_range.is_some(), "--range"),
+        (args.asynchronous, "--async"),
+    ];
+    match shared.iter().chain(options).find(|(given, _)| *given && !single) {
+        Some((_, option)) => {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} needs a single URL to download", option)))
+        }
+        None => Ok(()),
+    }
+}
+
+// `args` with "download" put first unless they start with another mode (or
+// a request for help), so that a bare URL downloads.
+fn with_mode(args: &[String]) -> Vec<String> {
+    let mut args = args.to_vec();
+    let command = Cli::command();
+    match args.get(1).map(String::as_str) {
+        None | Some("help" | "-h" | "--help" | "-V" | "--version") => {}
+        Some(word) if command.find_subcommand(word).is_some() => {}
+        Some(_) => args.insert(1, "download".to_string()),
+    }
+    args
+}
+
+// The mode the command line picks and its options.
+pub fn parse(args: &[String]) -> io::Result<Mode> {
+    Cli::try_parse_from(with_mode(args))
+        .map(|cli| cli.mode)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+// `args` with the options the HTTPC_* variables in `var` set put before the
+// command line's own, after the mode, leaving out those the command line
+// gives itself and those the mode does not take.
+pub fn with_env(args: &[String], var: impl Fn(&str) -> Option<String>) -> io::Result<Vec<String>> {
+    let args = with_mode(args);
+    let command = Cli::command();
+    let Some(mode) = args.get(1).and_then(|mode| command.find_subcommand(mode)) else { return Ok(args) };
+    let takes = |flag: &str| mode.get_arguments().any(|arg| arg.get_long() == flag.strip_prefix("--"));
+    let given = |flag: &str| {
+        args.iter().skip(2).any(|arg| arg == flag || arg.strip_prefix(flag).is_some_and(|rest| rest.starts_with('=')))
+    };
+    // An --expect on the command line picks the algorithm itself, so an
+    // HTTPC_HASH with another one does not make it fail.
+    let overridden = |flag: &str| given(flag) || (flag == "--hash" && given("--expect"));
+    let mut merged = args[..2].to_vec();
+    for (name, flag) in ENV_OPTIONS {
+        if let Some(value) = var(name).filter(|value| !value.is_empty() && takes(flag) && !overridden(flag)) {
+            merged.extend([flag.to_string(), value]);
+        }
+    }
+    for (name, flag) in ENV_SWITCHES {
+        match var(name).as_deref().map(str::trim) {
+            None | Some("" | "0" | "false" | "no") => {}
+            Some("1" | "true" | "yes") if takes(flag) && !given(flag) => merged.push(flag.to_string()),
+            Some("1" | "true" | "yes") => {}
+            Some(value) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} must be 1 or 0, not {:?}", name, value)));
+            }
+        }
+    }
+    merged.extend(args[2..].iter().cloned());
+    Ok(merged)
+}
+
+// Pins use the same encoding as HPKP and curl: base64 of the SHA-256 digest
+// of the DER SubjectPublicKeyInfo. A "sha256//" prefix is accepted.
+pub fn parse_pin(value: &str) -> io::Result<[u8; 32]> {
+    let encoded = value.strip_prefix("sha256//").unwrap_or(value);
+    BASE64
+        .decode(encoded)
+        .ok()
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid SHA-256 pin: {}", value))
+        })
+}
+
+// Parses "Name: value" for -H. Header names must be tokens and values may not
+// contain CR, LF or NUL, so nothing can smuggle extra lines into the request.
+pub fn parse_header(value: &str) -> io::Result<(String, String)> {
+    let invalid = |reason: &str| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid header {:?}: {}", value, reason))
+    };
+    let (name, field) = value.split_once(':').ok_or_else(|| invalid("expected \"Name: value\""))?;
+    let name = name.trim();
+    if !request::is_token(name) {
+        return Err(invalid("the name must be a token"));
+    }
+    if field.contains(['\r', '\n', '\0']) {
+        return Err(invalid("the value must not contain CR, LF or NUL"));
+    }
+    Ok((name.to_string(), field.trim().to_string()))
+}
+
+// Splits "user:password" at the first colon, as curl's -u does; user names
+// cannot contain one in Basic authentication anyway.
+pub fn parse_credentials(value: &str) -> io::Result<Credentials> {
+    match value.split_once(':') {
+        Some((user, password)) if !user.is_empty() => {
+            Ok(Credentials { user: user.to_string(), password: password.to_string() })
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "--user expects user:password")),
+    }
+}
+
+// Methods are case-sensitive tokens; "delete" is sent as given.
+fn parse_method(value: &str) -> io::Result<String> {
+    if !request::is_token(value) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid method: {:?}", value)));
+    }
+    Ok(value.to_string())
+}
+
+// "-" stands for stdin, as in curl.
+fn body_source(value: &str) -> BodySource {
+    match value {
+        "-" => BodySource::Stdin,
+        file => BodySource::File(PathBuf::from(file)),
+    }
+}
+
+// A positive count, of threads, attempts or the like.
+fn positive(value: &str) -> io::Result<usize> {
+    value
+        .parse()
+        .ok()
+        .filter(|&n| n > 0)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "must be a positive integer"))
+}
+
+// A size held in memory, which must fit a usize.
+fn memory_size(value: &str) -> io::Result<usize> {
+    usize::try_from(parse_size(value)?).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "is too large"))
+}
+
+// A socket buffer size, which the system takes as a C int.
+fn socket_buffer_size(value: &str) -> io::Result<usize> {
+    let size = parse_size(value)?;
+    i32::try_from(size)
+        .map(|_| size as usize)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "is too large for a socket buffer"))
+}
+
+// A positive byte count, optionally with a K, M or G (binary) suffix.
+pub fn parse_size(value: &str) -> io::Result<u64> {
+    let (digits, unit) = match value.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&value[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&value[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&value[..i], 1 << 30),
+        _ => (value, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|count| count.checked_mul(unit))
+        .filter(|&size| size > 0)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid size {}; use e.g. 65536, 256K or 1M", value)))
+}
+
+// "first-last" or "first-", inclusive byte offsets like the Range header's.
+pub fn parse_byte_range(value: &str) -> io::Result<(usize, Option<usize>)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid range {}; use e.g. 1000-2047 or 1000-", value));
+    let (first, last) = value.split_once('-').ok_or_else(invalid)?;
+    let first = first.parse().map_err(|_| invalid())?;
+    match last {
+        "" => Ok((first, None)),
+        last => match last.parse() {
+            Ok(last) if last >= first => Ok((first, Some(last))),
+            _ => Err(invalid()),
+        },
+    }
+}
+
+// A positive number of seconds, e.g. "30" or "2.5".
+pub fn parse_seconds(value: &str) -> io::Result<Duration> {
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|seconds| seconds.is_finite() && *seconds > 0.0)
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid time {}; use seconds, e.g. 30 or 2.5", value)))
+}
+
+// "<algorithm>:<hex digest>", e.g. "sha256:" and the digits sha256sum
+// prints; returns the algorithm and the lowercase digits.
+pub fn parse_expect(value: &str) -> io::Result<(Algorithm, String)> {
+    let (name, hex) = value
+        .split_once(':')
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "--expect takes <algorithm>:<hex digest>"))?;
+    let algorithm = Algorithm::parse(name)?;
+    if hex.len() != algorithm.len() * 2 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("--expect takes {}:<{} hex digits>", algorithm.name(), algorithm.len() * 2),
+        ));
+    }
+    Ok((algorithm, hex.to_ascii_lowercase()))
+}
+
+// The -i file: one URL per line; blank lines and lines starting with '#' are
+// skipped.
+pub fn parse_url_list(text: &str) -> io::Result<Vec<Url>> {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| (i, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| {
+            Url::parse(line).map_err(|e| io::Error::new(e.kind(), format!("Line {} of the URL list: {}", i + 1, e)))
+        })
+        .collect()
+}
@@ -0,0 +1,104 @@
+use std::sync::{Arc, Mutex};
+
+use crate::auth::Auth;
+use crate::connector::Connector;
+use crate::cookies::CookieJar;
+use crate::headers::HeaderMap;
+use crate::hooks::{Hooks, Request};
+use crate::response::Response;
+use crate::url::Url;
+
+pub const DEFAULT_MAX_REDIRECTS: usize = 10;
+// How much of a response body is read at a time (--read-buffer-size).
+pub const DEFAULT_READ_SIZE: usize = 256 * 1024;
+pub const DEFAULT_USER_AGENT: &str = concat!("http-client-rust/", env!("CARGO_PKG_VERSION"));
+
+// Everything needed to issue a request: how to connect, plus the extra
+// headers sent on the size probe and on every range request.
+#[derive(Clone)]
+pub struct Client {
+    pub connector: Connector,
+    pub headers: Vec<(String, String)>,
+    pub max_redirects: usize,
+    pub read_size: usize,
+    // Validator from the size probe, sent as If-Range with every Range.
+    pub if_range: Option<String>,
+    // Where the --range slice starts in the resource. Range requests give
+    // offsets in the slice, shifted by this on the wire and back.
+    pub range_offset: usize,
+    // Shared by every clone, so cookies set on the probe reach all workers.
+    pub cookies: Arc<Mutex<CookieJar>>,
+    // Credentials and the cached authentication challenge, also shared.
+    pub auth: Auth,
+    pub hooks: Hooks,
+}
+
+impl Client {
+    pub fn new(connector: Connector) -> Client {
+        Client {
+            connector,
+            headers: Vec::new(),
+            max_redirects: DEFAULT_MAX_REDIRECTS,
+            read_size: DEFAULT_READ_SIZE,
+            if_range: None,
+            range_offset: 0,
+            cookies: Arc::new(Mutex::new(CookieJar::default())),
+            auth: Auth::default(),
+            hooks: Hooks::default(),
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Client {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn on_request(mut self, hook: impl Fn(&mut Request) + Send + Sync + 'static) -> Client {
+        self.hooks.on_request(hook);
+        self
+    }
+
+    pub fn on_response(mut self, hook: impl Fn(&Url, &Response) + Send + Sync + 'static) -> Client {
+        self.hooks.on_response(hook);
+        self
+    }
+
+    // The extra headers for one request, as the request hooks left them.
+    pub fn request_headers(&self, method: &str, url: &Url) -> Vec<(String, String)> {
+        let mut request = Request { method, url, headers: self.headers.clone() };
+        self.hooks.request(&mut request);
+        request.headers
+    }
+
+    // The extra headers for an HTTP/1.1 request, plus the cookies that apply
+    // to `url`.
+    pub fn header_fields(&self, method: &str, url: &Url) -> Vec<(String, String)> {
+        let mut fields = self.request_headers(method, url);
+        if let Some(cookie) = self.cookie_header(url) {
+            fields.push(("Cookie".to_string(), cookie));
+        }
+        fields
+    }
+
+    pub fn cookie_header(&self, url: &Url) -> Option<String> {
+        self.cookies.lock().unwrap().header(url)
+    }
+
+    // Handles every response from `url`: its cookies are stored and the
+    // response hooks see it.
+    pub fn handle_response(&self, url: &Url, response: &Response) {
+        self.store_cookies(url, &response.headers);
+        self.hooks.response(url, response);
+    }
+
+    // Records the Set-Cookie headers of a response from `url`.
+    pub fn store_cookies(&self, url: &Url, headers: &HeaderMap) {
+        let set_cookies = headers.get_all("set-cookie");
+        if !set_cookies.is_empty() {
+            let mut jar = self.cookies.lock().unwrap();
+            for set_cookie in set_cookies {
+                jar.store(url, set_cookie);
+            }
+        }
+    }
+}
@@ -0,0 +1,13 @@
+use clap::CommandFactory;
+use clap_complete::Shell;
+
+use crate::cli::Cli;
+
+// A completion script for `program` in `shell`, generated from the clap
+// command: the modes as the first word, then the options of the mode typed,
+// with file names after those taking a path.
+pub fn script(shell: Shell, program: &str) -> Vec<u8> {
+    let mut script = Vec::new();
+    clap_complete::generate(shell, &mut Cli::command(), program, &mut script);
+    script
+}
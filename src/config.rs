@@ -0,0 +1,365 @@
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::callbacks::Callbacks;
+use crate::cancel::CancellationToken;
+use crate::client::Client;
+use crate::connector::{CONNECT_TIMEOUT, READ_TIMEOUT};
+use crate::deadline::Deadline;
+use crate::hash::{self, Algorithm, ChecksumStyle};
+use crate::queue::MAX_ATTEMPTS;
+use crate::ramp::MAX_THREADS;
+use crate::stats::SummarySlot;
+use crate::store::{DEFAULT_BUFFER_LIMIT, PIECE_SIZE};
+use crate::stream::Sender;
+use crate::url::Url;
+
+// The settings every download of a run shares, made with
+// DownloadConfig::builder() so that only what differs from the defaults
+// needs to be named.
+#[derive(Clone)]
+pub struct DownloadConfig {
+    // None picks the count automatically, up to max_threads.
+    pub num_threads: Option<usize>,
+    pub max_threads: usize,
+    // Most bytes one range request asks for.
+    pub chunk_size: usize,
+    // Failed requests for the same bytes before they are given up on.
+    pub max_attempts: usize,
+    // How long each address of the server may take to accept a connection,
+    // and a read may wait for the server before the request fails.
+    pub connect_timeout: Duration,
+    pub read_timeout: Option<Duration>,
+    // Sent on every request of the download, besides the client's own.
+    pub headers: Vec<(String, String)>,
+    pub http2: bool,
+    // Drive every connection from the downloading thread with non-blocking
+    // sockets, instead of a thread each.
+    pub event_loop: bool,
+    pub decompress: bool,
+    pub pipeline_depth: usize,
+    pub want_repr_digest: bool,
+    pub output: Option<PathBuf>,
+    // Other servers with the same file to spread the range requests over.
+    pub mirrors: Vec<Url>,
+    pub remote_name: bool,
+    pub continue_download: bool,
+    pub mmap: bool,
+    pub sync: bool,
+    // Size of the pieces hashed for a manifest.
+    pub piece_hashes: Option<u64>,
+    // Bytes held in memory past the hashing position before threads wait.
+    pub max_buffer: usize,
+    // Bytes of those kept in memory, the rest going to a temporary file.
+    pub spill: Option<usize>,
+    // The slice of the resource to download, if not all of it.
+    pub byte_range: Option<(usize, Option<usize>)>,
+    // What the data is summarized with, the first one kept for --continue.
+    pub hashes: Vec<Algorithm>,
+    // Most threads BLAKE3 hashes on.
+    pub hash_threads: usize,
+    // Digest the data must have, lowercase hex.
+    pub expect: Option<(Algorithm, String)>,
+    pub write_checksum: Option<ChecksumStyle>,
+    // Whether bytes that cannot be downloaded end the download; otherwise
+    // they are zero-filled, and the digest no longer describes the resource.
+    pub strict: bool,
+    // Stream the ordered data to stdout, with --tee or "-o -".
+    pub stdout: bool,
+    // When to stop and leave the rest to --continue.
+    pub deadline: Option<Deadline>,
+    // Report what each connection did at the end, and where to save that as JSON.
+    pub stats: bool,
+    pub stats_json: Option<PathBuf>,
+    // Where the download leaves its summary when done, for --json.
+    pub summary: Option<SummarySlot>,
+    // Progress, chunks, retries and completion, for an embedding UI.
+    pub callbacks: Callbacks,
+    // Stops the download early, like the deadline, when cancelled.
+    pub cancel: CancellationToken,
+    // Where download_stream has the ordered bytes sent.
+    pub stream: Option<Sender>,
+}
+
+impl DownloadConfig {
+    pub fn builder() -> DownloadConfigBuilder {
+        DownloadConfigBuilder {
+            config: DownloadConfig {
+                num_threads: None,
+                max_threads: MAX_THREADS,
+                chunk_size: PIECE_SIZE,
+                max_attempts: MAX_ATTEMPTS,
+                connect_timeout: CONNECT_TIMEOUT,
+                read_timeout: Some(READ_TIMEOUT),
+                headers: Vec::new(),
+                http2: false,
+                event_loop: false,
+                decompress: true,
+                pipeline_depth: 1,
+                want_repr_digest: false,
+                output: None,
+                mirrors: Vec::new(),
+                remote_name: false,
+                continue_download: false,
+                mmap: false,
+                sync: false,
+                piece_hashes: None,
+                max_buffer: DEFAULT_BUFFER_LIMIT,
+                spill: None,
+                byte_range: None,
+                hashes: Vec::new(),
+                hash_threads: 1,
+                expect: None,
+                write_checksum: None,
+                strict: true,
+                stdout: false,
+                deadline: None,
+                stats: false,
+                stats_json: None,
+                summary: None,
+                callbacks: Callbacks::default(),
+                cancel: CancellationToken::default(),
+                stream: None,
+            },
+            max_time: None,
+            hash_threads: None,
+        }
+    }
+
+    // `client` with the download's headers and timeouts.
+    pub fn client(&self, client: &Client) -> Client {
+        let mut client = self.headers.iter().fold(client.clone(), |client, (name, value)| client.header(name, value));
+        client.connector = client.connector.with_timeouts(self.connect_timeout, self.read_timeout);
+        client
+    }
+}
+
+pub struct DownloadConfigBuilder {
+    config: DownloadConfig,
+    // Counted from build(), when the run starts.
+    max_time: Option<Duration>,
+    // None uses every CPU, counted in build().
+    hash_threads: Option<usize>,
+}
+
+impl DownloadConfigBuilder {
+    // None adds threads while they help.
+    pub fn threads(mut self, threads: Option<usize>) -> Self {
+        self.config.num_threads = threads;
+        self
+    }
+
+    pub fn chunk_size(mut self, bytes: usize) -> Self {
+        self.config.chunk_size = bytes;
+        self
+    }
+
+    pub fn max_attempts(mut self, attempts: usize) -> Self {
+        self.config.max_attempts = attempts;
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.config.connect_timeout = timeout;
+        self
+    }
+
+    // None lets reads wait for the server as long as it takes.
+    pub fn read_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.config.read_timeout = timeout;
+        self
+    }
+
+    pub fn max_time(mut self, limit: Option<Duration>) -> Self {
+        self.max_time = limit;
+        self
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.config.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    // Adds an algorithm to hash with; the first one is the digest returned.
+    pub fn hash(mut self, algorithm: Algorithm) -> Self {
+        if !self.config.hashes.contains(&algorithm) {
+            self.config.hashes.push(algorithm);
+        }
+        self
+    }
+
+    // None hashes on as many threads as there are CPUs.
+    pub fn hash_threads(mut self, threads: Option<usize>) -> Self {
+        self.hash_threads = threads;
+        self
+    }
+
+    pub fn expect(mut self, expect: Option<(Algorithm, String)>) -> Self {
+        self.config.expect = expect;
+        self
+    }
+
+    // Where the data is saved: a file, named after the resource with
+    // `remote_name`, and stdout.
+    pub fn output(mut self, output: Option<PathBuf>) -> Self {
+        self.config.output = output;
+        self
+    }
+
+    pub fn remote_name(mut self, remote_name: bool) -> Self {
+        self.config.remote_name = remote_name;
+        self
+    }
+
+    pub fn stdout(mut self, stdout: bool) -> Self {
+        self.config.stdout = stdout;
+        self
+    }
+
+    pub fn continue_download(mut self, continue_download: bool) -> Self {
+        self.config.continue_download = continue_download;
+        self
+    }
+
+    pub fn byte_range(mut self, byte_range: Option<(usize, Option<usize>)>) -> Self {
+        self.config.byte_range = byte_range;
+        self
+    }
+
+    pub fn mirrors(mut self, mirrors: Vec<Url>) -> Self {
+        self.config.mirrors = mirrors;
+        self
+    }
+
+    pub fn http2(mut self, http2: bool) -> Self {
+        self.config.http2 = http2;
+        self
+    }
+
+    pub fn event_loop(mut self, event_loop: bool) -> Self {
+        self.config.event_loop = event_loop;
+        self
+    }
+
+    pub fn decompress(mut self, decompress: bool) -> Self {
+        self.config.decompress = decompress;
+        self
+    }
+
+    pub fn pipeline_depth(mut self, depth: usize) -> Self {
+        self.config.pipeline_depth = depth;
+        self
+    }
+
+    pub fn want_repr_digest(mut self, want_repr_digest: bool) -> Self {
+        self.config.want_repr_digest = want_repr_digest;
+        self
+    }
+
+    pub fn mmap(mut self, mmap: bool) -> Self {
+        self.config.mmap = mmap;
+        self
+    }
+
+    pub fn sync(mut self, sync: bool) -> Self {
+        self.config.sync = sync;
+        self
+    }
+
+    pub fn piece_hashes(mut self, size: Option<u64>) -> Self {
+        self.config.piece_hashes = size;
+        self
+    }
+
+    pub fn max_buffer(mut self, bytes: usize) -> Self {
+        self.config.max_buffer = bytes;
+        self
+    }
+
+    pub fn spill(mut self, bytes: Option<usize>) -> Self {
+        self.config.spill = bytes;
+        self
+    }
+
+    pub fn write_checksum(mut self, style: Option<ChecksumStyle>) -> Self {
+        self.config.write_checksum = style;
+        self
+    }
+
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.config.strict = strict;
+        self
+    }
+
+    pub fn stats(mut self, stats: bool, json: Option<PathBuf>) -> Self {
+        self.config.stats = stats;
+        self.config.stats_json = json;
+        self
+    }
+
+    // Where to leave the summary of the download once it is done.
+    pub fn summary(mut self, slot: Option<SummarySlot>) -> Self {
+        self.config.summary = slot;
+        self
+    }
+
+    // A token kept by the caller, to cancel the download with.
+    pub fn cancellation(mut self, token: CancellationToken) -> Self {
+        self.config.cancel = token;
+        self
+    }
+
+    pub fn on_progress(mut self, callback: impl Fn(usize, usize) + Send + Sync + 'static) -> Self {
+        self.config.callbacks.on_progress(callback);
+        self
+    }
+
+    pub fn on_chunk_complete(mut self, callback: impl Fn((usize, usize)) + Send + Sync + 'static) -> Self {
+        self.config.callbacks.on_chunk_complete(callback);
+        self
+    }
+
+    pub fn on_retry(mut self, callback: impl Fn((usize, usize), &io::Error) + Send + Sync + 'static) -> Self {
+        self.config.callbacks.on_retry(callback);
+        self
+    }
+
+    pub fn on_complete(mut self, callback: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.config.callbacks.on_complete(callback);
+        self
+    }
+
+    // Checks the settings fit together. Without any --hash, the data is
+    // hashed with what `expect` names, else SHA-256.
+    pub fn build(mut self) -> io::Result<DownloadConfig> {
+        let config = &mut self.config;
+        let invalid = |message: &str| Err(io::Error::new(io::ErrorKind::InvalidInput, message.to_string()));
+        if config.num_threads == Some(0) || self.hash_threads == Some(0) {
+            return invalid("Number of threads must be at least 1");
+        }
+        if config.chunk_size == 0 || config.max_attempts == 0 || config.pipeline_depth == 0 {
+            return invalid("The chunk size, attempts and pipeline depth must be at least 1");
+        }
+        if config.connect_timeout.is_zero() || config.read_timeout.is_some_and(|timeout| timeout.is_zero()) {
+            return invalid("Timeouts must be longer than zero");
+        }
+        if config.hashes.is_empty() {
+            config.hashes.push(config.expect.as_ref().map_or(Algorithm::Sha256, |(algorithm, _)| *algorithm));
+        }
+        if let Some((algorithm, _)) = &config.expect {
+            if !config.hashes.contains(algorithm) {
+                return invalid(&format!("The expected digest is {}, which is not hashed", algorithm.name()));
+            }
+        }
+        if config.piece_hashes.is_some() && config.output.is_none() && !config.remote_name {
+            return invalid("Piece hashes are written next to the output file, so they need one");
+        }
+        if config.output.is_some() && config.remote_name {
+            return invalid("Give either an output file or remote_name, not both");
+        }
+        config.hash_threads = self.hash_threads.unwrap_or_else(hash::default_threads);
+        config.deadline = self.max_time.map(|limit| Deadline::new(limit, Instant::now()));
+        Ok(self.config)
+    }
+}
@@ -0,0 +1,691 @@
+use std::io::{self, Read, Write};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tracing::warn;
+
+use crate::cassette::{Cassette, CassetteStream};
+use crate::error::DownloadError;
+use crate::proxy::{Proxy, ProxySettings};
+use crate::resolver::{Resolver, SystemResolver};
+use crate::transport::{Connection, Transport};
+use crate::url::Url;
+
+// A connection to the server, either plaintext or wrapped in TLS.
+pub enum Stream {
+    Plain(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<rustls::StreamOwned<rustls::ClientConnection, TcpStream>>),
+    // Recorded to or replayed from a --record/--replay file.
+    Cassette(Box<CassetteStream>),
+}
+
+// A connection to the server as opened, before any TLS.
+pub enum Opened {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+// How a connection to a URL is secured once open.
+pub enum Layer {
+    Plain,
+    // TLS, checking the certificate against this name.
+    #[cfg(feature = "tls")]
+    Tls(rustls::pki_types::ServerName<'static>),
+}
+
+// The layer `url`'s scheme calls for; schemes other than http and https,
+// and https without the `tls` feature, are refused.
+pub fn layer(url: &Url) -> io::Result<Layer> {
+    match url.scheme.as_str() {
+        "http" => Ok(Layer::Plain),
+        #[cfg(feature = "tls")]
+        "https" => rustls::pki_types::ServerName::try_from(url.host.clone())
+            .map(Layer::Tls)
+            .map_err(|e| DownloadError::Tls(format!("Invalid TLS server name {}: {}", url.host, e)).into()),
+        #[cfg(not(feature = "tls"))]
+        "https" => Err(DownloadError::Tls(
+            "HTTPS support is not compiled in; rebuild with the `tls` feature".to_string(),
+        )
+        .into()),
+        scheme => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unsupported URL scheme: {}", scheme),
+        )),
+    }
+}
+
+impl Stream {
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.sock.set_read_timeout(timeout),
+            Stream::Cassette(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.read(buf).map_err(tls_error),
+            Stream::Cassette(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.write(buf).map_err(tls_error),
+            Stream::Cassette(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.flush().map_err(tls_error),
+            Stream::Cassette(stream) => stream.flush(),
+        }
+    }
+}
+
+// rustls reports a failed handshake, such as an untrusted certificate, as an
+// I/O error on the first read or write.
+#[cfg(feature = "tls")]
+pub fn tls_error(e: io::Error) -> io::Error {
+    match e.get_ref().and_then(|inner| inner.downcast_ref::<rustls::Error>()) {
+        Some(tls) => DownloadError::Tls(format!("TLS error: {}", tls)).into(),
+        None => e,
+    }
+}
+
+// A connection a worker keeps open between requests to the same origin.
+pub struct KeepAlive<C = Stream> {
+    open: Option<(String, C)>,
+    // Connections opened since take_opened last asked, for --stats.
+    opened: usize,
+}
+
+impl<C> Default for KeepAlive<C> {
+    fn default() -> Self {
+        KeepAlive { open: None, opened: 0 }
+    }
+}
+
+impl<C: Connection> KeepAlive<C> {
+    // Hands out the open connection if it goes to `url`'s origin, otherwise a
+    // new one. The flag tells whether the connection was reused.
+    pub fn take<T: Transport<Connection = C>>(&mut self, transport: &T, url: &Url) -> io::Result<(C, bool)> {
+        match self.open.take() {
+            Some((origin, stream)) if origin == url.origin() => Ok((stream, true)),
+            _ => {
+                let stream = transport.connect(url)?;
+                self.opened += 1;
+                Ok((stream, false))
+            }
+        }
+    }
+
+    // How many connections were opened since the last call.
+    pub fn take_opened(&mut self) -> usize {
+        std::mem::take(&mut self.opened)
+    }
+
+    // Keeps a connection whose last response left it usable.
+    pub fn put_back(&mut self, url: &Url, stream: C) {
+        self.open = Some((url.origin(), stream));
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    // PEM bundle of trusted CA certificates replacing the built-in roots.
+    pub ca_file: Option<PathBuf>,
+    // Accept any server certificate. Only meant for test environments.
+    pub insecure: bool,
+    // PEM client certificate chain and private key presented for mutual TLS.
+    pub cert_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
+    // SHA-256 hashes of acceptable leaf SubjectPublicKeyInfo structures. When
+    // non-empty, the server key must match one of them in addition to the
+    // usual certificate validation.
+    pub pinned_keys: Vec<[u8; 32]>,
+}
+
+// How long one address may take to accept a connection before the next
+// address of the host is tried, unless the download sets its own.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// How long a read may wait for the server to send anything before the request
+// fails, and is retried, so a stalled server cannot hang a download.
+pub const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Address family allowed for server connections (--ipv4 / --ipv6).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IpFamily {
+    #[default]
+    Any,
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    fn allows(self, addr: &SocketAddr) -> bool {
+        match self {
+            IpFamily::Any => true,
+            IpFamily::V4 => addr.is_ipv4(),
+            IpFamily::V6 => addr.is_ipv6(),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            IpFamily::Any => "IP",
+            IpFamily::V4 => "IPv4",
+            IpFamily::V6 => "IPv6",
+        }
+    }
+}
+
+// Options set on every TCP connection (--tcp-nodelay, --recv-buffer-size,
+// --send-buffer-size); the system defaults are kept for those left unset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    pub nodelay: bool,
+    pub recv_buffer_size: Option<usize>,
+    pub send_buffer_size: Option<usize>,
+}
+
+impl SocketOptions {
+    pub fn apply(&self, tcp: &TcpStream) -> io::Result<()> {
+        if self.nodelay {
+            tcp.set_nodelay(true)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            set_buffer_size(tcp, "--recv-buffer-size", size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            set_buffer_size(tcp, "--send-buffer-size", size)?;
+        }
+        Ok(())
+    }
+}
+
+// SO_RCVBUF or SO_SNDBUF, named by its option. Linux doubles the size asked
+// for and caps it at net.core.rmem_max / wmem_max.
+#[cfg(unix)]
+fn set_buffer_size(tcp: &TcpStream, option: &str, size: usize) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    let name = if option == "--recv-buffer-size" { libc::SO_RCVBUF } else { libc::SO_SNDBUF };
+    let value = libc::c_int::try_from(size)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is too large", option)))?;
+    // SAFETY: the descriptor is open for as long as `tcp` is borrowed, and
+    // the value is a c_int of the size given.
+    let result = unsafe {
+        libc::setsockopt(
+            tcp.as_raw_fd(),
+            libc::SOL_SOCKET,
+            name,
+            (&value as *const libc::c_int).cast(),
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        let e = io::Error::last_os_error();
+        return Err(io::Error::new(e.kind(), format!("Cannot set {}: {}", option, e)));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_buffer_size(_: &TcpStream, option: &str, _: usize) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, format!("{} is not available on this platform", option)))
+}
+
+// Opens connections for a URL. The TLS configuration is built once and shared
+// by every connection, so cloning a Connector into worker threads is cheap.
+#[derive(Clone)]
+pub struct Connector {
+    #[cfg(feature = "tls")]
+    tls_config: Arc<rustls::ClientConfig>,
+    // Same configuration, but advertising h2 via ALPN for HTTP/2 connections.
+    #[cfg(all(feature = "tls", feature = "http2"))]
+    h2_tls_config: Arc<rustls::ClientConfig>,
+    proxies: Arc<ProxySettings>,
+    ip_family: IpFamily,
+    socket_options: SocketOptions,
+    connect_timeout: Duration,
+    // How long a read may wait for the server; None waits for ever.
+    read_timeout: Option<Duration>,
+    resolver: Arc<dyn Resolver>,
+    // Every connection goes to this socket instead; proxies and DNS are unused.
+    unix_socket: Option<PathBuf>,
+    // The address each host:port was last reached at, tried first next time
+    // so that every worker does not wait out the same dead address.
+    working: Arc<Mutex<HashMap<String, SocketAddr>>>,
+    // Where connections are recorded to or replayed from.
+    cassettes: Vec<Arc<Cassette>>,
+}
+
+impl Connector {
+    #[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+    pub fn new(tls_options: &TlsOptions) -> io::Result<Connector> {
+        #[cfg(feature = "tls")]
+        let tls_config = tls::client_config(tls_options)?;
+        #[cfg(all(feature = "tls", feature = "http2"))]
+        let h2_tls_config = {
+            let mut config = tls_config.clone();
+            config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+            Arc::new(config)
+        };
+        Ok(Connector {
+            #[cfg(feature = "tls")]
+            tls_config: Arc::new(tls_config),
+            #[cfg(all(feature = "tls", feature = "http2"))]
+            h2_tls_config,
+            proxies: Arc::default(),
+            ip_family: IpFamily::Any,
+            socket_options: SocketOptions::default(),
+            connect_timeout: CONNECT_TIMEOUT,
+            read_timeout: Some(READ_TIMEOUT),
+            resolver: Arc::new(SystemResolver),
+            unix_socket: None,
+            working: Arc::default(),
+            cassettes: Vec::new(),
+        })
+    }
+
+    pub fn with_ip_family(mut self, ip_family: IpFamily) -> Connector {
+        self.ip_family = ip_family;
+        self
+    }
+
+    pub fn with_socket_options(mut self, socket_options: SocketOptions) -> Connector {
+        self.socket_options = socket_options;
+        self
+    }
+
+    pub fn with_timeouts(mut self, connect: Duration, read: Option<Duration>) -> Connector {
+        self.connect_timeout = connect;
+        self.read_timeout = read;
+        self
+    }
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout
+    }
+
+    // Sets the socket options and the read timeout on a connection to the
+    // server or a proxy.
+    pub fn tune(&self, tcp: &TcpStream) -> io::Result<()> {
+        self.socket_options.apply(tcp)?;
+        tcp.set_read_timeout(self.read_timeout)
+    }
+
+    pub fn with_unix_socket(mut self, path: PathBuf) -> Connector {
+        self.unix_socket = Some(path);
+        self
+    }
+
+    pub fn unix_socket(&self) -> Option<&Path> {
+        self.unix_socket.as_deref()
+    }
+
+    // Adds a cassette; a replay answers in place of the network, and the
+    // recordings get what it or the network sends.
+    pub fn with_cassette(mut self, cassette: Arc<Cassette>) -> Connector {
+        self.cassettes.push(cassette);
+        self
+    }
+
+    pub fn with_resolver(mut self, resolver: Arc<dyn Resolver>) -> Connector {
+        self.resolver = resolver;
+        self
+    }
+
+    // A TCP connection to the first of `url`'s addresses that accepts one.
+    // Through a proxy the proxy resolves names instead.
+    pub fn connect_direct(&self, url: &Url) -> io::Result<TcpStream> {
+        let key = url.socket_addr();
+        let mut addrs = self.resolve(url)?;
+        if let Some(working) = self.working.lock().unwrap().get(&key) {
+            if let Some(i) = addrs.iter().position(|addr| addr == working) {
+                addrs[..=i].rotate_right(1);
+            }
+        }
+        let (tcp, addr) = connect_any(&url.host, &addrs, self.connect_timeout)
+            .map_err(|e| DownloadError::Connect(format!("Cannot connect to {}: {}", url.host, e)))?;
+        self.working.lock().unwrap().insert(key, addr);
+        Ok(tcp)
+    }
+
+    fn resolve(&self, url: &Url) -> io::Result<Vec<SocketAddr>> {
+        let ips = match url.host.parse::<IpAddr>() {
+            Ok(ip) => vec![ip],
+            Err(_) => self
+                .resolver
+                .resolve(&url.host)
+                .map_err(|e| DownloadError::Dns(format!("Cannot resolve {}: {}", url.host, e)))?,
+        };
+        let addrs: Vec<SocketAddr> = ips
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, url.port))
+            .filter(|addr| self.ip_family.allows(addr))
+            .collect();
+        if addrs.is_empty() {
+            return Err(DownloadError::Dns(format!("{} has no {} address", url.host, self.ip_family.name())).into());
+        }
+        Ok(addrs)
+    }
+
+    pub fn with_proxies(mut self, proxies: ProxySettings) -> Connector {
+        self.proxies = Arc::new(proxies);
+        self
+    }
+
+    pub fn proxy(&self, url: &Url) -> Option<&Proxy> {
+        self.proxies.for_url(url).filter(|_| self.unix_socket.is_none())
+    }
+
+    // The proxy that forwards plaintext requests to `url`; those requests
+    // name the absolute URL as their target.
+    pub fn forward_proxy(&self, url: &Url) -> Option<&Proxy> {
+        self.proxy(url).filter(|_| url.scheme == "http")
+    }
+
+    #[cfg(all(feature = "tls", feature = "http2"))]
+    pub fn h2_tls_config(&self) -> Arc<rustls::ClientConfig> {
+        Arc::clone(&self.h2_tls_config)
+    }
+
+    #[cfg(all(feature = "tls", feature = "async"))]
+    pub fn tls_config(&self) -> Arc<rustls::ClientConfig> {
+        Arc::clone(&self.tls_config)
+    }
+
+    // The client side of a TLS session with the server named, not yet
+    // handshaken, for a connection the caller drives itself.
+    #[cfg(feature = "tls")]
+    pub fn tls_connection(&self, server_name: rustls::pki_types::ServerName<'static>) -> io::Result<rustls::ClientConnection> {
+        rustls::ClientConnection::new(Arc::clone(&self.tls_config), server_name)
+            .map_err(|e| DownloadError::Tls(format!("TLS setup failed: {}", e)).into())
+    }
+
+    pub fn connect(&self, url: &Url) -> io::Result<Stream> {
+        // A replay never touches the network.
+        let mut stream = match self.cassettes.iter().find(|cassette| cassette.is_replay()) {
+            Some(cassette) => Stream::Cassette(Box::new(cassette.connection(url, None))),
+            None => self.network_stream(url)?,
+        };
+        for cassette in self.cassettes.iter().filter(|cassette| !cassette.is_replay()) {
+            stream = Stream::Cassette(Box::new(cassette.connection(url, Some(stream))));
+        }
+        Ok(stream)
+    }
+
+    fn network_stream(&self, url: &Url) -> io::Result<Stream> {
+        let layer = layer(url)?;
+        let tcp = match self.open(url)? {
+            Opened::Tcp(tcp) => tcp,
+            #[cfg(unix)]
+            Opened::Unix(stream) => return Ok(Stream::Unix(stream)),
+        };
+        match layer {
+            Layer::Plain => Ok(Stream::Plain(tcp)),
+            #[cfg(feature = "tls")]
+            Layer::Tls(server_name) => {
+                Ok(Stream::Tls(Box::new(rustls::StreamOwned::new(self.tls_connection(server_name)?, tcp))))
+            }
+        }
+    }
+
+    // A connection to the server for `url`, before any TLS: over the Unix
+    // socket if one is set, else TCP through the proxy or straight to the
+    // server, with the socket options set. The blocking, async and event
+    // loop downloads all connect through it.
+    pub fn open(&self, url: &Url) -> io::Result<Opened> {
+        if let Some(path) = &self.unix_socket {
+            #[cfg(unix)]
+            return connect_unix(url, path).map(Opened::Unix);
+            #[cfg(not(unix))]
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Unix domain sockets such as {} are not available on this platform", path.display()),
+            ));
+        }
+        // HTTPS goes through a CONNECT tunnel, so TLS still ends at the server.
+        let tcp = match self.proxy(url) {
+            Some(proxy) if url.scheme == "https" => proxy.tunnel(url)?,
+            Some(proxy) => proxy.connect()?,
+            None => self.connect_direct(url)?,
+        };
+        self.tune(&tcp)?;
+        Ok(Opened::Tcp(tcp))
+    }
+}
+
+// The server behind a Unix domain socket only gets plaintext HTTP.
+#[cfg(unix)]
+pub fn connect_unix(url: &Url, path: &Path) -> io::Result<UnixStream> {
+    if url.scheme != "http" {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "--unix-socket only carries http:// URLs"));
+    }
+    UnixStream::connect(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("Cannot connect to {}: {}", path.display(), e)))
+}
+
+// Tries each address in order, each for up to `timeout`, reporting the ones
+// that fail; the error of the last one is returned when none accepts.
+pub fn connect_any(host: &str, addrs: &[SocketAddr], timeout: Duration) -> io::Result<(TcpStream, SocketAddr)> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, format!("{} has no address", host));
+    for (i, addr) in addrs.iter().enumerate() {
+        match TcpStream::connect_timeout(addr, timeout) {
+            Ok(tcp) => return Ok((tcp, *addr)),
+            Err(e) => {
+                if i + 1 < addrs.len() {
+                    warn!("Connecting to {} at {} failed, trying the next address: {}", host, addr, e);
+                }
+                last_error = e;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+#[cfg(feature = "tls")]
+mod tls {
+    use std::io;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+    use rustls::client::WebPkiServerVerifier;
+    use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+    use rustls::{CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+    use sha2::{Digest, Sha256};
+
+    use super::TlsOptions;
+
+    pub fn client_config(options: &TlsOptions) -> io::Result<ClientConfig> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| io::Error::other(format!("TLS setup failed: {}", e)))?;
+
+        let verifier: Arc<dyn ServerCertVerifier> = if options.insecure {
+            Arc::new(NoVerification(provider))
+        } else {
+            WebPkiServerVerifier::builder_with_provider(Arc::new(root_store(options)?), provider)
+                .build()
+                .map_err(|e| io::Error::other(format!("TLS setup failed: {}", e)))?
+        };
+        let verifier: Arc<dyn ServerCertVerifier> = if options.pinned_keys.is_empty() {
+            verifier
+        } else {
+            Arc::new(PinnedVerifier { inner: verifier, pins: options.pinned_keys.clone() })
+        };
+        let builder = builder.dangerous().with_custom_certificate_verifier(verifier);
+
+        match (&options.cert_file, &options.key_file) {
+            (Some(cert_file), Some(key_file)) => {
+                let certs = load_certs(cert_file)?;
+                let key = PrivateKeyDer::from_pem_file(key_file).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("Failed to read private key {}: {}", key_file.display(), e),
+                    )
+                })?;
+                builder
+                    .with_client_auth_cert(certs, key)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid client identity: {}", e)))
+            }
+            (None, None) => Ok(builder.with_no_client_auth()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "A client certificate and its private key must be given together",
+            )),
+        }
+    }
+
+    fn root_store(options: &TlsOptions) -> io::Result<RootCertStore> {
+        let mut roots = RootCertStore::empty();
+        match &options.ca_file {
+            Some(path) => {
+                let (added, _) = roots.add_parsable_certificates(load_certs(path)?);
+                if added == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("No usable CA certificates in {}", path.display()),
+                    ));
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        Ok(roots)
+    }
+
+    fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+        CertificateDer::pem_file_iter(path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Failed to read certificates from {}: {}", path.display(), e),
+                )
+            })
+    }
+
+    // Accepts any certificate chain but still checks handshake signatures, so
+    // the connection is encrypted, just not authenticated.
+    #[derive(Debug)]
+    struct NoVerification(Arc<CryptoProvider>);
+
+    impl ServerCertVerifier for NoVerification {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.0.signature_verification_algorithms.supported_schemes()
+        }
+    }
+
+    // Rejects servers whose leaf public key does not hash to one of the pins,
+    // then defers to the wrapped verifier for the regular chain validation.
+    #[derive(Debug)]
+    struct PinnedVerifier {
+        inner: Arc<dyn ServerCertVerifier>,
+        pins: Vec<[u8; 32]>,
+    }
+
+    impl ServerCertVerifier for PinnedVerifier {
+        fn verify_server_cert(
+            &self,
+            end_entity: &CertificateDer<'_>,
+            intermediates: &[CertificateDer<'_>],
+            server_name: &ServerName<'_>,
+            ocsp_response: &[u8],
+            now: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            let cert = webpki::EndEntityCert::try_from(end_entity)
+                .map_err(|_| rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+            let spki_hash: [u8; 32] = Sha256::digest(cert.subject_public_key_info().as_ref()).into();
+            if !self.pins.contains(&spki_hash) {
+                return Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure));
+            }
+            self.inner.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            self.inner.verify_tls12_signature(message, cert, dss)
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            self.inner.verify_tls13_signature(message, cert, dss)
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.inner.supported_verify_schemes()
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use crate::url::Url;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Cookie {
+    name: String,
+    value: String,
+    domain: String,
+    // Set without a Domain attribute: only sent back to the exact host.
+    host_only: bool,
+    path: String,
+    secure: bool,
+}
+
+// Session cookies collected from Set-Cookie headers (RFC 6265, without
+// persistence: Expires is ignored and everything lives for one run).
+#[derive(Debug, Default)]
+pub struct CookieJar {
+    cookies: Vec<Cookie>,
+}
+
+impl CookieJar {
+    // Stores one Set-Cookie value received in a response from `url`. Cookies
+    // for a domain the host does not belong to are dropped, and Max-Age <= 0
+    // removes an existing cookie.
+    pub fn store(&mut self, url: &Url, set_cookie: &str) {
+        let mut attributes = set_cookie.split(';');
+        let (name, value) = match attributes.next().and_then(|pair| pair.split_once('=')) {
+            Some((name, value)) if !name.trim().is_empty() => (name.trim(), value.trim()),
+            _ => return,
+        };
+        let host = url.host.to_lowercase();
+        let mut cookie = Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            domain: host.clone(),
+            host_only: true,
+            path: default_path(&url.path),
+            secure: false,
+        };
+        let mut expired = false;
+        for attribute in attributes {
+            let (key, val) = attribute.split_once('=').unwrap_or((attribute, ""));
+            let val = val.trim();
+            match key.trim().to_lowercase().as_str() {
+                "domain" if !val.is_empty() => {
+                    let domain = val.trim_start_matches('.').to_lowercase();
+                    if !domain_matches(&host, &domain) {
+                        return;
+                    }
+                    cookie.domain = domain;
+                    cookie.host_only = false;
+                }
+                "path" if val.starts_with('/') => cookie.path = val.to_string(),
+                "secure" => cookie.secure = true,
+                "max-age" => expired = val.parse::<i64>().is_ok_and(|age| age <= 0),
+                _ => {}
+            }
+        }
+
+        self.cookies.retain(|old| (&old.name, &old.domain, &old.path) != (&cookie.name, &cookie.domain, &cookie.path));
+        if !expired {
+            self.cookies.push(cookie);
+        }
+    }
+
+    // Value of the Cookie header for a request to `url`, if any cookie applies.
+    // Longer paths go first, as RFC 6265 5.4 recommends.
+    pub fn header(&self, url: &Url) -> Option<String> {
+        let host = url.host.to_lowercase();
+        let mut matching: Vec<&Cookie> = self
+            .cookies
+            .iter()
+            .filter(|cookie| {
+                let domain_ok =
+                    if cookie.host_only { host == cookie.domain } else { domain_matches(&host, &cookie.domain) };
+                domain_ok && path_matches(&url.path, &cookie.path) && (!cookie.secure || url.scheme == "https")
+            })
+            .collect();
+        if matching.is_empty() {
+            return None;
+        }
+        matching.sort_by_key(|cookie| std::cmp::Reverse(cookie.path.len()));
+        Some(matching.iter().map(|cookie| format!("{}={}", cookie.name, cookie.value)).collect::<Vec<_>>().join("; "))
+    }
+}
+
+// IP addresses only ever match themselves.
+fn domain_matches(host: &str, domain: &str) -> bool {
+    host == domain
+        || (host.parse::<std::net::IpAddr>().is_err()
+            && host.strip_suffix(domain).is_some_and(|prefix| prefix.ends_with('.')))
+}
+
+fn path_matches(request_path: &str, cookie_path: &str) -> bool {
+    request_path == cookie_path
+        || (request_path.starts_with(cookie_path)
+            && (cookie_path.ends_with('/') || request_path[cookie_path.len()..].starts_with('/')))
+}
+
+// The directory of the request path (RFC 6265 5.1.4).
+fn default_path(request_path: &str) -> String {
+    match request_path.rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(slash) => request_path[..slash].to_string(),
+    }
+}
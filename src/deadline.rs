@@ -0,0 +1,46 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::error::DownloadError;
+
+// Exit status of a run stopped by --max-time, as curl's for a timeout.
+pub const EXIT_CODE: u8 = 28;
+
+// When --max-time runs out, counted from the start of the run.
+#[derive(Debug, Clone, Copy)]
+pub struct Deadline {
+    at: Instant,
+    limit: Duration,
+}
+
+impl Deadline {
+    pub fn new(limit: Duration, started: Instant) -> Deadline {
+        Deadline { at: started + limit, limit }
+    }
+
+    pub fn passed(&self, now: Instant) -> bool {
+        now >= self.at
+    }
+
+    #[cfg(any(feature = "async", feature = "event-loop"))]
+    pub fn remaining(&self, now: Instant) -> Duration {
+        self.at.saturating_duration_since(now)
+    }
+
+    // The error a download stopped by the deadline ends with.
+    pub fn exceeded(&self, reason: String) -> io::Error {
+        DownloadError::Timeout(format!("Maximum time of {} s reached: {}", self.limit.as_secs_f64(), reason)).into()
+    }
+
+    // Fails once the deadline has passed, before starting on `what`.
+    pub fn check(&self, what: &str) -> io::Result<()> {
+        if self.passed(Instant::now()) {
+            return Err(self.exceeded(format!("stopped before {}", what)));
+        }
+        Ok(())
+    }
+}
+
+pub fn is_max_time_exceeded(e: &io::Error) -> bool {
+    matches!(DownloadError::of(e), Some(DownloadError::Timeout(_)))
+}
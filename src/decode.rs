@@ -0,0 +1,113 @@
+use std::io::{self, Write};
+
+use flate2::write::{GzDecoder, ZlibDecoder};
+
+#[cfg(feature = "brotli")]
+const BROTLI_BUFFER_SIZE: usize = 64 * 1024;
+
+// Accept-Encoding value listing every coding this build can decode.
+pub fn accept_encoding() -> String {
+    let mut codings = vec!["gzip", "deflate"];
+    if cfg!(feature = "brotli") {
+        codings.push("br");
+    }
+    if cfg!(feature = "zstd") {
+        codings.push("zstd");
+    }
+    codings.join(", ")
+}
+
+// Undoes a response's Content-Encoding on the ordered byte stream before it
+// reaches the hasher. Ranges address the encoded bytes, so decoding can only
+// happen after the chunks have been put back in order.
+pub enum ContentDecoder<W: Write> {
+    Identity(W),
+    Gzip(GzDecoder<W>),
+    // HTTP "deflate" is the zlib format (RFC 9110 8.4.1.2).
+    Deflate(ZlibDecoder<W>),
+    #[cfg(feature = "brotli")]
+    Brotli(Box<brotli_decompressor::DecompressorWriter<W>>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::write::Decoder<'static, W>),
+}
+
+impl<W: Write> ContentDecoder<W> {
+    pub fn new(encoding: Option<&str>, inner: W) -> io::Result<ContentDecoder<W>> {
+        match encoding.map(|e| e.trim().to_lowercase()).as_deref() {
+            None | Some("") | Some("identity") => Ok(ContentDecoder::Identity(inner)),
+            Some("gzip") | Some("x-gzip") => Ok(ContentDecoder::Gzip(GzDecoder::new(inner))),
+            Some("deflate") => Ok(ContentDecoder::Deflate(ZlibDecoder::new(inner))),
+            #[cfg(feature = "brotli")]
+            Some("br") => Ok(ContentDecoder::Brotli(Box::new(brotli_decompressor::DecompressorWriter::new(
+                inner,
+                BROTLI_BUFFER_SIZE,
+            )))),
+            #[cfg(feature = "zstd")]
+            Some("zstd") => Ok(ContentDecoder::Zstd(zstd::stream::write::Decoder::new(inner)?)),
+            Some(other) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "Unsupported Content-Encoding: {}; rebuild with its feature or use --no-decompress to hash the raw bytes",
+                    other
+                ),
+            )),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        match self {
+            ContentDecoder::Identity(inner) => inner,
+            ContentDecoder::Gzip(decoder) => decoder.get_mut(),
+            ContentDecoder::Deflate(decoder) => decoder.get_mut(),
+            #[cfg(feature = "brotli")]
+            ContentDecoder::Brotli(decoder) => decoder.get_mut(),
+            #[cfg(feature = "zstd")]
+            ContentDecoder::Zstd(decoder) => decoder.get_mut(),
+        }
+    }
+
+    // Flushes the decoder and checks that the encoded stream was complete.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            ContentDecoder::Identity(inner) => Ok(inner),
+            ContentDecoder::Gzip(decoder) => decoder.finish(),
+            ContentDecoder::Deflate(decoder) => decoder.finish(),
+            #[cfg(feature = "brotli")]
+            ContentDecoder::Brotli(mut decoder) => {
+                decoder.close()?;
+                Ok(decoder.into_inner().unwrap_or_else(|inner| inner))
+            }
+            #[cfg(feature = "zstd")]
+            ContentDecoder::Zstd(mut decoder) => {
+                decoder.flush()?;
+                Ok(decoder.into_inner())
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for ContentDecoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ContentDecoder::Identity(inner) => inner.write(buf),
+            ContentDecoder::Gzip(decoder) => decoder.write(buf),
+            ContentDecoder::Deflate(decoder) => decoder.write(buf),
+            #[cfg(feature = "brotli")]
+            ContentDecoder::Brotli(decoder) => decoder.write(buf),
+            #[cfg(feature = "zstd")]
+            ContentDecoder::Zstd(decoder) => decoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ContentDecoder::Identity(inner) => inner.flush(),
+            ContentDecoder::Gzip(decoder) => decoder.flush(),
+            ContentDecoder::Deflate(decoder) => decoder.flush(),
+            #[cfg(feature = "brotli")]
+            ContentDecoder::Brotli(decoder) => decoder.flush(),
+            #[cfg(feature = "zstd")]
+            ContentDecoder::Zstd(decoder) => decoder.flush(),
+        }
+    }
+}
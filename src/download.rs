@@ -0,0 +1,121 @@
+#[cfg(any(feature = "async", feature = "event-loop"))]
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+
+use tracing::{debug, info};
+
+#[cfg(any(feature = "async", feature = "event-loop"))]
+use crate::client::Client;
+use crate::config::DownloadConfig;
+use crate::error::DownloadError;
+use crate::hash::{self, Algorithm};
+use crate::output::{self, Sink};
+#[cfg(any(feature = "async", feature = "event-loop"))]
+use crate::url::Url;
+use crate::{integrity, pool, resume};
+#[cfg(any(feature = "async", feature = "event-loop"))]
+use crate::{get_resource_info, probe_range_support, ResourceInfo};
+
+// The steps a download takes the same way whether it runs on threads, on a
+// tokio runtime (downloader.rs) or on the event loop (event_loop.rs).
+
+// Fails with the first option set in `config` that only threaded downloads
+// have; `by` names the downloader in the message.
+#[cfg(any(feature = "async", feature = "event-loop"))]
+pub fn check_supported(config: &DownloadConfig, by: &str) -> io::Result<()> {
+    let unsupported = [
+        (config.http2, "HTTP/2"),
+        (config.pipeline_depth > 1, "Pipelining"),
+        (!config.mirrors.is_empty(), "--mirror"),
+        (config.continue_download, "--continue"),
+        (config.byte_range.is_some(), "--range"),
+        (config.mmap, "--mmap"),
+        (config.piece_hashes.is_some(), "--piece-hashes"),
+        (config.write_checksum.is_some(), "--write-checksum"),
+        (!config.strict, "--no-strict"),
+        (config.stats || config.stats_json.is_some(), "--stats"),
+        (config.summary.is_some(), "--json"),
+    ]
+    .into_iter()
+    .find_map(|(set, option)| set.then_some(option));
+    match unsupported {
+        Some(option) => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not supported by {}", option, by))),
+        None => Ok(()),
+    }
+}
+
+// The resource behind `url` after redirects, and whether it can be fetched
+// in ranges: as the server says, or else as a range probe finds.
+#[cfg(any(feature = "async", feature = "event-loop"))]
+pub fn probe(url: &Url, client: &Client) -> io::Result<(Url, ResourceInfo, bool)> {
+    let (url, info) = get_resource_info(url, client)?;
+    let ranges_supported = match info.accept_ranges.as_deref() {
+        Some(units) => !units.eq_ignore_ascii_case("none"),
+        None => probe_range_support(&url, client)?,
+    };
+    Ok((url, info, ranges_supported))
+}
+
+// What a download that gave up on bytes start..=end fails with.
+pub fn cannot_download(start: usize, end: usize, attempts: usize) -> String {
+    format!("Cannot download bytes {}-{} after {} attempts", start, end, attempts)
+}
+
+// Fails if the connections are done but bytes are left unhashed. The gap
+// runs up to the first chunk still waiting for it, or to the end.
+#[cfg(any(feature = "async", feature = "event-loop"))]
+pub fn check_complete(
+    bytes_hashed: usize,
+    total_size: usize,
+    waiting: &BTreeMap<usize, Vec<u8>>,
+    attempts: usize,
+) -> io::Result<()> {
+    if bytes_hashed < total_size {
+        let end = waiting.keys().next().map_or(total_size, |&start| start) - 1;
+        return Err(io::Error::other(cannot_download(bytes_hashed, end, attempts)));
+    }
+    Ok(())
+}
+
+// The sink's digests in hex, the first algorithm's first.
+pub fn hex_digests(sink: Sink) -> io::Result<Vec<(Algorithm, String)>> {
+    Ok(sink.finish()?.into_iter().map(|(algorithm, digest)| (algorithm, hash::hex(&digest))).collect())
+}
+
+// Once every byte is hashed: checks the digest the caller expects, moves the
+// part file to `output` and logs the digests. Data that is not what the
+// caller expects never gets the final name, and is not kept to be resumed
+// either.
+pub fn finish(
+    config: &DownloadConfig,
+    digests: &[(Algorithm, String)],
+    part: Option<&Path>,
+    output: Option<&Path>,
+    written: u64,
+    bytes_hashed: usize,
+) -> io::Result<()> {
+    if let Some((algorithm, expected)) = &config.expect {
+        let actual = digests.iter().find(|(computed, _)| computed == algorithm).map(|(_, digest)| digest.as_str());
+        if let Err(e) = integrity::check_expected(algorithm.label(), expected, actual.unwrap_or_default()) {
+            if let Some(part) = part {
+                std::fs::remove_file(part)?;
+                resume::remove(part)?;
+                return Err(DownloadError::Integrity(format!("{}; removed {}", e, part.display())).into());
+            }
+            return Err(e);
+        }
+    }
+    if let (Some(output), Some(part)) = (output, part) {
+        output::commit(part, output, config.sync)?;
+        resume::remove(part)?;
+        info!("Saved {} bytes to {}", written, output.display());
+    }
+    info!("Hashed {} bytes", bytes_hashed);
+    let (allocated, reused) = pool::POOL.counts();
+    debug!("Chunk buffers: {} allocated, {} reused", allocated, reused);
+    for (algorithm, digest) in digests {
+        info!("Final message - {} hash of the downloaded data: {}", algorithm.label(), digest);
+    }
+    Ok(())
+}
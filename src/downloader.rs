@@ -0,0 +1,330 @@
+use std::collections::BTreeMap;
+use std::io::{self, Cursor};
+use std::sync::Arc;
+use std::time::Instant;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+use crate::client::Client;
+use crate::config::DownloadConfig;
+use crate::connector::{self, Layer, Opened};
+use crate::decode::ContentDecoder;
+use crate::error::retryable;
+use crate::queue::WorkQueue;
+use crate::response::Response;
+use crate::resume::POLL_INTERVAL;
+use crate::url::Url;
+use crate::{chunked, download, integrity, metrics, output, range};
+use crate::{
+    can_reuse, format_request, non_empty,
+    process_chunks, range_body, read_message,
+};
+
+// A connection the tasks read and write without blocking a thread.
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+// Downloads one URL on a tokio runtime, for callers that already run one:
+// each connection is a task rather than an OS thread. Only connecting and the
+// size probe go to the runtime's blocking pool.
+pub struct Downloader {
+    url: Url,
+    client: Client,
+    config: DownloadConfig,
+}
+
+impl Downloader {
+    pub fn new(url: Url, client: &Client, config: DownloadConfig) -> Downloader {
+        let client = config.client(client);
+        Downloader { url, client, config }
+    }
+
+    // Returns the hex digest of the data with the first of the config's
+    // hashes, as a threaded download does. Ranges wait in memory until they
+    // can be hashed in order, so max_buffer and spill do not apply.
+    pub async fn run(self) -> io::Result<String> {
+        let Downloader { url, mut client, config } = self;
+        download::check_supported(&config, "the async downloader")?;
+        if let Some(deadline) = &config.deadline {
+            deadline.check(&format!("downloading {}", url))?;
+        }
+        config.cancel.check(&format!("downloading {}", url))?;
+        let probe_client = if config.want_repr_digest {
+            client.clone().header("Want-Repr-Digest", integrity::WANT_REPR_DIGEST)
+        } else {
+            client.clone()
+        };
+        let (url, info, ranges_supported) = blocking(move || download::probe(&url, &probe_client)).await?;
+        let total_size = info.total_size;
+        info!("Total size to download: {} bytes", total_size);
+        client.if_range = info.validator.clone();
+
+        let tasks = if ranges_supported { config.num_threads.unwrap_or(config.max_threads) } else { 1 };
+        if ranges_supported {
+            info!("Using {} tasks", tasks);
+        } else {
+            info!("Server does not support range requests, downloading in a single stream");
+        }
+        let encoding = if config.decompress { info.content_encoding.as_deref() } else { None };
+        if let Some(encoding) = encoding {
+            info!("Decoding Content-Encoding: {}", encoding);
+        }
+        let output = match config.output.clone() {
+            None if config.remote_name => Some(output::remote_name(info.content_disposition.as_deref(), &url)?),
+            output => output,
+        };
+        let part = output.as_deref().map(output::part_path);
+        let mut sink = output::Sink::new(part.as_deref())?.with_algorithms(&config.hashes, config.hash_threads);
+        if config.stdout {
+            sink = sink.with_stdout();
+        }
+        if let Some(sender) = &config.stream {
+            sink = sink.with_stream(sender.clone());
+        }
+        let mut verifier = integrity::Verifier::new(info.digests.clone(), ContentDecoder::new(encoding, &mut sink)?);
+
+        let ranges = if total_size > 0 { vec![(0, total_size - 1)] } else { Vec::new() };
+        let queue = Arc::new(
+            WorkQueue::new(&ranges, tasks, config.chunk_size)
+                .with_max_attempts(config.max_attempts)
+                .with_callbacks(config.callbacks.clone())
+                .with_cancellation(config.cancel.clone()),
+        );
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let mut running = JoinSet::new();
+        for i in 0..tasks {
+            let fetch = fetch_ranges(i, url.clone(), client.clone(), ranges_supported, Arc::clone(&queue), sender.clone());
+            running.spawn(fetch.instrument(info_span!("task", id = i)));
+        }
+        drop(sender);
+
+        // Chunks are hashed as soon as the ones before them have arrived.
+        let mut buffer = BTreeMap::new();
+        let mut bytes_hashed = 0;
+        let mut downloaded = 0;
+        loop {
+            // Dropping the tasks cancels the requests in flight.
+            let timed_out = config.deadline.filter(|deadline| deadline.passed(Instant::now()));
+            if timed_out.is_some() || config.cancel.is_cancelled() {
+                queue.stop();
+                let reason = format!("{} of {} bytes downloaded", downloaded, total_size);
+                return Err(match timed_out {
+                    Some(deadline) => deadline.exceeded(reason),
+                    None => config.cancel.cancelled(reason),
+                });
+            }
+            // Woken regularly to notice a cancellation.
+            let wait = config
+                .deadline
+                .map_or(POLL_INTERVAL, |deadline| deadline.remaining(Instant::now()).min(POLL_INTERVAL));
+            let Ok(received) = tokio::time::timeout(wait, receiver.recv()).await else { continue };
+            let Some((start, chunk)) = received else { break };
+            downloaded += chunk.len();
+            config.callbacks.progress(downloaded, total_size);
+            buffer.insert(start, chunk);
+            process_chunks(&mut verifier, &mut bytes_hashed, &mut buffer, total_size)?;
+        }
+        let mut changed = None;
+        while let Some(result) = running.join_next().await {
+            match result {
+                Ok(Ok(())) => debug!("Task finished"),
+                Ok(Err(e)) => changed = Some(e),
+                Err(e) => error!("Task failed: {}", e),
+            }
+        }
+        if let Some(e) = changed {
+            return Err(e);
+        }
+        download::check_complete(bytes_hashed, total_size, &buffer, queue.max_attempts())?;
+
+        verifier.finish()?.finish()?;
+        let written = sink.written();
+        let mut digests = download::hex_digests(sink)?;
+        download::finish(&config, &digests, part.as_deref(), output.as_deref(), written, bytes_hashed)?;
+        config.callbacks.complete(&digests[0].1);
+        Ok(digests.swap_remove(0).1)
+    }
+}
+
+// Runs blocking work, such as resolving and connecting, off the runtime's
+// own threads.
+async fn blocking<T: Send + 'static>(work: impl FnOnce() -> io::Result<T> + Send + 'static) -> io::Result<T> {
+    tokio::task::spawn_blocking(work).await.map_err(io::Error::other)?
+}
+
+// Takes ranges from the queue until it runs dry, as fetch_ranges does for a
+// thread, sending each chunk to be hashed. Without range support the one
+// task fetches the whole body.
+async fn fetch_ranges(
+    i: usize,
+    url: Url,
+    client: Client,
+    ranges_supported: bool,
+    queue: Arc<WorkQueue>,
+    chunks: mpsc::UnboundedSender<(usize, Vec<u8>)>,
+) -> io::Result<()> {
+    let started = Instant::now();
+    metrics::METRICS.connection_opened();
+    let result = take_ranges(i, &url, &client, ranges_supported, &queue, &chunks).await;
+    queue.finished(i, started.elapsed());
+    metrics::METRICS.connection_closed();
+    result
+}
+
+async fn take_ranges(
+    i: usize,
+    url: &Url,
+    client: &Client,
+    ranges_supported: bool,
+    queue: &WorkQueue,
+    chunks: &mpsc::UnboundedSender<(usize, Vec<u8>)>,
+) -> io::Result<()> {
+    let mut open = None;
+    while let Some((start, range_end)) = queue.next() {
+        let mut current_start = start;
+        while current_start <= range_end {
+            debug!("requesting bytes={}-{}", current_start, range_end);
+            queue.requested(i, 1);
+            let range = ranges_supported.then_some((current_start, range_end + 1));
+            match fetch_range(url, client, &mut open, range).await.and_then(non_empty) {
+                Ok(chunk) => {
+                    let chunk_size = chunk.len();
+                    queue.record(i, current_start, chunk_size);
+                    // The receiver is only gone once the download has ended.
+                    if chunks.send((current_start, chunk)).is_err() {
+                        return Ok(());
+                    }
+                    current_start += chunk_size;
+                }
+                Err(e) if range::is_resource_changed(&e) => {
+                    queue.stop();
+                    return Err(e);
+                }
+                Err(e) if retryable(&e) && queue.retry((current_start, range_end), &e) => {
+                    queue.failed(i);
+                    metrics::METRICS.failed(url);
+                    break;
+                }
+                Err(e) => {
+                    queue.failed(i);
+                    metrics::METRICS.failed(url);
+                    warn!("giving up on {}-{}: {}", current_start, range_end, e);
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Sends one GET over the task's kept-alive connection, reconnecting once if
+// a reused connection fails, and returns the bytes that belong at the start
+// of `range`.
+async fn fetch_range(
+    url: &Url,
+    client: &Client,
+    open: &mut Option<Box<dyn Io>>,
+    range: Option<(usize, usize)>,
+) -> io::Result<Vec<u8>> {
+    let request = format_request("GET", url, client, range, true);
+    let response = loop {
+        let (mut stream, reused) = match open.take() {
+            Some(stream) => (stream, true),
+            None => (connect(url, client).await?, false),
+        };
+        match exchange(&mut stream, request.as_bytes(), client.read_size).await {
+            Ok(response) => {
+                if can_reuse(&response) {
+                    *open = Some(stream);
+                }
+                break response;
+            }
+            Err(e) if reused => debug!("Kept-alive connection to {} failed, reconnecting: {}", url.origin(), e),
+            Err(e) => return Err(e),
+        }
+    };
+    client.handle_response(url, &response);
+    let status = response.status_line();
+    if !status.is_success() {
+        return Err(status.error(url).into());
+    }
+    match range {
+        Some((start, end)) => range_body(client, response, start, end),
+        None => Ok(response.body),
+    }
+}
+
+// Writes the request and reads the whole response, `read_size` bytes at a
+// time, which read_message then parses like one read from a blocking stream.
+async fn exchange(stream: &mut Box<dyn Io>, request: &[u8], read_size: usize) -> io::Result<Response> {
+    stream.write_all(request).await?;
+    let mut data = Vec::new();
+    let body_start = loop {
+        if let Some(pos) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        data.reserve(read_size);
+        if stream.read_buf(&mut data).await? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed prematurely"));
+        }
+    };
+    let headers = Response::parse(&data[..body_start], Vec::new())?.headers;
+    let chunked = headers.is_chunked();
+    let content_length = headers.content_length()?;
+    loop {
+        let body = &data[body_start..];
+        let complete = match content_length {
+            _ if chunked => chunked::body_len(body)?.is_some(),
+            Some(len) => body.len() >= len,
+            // Without framing the body runs until the server closes.
+            None => false,
+        };
+        if complete {
+            break;
+        }
+        data.reserve(read_size);
+        let n = match stream.read_buf(&mut data).await {
+            Ok(n) => n,
+            // As in read_message, a truncated body keeps what arrived.
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => 0,
+            Err(e) => return Err(e),
+        };
+        if n == 0 {
+            break;
+        }
+    }
+    read_message(&mut Cursor::new(data), read_size).map(|(response, _)| response)
+}
+
+// Connects the way the Connector does, through a proxy or a Unix socket as
+// configured, then hands the socket to tokio.
+#[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+async fn connect(url: &Url, client: &Client) -> io::Result<Box<dyn Io>> {
+    let layer = connector::layer(url)?;
+    let (opener, target) = (client.connector.clone(), url.clone());
+    let tcp = match blocking(move || opener.open(&target)).await? {
+        Opened::Tcp(tcp) => tcp,
+        #[cfg(unix)]
+        Opened::Unix(stream) => {
+            stream.set_nonblocking(true)?;
+            return Ok(Box::new(tokio::net::UnixStream::from_std(stream)?));
+        }
+    };
+    tcp.set_nonblocking(true)?;
+    let tcp = tokio::net::TcpStream::from_std(tcp)?;
+    match layer {
+        Layer::Plain => Ok(Box::new(tcp)),
+        #[cfg(feature = "tls")]
+        Layer::Tls(server_name) => {
+            let tls = tokio_rustls::TlsConnector::from(client.connector.tls_config())
+                .connect(server_name, tcp)
+                .await
+                .map_err(connector::tls_error)?;
+            Ok(Box::new(tls))
+        }
+    }
+}
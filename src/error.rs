@@ -0,0 +1,182 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::{cancel, deadline};
+
+// The ways a download fails that callers and the retry logic tell apart.
+// They travel inside io::Error like any other failure, so `?` works across
+// the I/O code; DownloadError::of finds them again. Errors from files and
+// sockets themselves stay plain io::Errors.
+#[derive(Debug)]
+pub enum DownloadError {
+    // No address of the host took the connection.
+    Connect(String),
+    // The host name did not resolve.
+    Dns(String),
+    // TLS could not be set up, or the handshake failed, e.g. on an
+    // untrusted certificate.
+    Tls(String),
+    // The final response had a status other than 2xx.
+    HttpStatus { code: u16, message: String },
+    // A response that breaks HTTP framing or syntax.
+    Parse(String),
+    // A range response that does not hold the bytes asked for.
+    RangeMismatch(String),
+    // The data does not have the digest the server or --expect gave.
+    Integrity(String),
+    // --max-time ran out.
+    Timeout(String),
+    // The caller cancelled the download, e.g. with Ctrl-C.
+    Cancelled(String),
+    // The representation changed since the size probe, so the bytes already
+    // fetched can no longer be combined with new ones.
+    ResourceChanged(String),
+}
+
+impl DownloadError {
+    pub fn of(e: &io::Error) -> Option<&DownloadError> {
+        e.get_ref().and_then(|inner| inner.downcast_ref())
+    }
+
+    // Whether asking again may work. Rate limits and server errors pass;
+    // other statuses, bad data and a changed resource would just repeat.
+    pub fn retryable(&self) -> bool {
+        match self {
+            DownloadError::Connect(_) | DownloadError::Dns(_) => true,
+            DownloadError::Parse(_) | DownloadError::RangeMismatch(_) => true,
+            DownloadError::HttpStatus { code, .. } => matches!(code, 408 | 425 | 429 | 500..=599),
+            DownloadError::Tls(_)
+            | DownloadError::Integrity(_)
+            | DownloadError::Timeout(_)
+            | DownloadError::Cancelled(_)
+            | DownloadError::ResourceChanged(_) => false,
+        }
+    }
+
+    // The same failure with its message changed by `f`.
+    fn map(self, f: impl FnOnce(String) -> String) -> DownloadError {
+        match self {
+            DownloadError::Connect(message) => DownloadError::Connect(f(message)),
+            DownloadError::Dns(message) => DownloadError::Dns(f(message)),
+            DownloadError::Tls(message) => DownloadError::Tls(f(message)),
+            DownloadError::HttpStatus { code, message } => DownloadError::HttpStatus { code, message: f(message) },
+            DownloadError::Parse(message) => DownloadError::Parse(f(message)),
+            DownloadError::RangeMismatch(message) => DownloadError::RangeMismatch(f(message)),
+            DownloadError::Integrity(message) => DownloadError::Integrity(f(message)),
+            DownloadError::Timeout(message) => DownloadError::Timeout(f(message)),
+            DownloadError::Cancelled(message) => DownloadError::Cancelled(f(message)),
+            DownloadError::ResourceChanged(reason) => DownloadError::ResourceChanged(f(reason)),
+        }
+    }
+
+    fn kind(&self) -> io::ErrorKind {
+        match self {
+            DownloadError::Connect(_) => io::ErrorKind::ConnectionRefused,
+            DownloadError::Dns(_) => io::ErrorKind::NotFound,
+            DownloadError::Parse(_) | DownloadError::RangeMismatch(_) | DownloadError::Integrity(_) => {
+                io::ErrorKind::InvalidData
+            }
+            DownloadError::Timeout(_) => io::ErrorKind::TimedOut,
+            // Cancelled is not Interrupted, which reads take as a reason to try again.
+            DownloadError::Tls(_)
+            | DownloadError::HttpStatus { .. }
+            | DownloadError::Cancelled(_)
+            | DownloadError::ResourceChanged(_) => io::ErrorKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DownloadError::ResourceChanged(reason) => write!(f, "Resource changed during download: {}", reason),
+            DownloadError::HttpStatus { message, .. } => f.write_str(message),
+            DownloadError::Connect(message)
+            | DownloadError::Dns(message)
+            | DownloadError::Tls(message)
+            | DownloadError::Parse(message)
+            | DownloadError::RangeMismatch(message)
+            | DownloadError::Integrity(message)
+            | DownloadError::Timeout(message)
+            | DownloadError::Cancelled(message) => f.write_str(message),
+        }
+    }
+}
+
+impl Error for DownloadError {}
+
+impl From<DownloadError> for io::Error {
+    fn from(e: DownloadError) -> io::Error {
+        io::Error::new(e.kind(), e)
+    }
+}
+
+// Puts `context` in front of the message, keeping the kind of failure.
+pub fn context(e: io::Error, context: &str) -> io::Error {
+    let kind = e.kind();
+    match e.into_inner() {
+        Some(inner) => match inner.downcast::<DownloadError>() {
+            Ok(typed) => typed.map(|message| format!("{}: {}", context, message)).into(),
+            Err(inner) => io::Error::new(kind, format!("{}: {}", context, inner)),
+        },
+        None => io::Error::new(kind, format!("{}: {}", context, kind)),
+    }
+}
+
+// Exit statuses of the command-line client, one per kind of failure, so
+// scripts can branch on them. A run stopped by --max-time or Ctrl-C has its
+// own, from deadline.rs and cancel.rs.
+pub const EXIT_FAILURE: u8 = 1;
+// Bad arguments, or an option the mode does not take.
+pub const EXIT_USAGE: u8 = 2;
+// The server answered with an error status.
+pub const EXIT_HTTP: u8 = 3;
+// The server could not be reached, or its replies could not be used.
+pub const EXIT_NETWORK: u8 = 4;
+// The data does not match its digest, or changed while downloading.
+pub const EXIT_INTEGRITY: u8 = 5;
+
+pub fn exit_code(e: &io::Error) -> u8 {
+    match DownloadError::of(e) {
+        Some(DownloadError::HttpStatus { .. }) => EXIT_HTTP,
+        Some(
+            DownloadError::Connect(_)
+            | DownloadError::Dns(_)
+            | DownloadError::Tls(_)
+            | DownloadError::Parse(_)
+            | DownloadError::RangeMismatch(_),
+        ) => EXIT_NETWORK,
+        Some(DownloadError::Integrity(_) | DownloadError::ResourceChanged(_)) => EXIT_INTEGRITY,
+        Some(DownloadError::Timeout(_)) => deadline::EXIT_CODE,
+        Some(DownloadError::Cancelled(_)) => cancel::EXIT_CODE,
+        // Sockets fail with plain io::Errors as well.
+        None => match e.kind() {
+            io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::UnexpectedEof => EXIT_NETWORK,
+            _ => EXIT_FAILURE,
+        },
+    }
+}
+
+// The message printed for a failed run: the error itself, then each error
+// that caused it, rather than the Debug dump of their structs.
+pub fn report(e: &io::Error) -> String {
+    let mut report = e.to_string();
+    let mut source = e.source();
+    while let Some(cause) = source {
+        report.push_str(&format!(": {}", cause));
+        source = cause.source();
+    }
+    report
+}
+
+// Errors that are not a DownloadError, such as a reset connection or one
+// that closed early, are worth another attempt.
+pub fn retryable(e: &io::Error) -> bool {
+    DownloadError::of(e).is_none_or(DownloadError::retryable)
+}
@@ -0,0 +1,525 @@
+use std::collections::BTreeMap;
+use std::io::{self, Cursor, Read, Write};
+use std::mem;
+use std::time::Instant;
+
+use mio::event::Source;
+use mio::{Events, Interest, Poll, Registry, Token};
+use tracing::{debug, info, warn};
+
+use crate::client::Client;
+use crate::config::DownloadConfig;
+use crate::connector::{self, Layer, Opened};
+use crate::decode::ContentDecoder;
+use crate::error;
+use crate::queue::WorkQueue;
+use crate::response::Response;
+use crate::resume::POLL_INTERVAL;
+use crate::url::Url;
+use crate::download::{check_complete, check_supported, finish, hex_digests, probe};
+use crate::{chunked, integrity, metrics, output, pool, range};
+use crate::{
+    can_reuse, format_request, non_empty, process_chunks, range_body,
+    read_message,
+};
+
+// --event-loop: the download on the calling thread alone, which drives one
+// non-blocking connection per slot from readiness events instead of a
+// thread per connection. Connecting, the size probe included, still blocks
+// the loop, but happens once per kept-alive connection. Like --async, ranges
+// wait in memory until they can be hashed in order.
+pub fn download(url: &Url, client: &Client, config: &DownloadConfig) -> io::Result<String> {
+    check_supported(config, "the event loop")?;
+    if let Some(deadline) = &config.deadline {
+        deadline.check(&format!("downloading {}", url))?;
+    }
+    config.cancel.check(&format!("downloading {}", url))?;
+    let mut client = config.client(client);
+    let probe_client = if config.want_repr_digest {
+        client.clone().header("Want-Repr-Digest", integrity::WANT_REPR_DIGEST)
+    } else {
+        client.clone()
+    };
+    let (url, info, ranges_supported) = probe(url, &probe_client)?;
+    let total_size = info.total_size;
+    info!("Total size to download: {} bytes", total_size);
+    client.if_range = info.validator.clone();
+
+    let slots = if ranges_supported { config.num_threads.unwrap_or(config.max_threads) } else { 1 };
+    if ranges_supported {
+        info!("Using {} connections on one thread", slots);
+    } else {
+        info!("Server does not support range requests, downloading in a single stream");
+    }
+    let encoding = if config.decompress { info.content_encoding.as_deref() } else { None };
+    if let Some(encoding) = encoding {
+        info!("Decoding Content-Encoding: {}", encoding);
+    }
+    let output = match config.output.clone() {
+        None if config.remote_name => Some(output::remote_name(info.content_disposition.as_deref(), &url)?),
+        output => output,
+    };
+    let part = output.as_deref().map(output::part_path);
+    let mut sink = output::Sink::new(part.as_deref())?.with_algorithms(&config.hashes, config.hash_threads);
+    if config.stdout {
+        sink = sink.with_stdout();
+    }
+    if let Some(sender) = &config.stream {
+        sink = sink.with_stream(sender.clone());
+    }
+    let mut verifier = integrity::Verifier::new(info.digests.clone(), ContentDecoder::new(encoding, &mut sink)?);
+
+    let ranges = if total_size > 0 { vec![(0, total_size - 1)] } else { Vec::new() };
+    let queue = WorkQueue::new(&ranges, slots, config.chunk_size)
+        .with_max_attempts(config.max_attempts)
+        .with_callbacks(config.callbacks.clone())
+        .with_cancellation(config.cancel.clone());
+    let mut poll = Poll::new()?;
+    let fetch = Fetch { url: &url, client: &client, ranges_supported, queue: &queue, registry: poll.registry().try_clone()? };
+    let mut slots: Vec<_> = (0..slots).map(Slot::new).collect();
+    for slot in &mut slots {
+        fetch.dispatch(slot)?;
+    }
+
+    // Chunks are hashed as soon as the ones before them have arrived.
+    let mut buffer = BTreeMap::new();
+    let mut bytes_hashed = 0;
+    let mut downloaded = 0;
+    let mut events = Events::with_capacity(1024);
+    while slots.iter().any(|slot| !slot.done) {
+        // Dropping the connections abandons the requests in flight.
+        let timed_out = config.deadline.filter(|deadline| deadline.passed(Instant::now()));
+        if timed_out.is_some() || config.cancel.is_cancelled() {
+            queue.stop();
+            let reason = format!("{} of {} bytes downloaded", downloaded, total_size);
+            return Err(match timed_out {
+                Some(deadline) => deadline.exceeded(reason),
+                None => config.cancel.cancelled(reason),
+            });
+        }
+        // Woken regularly to notice a cancellation.
+        let wait = config
+            .deadline
+            .map_or(POLL_INTERVAL, |deadline| deadline.remaining(Instant::now()).min(POLL_INTERVAL));
+        match poll.poll(&mut events, Some(wait)) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            result => result?,
+        }
+        for event in events.iter() {
+            let Some(slot) = slots.get_mut(event.token().0) else { continue };
+            if let Some((start, chunk)) = fetch.advance(slot)? {
+                downloaded += chunk.len();
+                config.callbacks.progress(downloaded, total_size);
+                buffer.insert(start, chunk);
+                process_chunks(&mut verifier, &mut bytes_hashed, &mut buffer, total_size)?;
+            }
+        }
+    }
+    check_complete(bytes_hashed, total_size, &buffer, queue.max_attempts())?;
+
+    verifier.finish()?.finish()?;
+    let written = sink.written();
+    let mut digests = hex_digests(sink)?;
+    finish(config, &digests, part.as_deref(), output.as_deref(), written, bytes_hashed)?;
+    config.callbacks.complete(&digests[0].1);
+    Ok(digests.swap_remove(0).1)
+}
+
+// What one connection of the loop is doing; it takes the place of a thread.
+struct Slot {
+    id: usize,
+    connection: Option<Connection>,
+    // Whether the connection has carried a response already, so that a
+    // failure may only mean the server closed it in the meantime.
+    reused: bool,
+    // The part of its range still to fetch, inclusive.
+    range: Option<(usize, usize)>,
+    // The response coming in, and where its body starts once the head is in.
+    data: Vec<u8>,
+    framing: Option<(usize, Framing)>,
+    started: Instant,
+    // Set once the queue has run dry for it.
+    done: bool,
+}
+
+// Where a response body ends.
+enum Framing {
+    Chunked,
+    Length(usize),
+    // Without framing the body runs until the server closes.
+    Close,
+}
+
+impl Slot {
+    fn new(id: usize) -> Slot {
+        metrics::METRICS.connection_opened();
+        Slot {
+            id,
+            connection: None,
+            reused: false,
+            range: None,
+            data: Vec::new(),
+            framing: None,
+            started: Instant::now(),
+            done: false,
+        }
+    }
+}
+
+// A download that ends early leaves slots that never finished.
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if !self.done {
+            metrics::METRICS.connection_closed();
+        }
+    }
+}
+
+// What the slots share: where they download from and the ranges they take.
+struct Fetch<'a> {
+    url: &'a Url,
+    client: &'a Client,
+    ranges_supported: bool,
+    queue: &'a WorkQueue,
+    registry: Registry,
+}
+
+impl Fetch<'_> {
+    // Sends the request for the rest of the slot's range, or for the next
+    // range in the queue, going on to the next until one can be sent. The
+    // slot is done once the queue runs dry.
+    fn dispatch(&self, slot: &mut Slot) -> io::Result<()> {
+        loop {
+            if slot.range.is_none() {
+                slot.range = self.queue.next();
+            }
+            let Some((start, end)) = slot.range else {
+                self.disconnect(slot);
+                if !slot.done {
+                    slot.done = true;
+                    self.queue.finished(slot.id, slot.started.elapsed());
+                    metrics::METRICS.connection_closed();
+                }
+                return Ok(());
+            };
+            debug!("requesting bytes={}-{}", start, end);
+            self.queue.requested(slot.id, 1);
+            match self.send(slot, start, end) {
+                Ok(()) => return Ok(()),
+                Err(e) => self.fail(slot, e)?,
+            }
+        }
+    }
+
+    // Writes the request over the slot's kept-alive connection, or a new one
+    // if there is none or it turns out to be closed.
+    fn send(&self, slot: &mut Slot, start: usize, end: usize) -> io::Result<()> {
+        let range = self.ranges_supported.then_some((start, end + 1));
+        let request = format_request("GET", self.url, self.client, range, true);
+        slot.data.clear();
+        slot.framing = None;
+        if let Some(connection) = &mut slot.connection {
+            match connection.send(request.as_bytes()) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    debug!("Kept-alive connection to {} failed, reconnecting: {}", self.url.origin(), e);
+                    self.disconnect(slot);
+                }
+            }
+        }
+        let mut connection = connect(self.url, self.client)?;
+        self.registry.register(connection.socket().source(), Token(slot.id), Interest::READABLE | Interest::WRITABLE)?;
+        self.queue.connected(slot.id, 1);
+        slot.reused = false;
+        connection.send(request.as_bytes())?;
+        slot.connection = Some(connection);
+        Ok(())
+    }
+
+    // Moves the slot's exchange on after a readiness event. Returns the
+    // chunk its response brought, once complete, having sent the next request.
+    fn advance(&self, slot: &mut Slot) -> io::Result<Option<(usize, Vec<u8>)>> {
+        let Some((start, end)) = slot.range else { return Ok(None) };
+        let response = match self.exchange(slot) {
+            Ok(None) => return Ok(None),
+            Ok(Some(response)) => response,
+            Err(e) => {
+                let reused = slot.reused;
+                self.disconnect(slot);
+                if reused {
+                    debug!("Kept-alive connection to {} failed, reconnecting: {}", self.url.origin(), e);
+                    if let Err(e) = self.send(slot, start, end) {
+                        self.fail(slot, e)?;
+                        self.dispatch(slot)?;
+                    }
+                } else {
+                    self.fail(slot, e)?;
+                    self.dispatch(slot)?;
+                }
+                return Ok(None);
+            }
+        };
+        if can_reuse(&response) {
+            slot.reused = true;
+        } else {
+            self.disconnect(slot);
+        }
+        self.client.handle_response(self.url, &response);
+        let status = response.status_line();
+        let chunk = if !status.is_success() {
+            Err(status.error(self.url).into())
+        } else if self.ranges_supported {
+            range_body(self.client, response, start, end + 1)
+        } else {
+            Ok(response.body)
+        };
+        match chunk.and_then(non_empty) {
+            Ok(chunk) => {
+                self.queue.record(slot.id, start, chunk.len());
+                let next = start + chunk.len();
+                slot.range = (next <= end).then_some((next, end));
+                self.dispatch(slot)?;
+                Ok(Some((start, chunk)))
+            }
+            Err(e) => {
+                self.fail(slot, e)?;
+                self.dispatch(slot)?;
+                Ok(None)
+            }
+        }
+    }
+
+    // Writes what the connection has queued and reads what has arrived.
+    // Returns the response once it is complete by its framing, or the
+    // server has closed the connection.
+    fn exchange(&self, slot: &mut Slot) -> io::Result<Option<Response>> {
+        let Some(connection) = &mut slot.connection else { return Ok(None) };
+        let read_size = self.client.read_size;
+        connection.flush()?;
+        let closed = loop {
+            match connection.receive(&mut slot.data, read_size) {
+                Ok(0) => break true,
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break false,
+                // As in read_message, a truncated body keeps what arrived.
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break true,
+                Err(e) => return Err(e),
+            }
+        };
+        // TLS may have something to answer with.
+        connection.flush()?;
+        if closed {
+            self.disconnect(slot);
+        } else if !complete(&slot.data, &mut slot.framing)? {
+            return Ok(None);
+        }
+        let mut data = Cursor::new(mem::replace(&mut slot.data, pool::POOL.take()));
+        let response = read_message(&mut data, read_size).map(|(response, _)| response);
+        pool::POOL.give(data.into_inner());
+        response.map(Some)
+    }
+
+    // Gives up on the request for the slot's range as the threads do: the
+    // range goes back to the queue, unless it has failed too often.
+    fn fail(&self, slot: &mut Slot, e: io::Error) -> io::Result<()> {
+        let Some((start, end)) = slot.range.take() else { return Ok(()) };
+        if range::is_resource_changed(&e) {
+            self.queue.stop();
+            return Err(e);
+        }
+        self.queue.failed(slot.id);
+        metrics::METRICS.failed(self.url);
+        if !(error::retryable(&e) && self.queue.retry((start, end), &e)) {
+            warn!("giving up on {}-{}: {}", start, end, e);
+        }
+        Ok(())
+    }
+
+    fn disconnect(&self, slot: &mut Slot) {
+        if let Some(mut connection) = slot.connection.take() {
+            let _ = self.registry.deregister(connection.socket().source());
+        }
+    }
+}
+
+// Whether `data` holds a whole response by the framing its head declares,
+// kept in `framing` once the head is in.
+fn complete(data: &[u8], framing: &mut Option<(usize, Framing)>) -> io::Result<bool> {
+    if framing.is_none() {
+        let Some(pos) = data.windows(4).position(|w| w == b"\r\n\r\n") else { return Ok(false) };
+        let headers = Response::parse(&data[..pos + 4], Vec::new())?.headers;
+        let body = match headers.content_length()? {
+            _ if headers.is_chunked() => Framing::Chunked,
+            Some(len) => Framing::Length(len),
+            None => Framing::Close,
+        };
+        *framing = Some((pos + 4, body));
+    }
+    let Some((body_start, body)) = framing else { unreachable!() };
+    let body_data = &data[*body_start..];
+    Ok(match body {
+        Framing::Chunked => chunked::body_len(body_data)?.is_some(),
+        Framing::Length(len) => body_data.len() >= *len,
+        Framing::Close => false,
+    })
+}
+
+// A non-blocking socket registered with the loop.
+enum Socket {
+    Tcp(mio::net::TcpStream),
+    #[cfg(unix)]
+    Unix(mio::net::UnixStream),
+}
+
+impl Socket {
+    fn source(&mut self) -> &mut dyn Source {
+        match self {
+            Socket::Tcp(tcp) => tcp,
+            #[cfg(unix)]
+            Socket::Unix(unix) => unix,
+        }
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(tcp) => tcp.read(buf),
+            #[cfg(unix)]
+            Socket::Unix(unix) => unix.read(buf),
+        }
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Socket::Tcp(tcp) => tcp.write(buf),
+            #[cfg(unix)]
+            Socket::Unix(unix) => unix.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Socket::Tcp(tcp) => tcp.flush(),
+            #[cfg(unix)]
+            Socket::Unix(unix) => unix.flush(),
+        }
+    }
+}
+
+// A connection that never blocks: what is sent is queued and written as
+// the socket takes it, and reads return WouldBlock once nothing is left.
+enum Connection {
+    // The bytes the socket has not taken yet.
+    Plain(Socket, Vec<u8>),
+    #[cfg(feature = "tls")]
+    Tls(Socket, Box<rustls::ClientConnection>),
+}
+
+impl Connection {
+    fn socket(&mut self) -> &mut Socket {
+        match self {
+            Connection::Plain(socket, _) => socket,
+            #[cfg(feature = "tls")]
+            Connection::Tls(socket, _) => socket,
+        }
+    }
+
+    fn send(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match self {
+            Connection::Plain(_, unsent) => unsent.extend_from_slice(bytes),
+            // Held by rustls until the handshake is done.
+            #[cfg(feature = "tls")]
+            Connection::Tls(_, tls) => tls.writer().write_all(bytes)?,
+        }
+        self.flush()
+    }
+
+    // Writes what is queued until the socket would block; a writable event
+    // brings the rest.
+    fn flush(&mut self) -> io::Result<()> {
+        let result = match self {
+            Connection::Plain(socket, unsent) => loop {
+                if unsent.is_empty() {
+                    break Ok(());
+                }
+                match socket.write(unsent) {
+                    Ok(0) => break Err(io::Error::new(io::ErrorKind::WriteZero, "Connection closed while sending")),
+                    Ok(n) => drop(unsent.drain(..n)),
+                    Err(e) => break Err(e),
+                }
+            },
+            #[cfg(feature = "tls")]
+            Connection::Tls(socket, tls) => loop {
+                if !tls.wants_write() {
+                    break Ok(());
+                }
+                if let Err(e) = tls.write_tls(socket) {
+                    break Err(e);
+                }
+            },
+        };
+        match result {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            result => result,
+        }
+    }
+
+    // Appends up to `size` bytes that have arrived onto `data`, returning
+    // how many; 0 once the server has closed.
+    fn receive(&mut self, data: &mut Vec<u8>, size: usize) -> io::Result<usize> {
+        match self {
+            Connection::Plain(socket, _) => read_available(socket, data, size),
+            #[cfg(feature = "tls")]
+            Connection::Tls(socket, tls) => loop {
+                match read_available(&mut tls.reader(), data, size) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    result => return result,
+                }
+                // No plaintext left: decrypt the next records off the socket.
+                if tls.read_tls(socket)? == 0 {
+                    return Ok(0);
+                }
+                tls.process_new_packets()
+                    .map_err(|e| crate::connector::tls_error(io::Error::new(io::ErrorKind::InvalidData, e)))?;
+            },
+        }
+    }
+}
+
+// Reads what `reader` has without blocking, up to `size` bytes, straight
+// onto the end of `data`. WouldBlock only if there was nothing.
+fn read_available<R: Read>(reader: &mut R, data: &mut Vec<u8>, size: usize) -> io::Result<usize> {
+    let len = data.len();
+    data.reserve(size);
+    match reader.take(size as u64).read_to_end(data) {
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock && data.len() > len => Ok(data.len() - len),
+        result => result,
+    }
+}
+
+// Connects the way the Connector does, through a proxy or a Unix socket as
+// configured, then makes the socket non-blocking for the loop.
+fn connect(url: &Url, client: &Client) -> io::Result<Connection> {
+    let layer = connector::layer(url)?;
+    let socket = match client.connector.open(url)? {
+        Opened::Tcp(tcp) => {
+            tcp.set_nonblocking(true)?;
+            Socket::Tcp(mio::net::TcpStream::from_std(tcp))
+        }
+        #[cfg(unix)]
+        Opened::Unix(stream) => {
+            stream.set_nonblocking(true)?;
+            Socket::Unix(mio::net::UnixStream::from_std(stream))
+        }
+    };
+    match layer {
+        Layer::Plain => Ok(Connection::Plain(socket, Vec::new())),
+        #[cfg(feature = "tls")]
+        Layer::Tls(server_name) => {
+            Ok(Connection::Tls(socket, Box::new(client.connector.tls_connection(server_name)?)))
+        }
+    }
+}
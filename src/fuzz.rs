@@ -0,0 +1,179 @@
+use std::io::{self, Cursor, Read, Write};
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::auth::Auth;
+use crate::client::DEFAULT_READ_SIZE;
+use crate::cookies::CookieJar;
+use crate::response::Response;
+use crate::transport::Connection;
+use crate::url::Url;
+use crate::{can_reuse, chunked, integrity, multipart, parse_resource_info, range, read_response, retry, upload};
+
+// Fragments that tend to reach the corners of the parsers when spliced in:
+// delimiters, numbers that overflow, and the words framing depends on.
+const TOKENS: &[&[u8]] = &[
+    b"\r\n",
+    b"\r\n\r\n",
+    b": ",
+    b",",
+    b";",
+    b"-",
+    b"/",
+    b"*",
+    b"=",
+    b"\"",
+    b"0",
+    b"-1",
+    b"18446744073709551616",
+    b"ffffffffffffffffff",
+    b"chunked",
+    b"Content-Length: ",
+    b"Transfer-Encoding: ",
+    b"Content-Range: bytes ",
+    b"multipart/byteranges; boundary=",
+    b"--",
+    b"\xff\xfe",
+];
+
+// xorshift64*: the same inputs on every run, so a failure can be replayed.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed.max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n.max(1) as u64) as usize
+    }
+}
+
+// `seed` with a few random edits: bytes changed, tokens spliced in, spans
+// removed or repeated, or the end cut off.
+pub fn mutate(rng: &mut Rng, seed: &[u8]) -> Vec<u8> {
+    let mut data = seed.to_vec();
+    for _ in 0..1 + rng.below(6) {
+        let at = rng.below(data.len() + 1);
+        match rng.below(6) {
+            0 if at < data.len() => data[at] = rng.next() as u8,
+            1 => {
+                let token = TOKENS[rng.below(TOKENS.len())];
+                data.splice(at..at, token.iter().copied());
+            }
+            2 => {
+                let end = (at + rng.below(16)).min(data.len());
+                data.drain(at..end);
+            }
+            3 => {
+                let end = (at + rng.below(32)).min(data.len());
+                let span = data[at..end].to_vec();
+                data.splice(at..at, span);
+            }
+            4 => data.truncate(at),
+            _ => data.insert(at, b"0123456789abcdef:-, \r\n"[rng.below(22)]),
+        }
+    }
+    data
+}
+
+// Runs `target` on `iterations` mutations of each seed, failing with the
+// input that made it panic.
+pub fn run(seeds: &[&[u8]], iterations: usize, rng: &mut Rng, target: impl Fn(&[u8])) {
+    for seed in seeds {
+        for _ in 0..iterations {
+            let input = mutate(rng, seed);
+            if panic::catch_unwind(AssertUnwindSafe(|| target(&input))).is_err() {
+                panic!("parser panicked on {:?}", String::from_utf8_lossy(&input));
+            }
+        }
+    }
+}
+
+// The input as a connection whose server sends it and then closes.
+struct Input<'a>(Cursor<&'a [u8]>);
+
+impl Read for Input<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for Input<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Connection for Input<'_> {
+    fn shutdown(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Everything a response head or body off the network goes through. Errors
+// are fine; only panics are not.
+pub fn response(input: &[u8]) {
+    let Ok(response) = read_response(&mut Input(Cursor::new(input)), DEFAULT_READ_SIZE) else { return };
+    let _ = parse_resource_info(&response);
+    can_reuse(&response);
+    fields(&response);
+    // As the reply to a range request at the offset it claims, and at 0.
+    let headers = &response.headers;
+    let (content_type, content_range) = (headers.get("content-type"), headers.get("content-range"));
+    let claimed = content_range.and_then(range::parse_content_range).map_or(0, |range| range.first);
+    for start in [claimed, 0] {
+        let end = start.saturating_add(response.body.len());
+        let _ = range::validate_range(response.status, content_type, content_range, start, end, response.body.clone());
+    }
+    if let Some(boundary) = content_type.and_then(multipart::boundary) {
+        let _ = multipart::parse(&response.body, &boundary);
+    }
+}
+
+// The reply to an upload, after any interim 1xx responses.
+pub fn final_response(input: &[u8]) {
+    if let Ok(response) = upload::read_final_response(&mut Cursor::new(input), Vec::new()) {
+        fields(&response);
+    }
+}
+
+// A chunked body on its own, read both ways.
+pub fn chunked_body(input: &[u8]) {
+    #[cfg(any(feature = "async", feature = "event-loop"))]
+    let _ = chunked::body_len(input);
+    let mut decoder = chunked::Decoder::new(Cursor::new(input.to_vec()), Vec::new());
+    let _ = decoder.read_to_end(&mut Vec::new());
+    decoder.finish();
+}
+
+// A multipart/byteranges body, its boundary on the first line.
+pub fn multipart_body(input: &[u8]) {
+    let split = input.iter().position(|&byte| byte == b'\n').unwrap_or(input.len());
+    let (boundary, body) = input.split_at(split);
+    let _ = multipart::parse(body.get(1..).unwrap_or_default(), &String::from_utf8_lossy(boundary));
+}
+
+// The fields other modules interpret.
+fn fields(response: &Response) {
+    let headers = &response.headers;
+    let url = Url::parse("http://fuzz.test/dir/file").unwrap();
+    retry::delay(headers);
+    integrity::expected_digests(&headers.get_all("repr-digest"), headers.get("content-md5"));
+    Auth::new(None, None).challenge(&url, &headers.get_all("www-authenticate"));
+    let mut jar = CookieJar::default();
+    for cookie in headers.get_all("set-cookie") {
+        jar.store(&url, cookie);
+    }
+    jar.header(&url);
+}
@@ -0,0 +1,200 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use tracing::warn;
+
+// The digest the downloaded data is summarized with, chosen with --hash to
+// match whatever checksum the publisher provides.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+    Blake3,
+    Crc32,
+}
+
+pub const ALGORITHMS: [Algorithm; 6] =
+    [Algorithm::Md5, Algorithm::Sha1, Algorithm::Sha256, Algorithm::Sha512, Algorithm::Blake3, Algorithm::Crc32];
+
+impl Algorithm {
+    pub fn parse(name: &str) -> io::Result<Algorithm> {
+        ALGORITHMS.into_iter().find(|algorithm| algorithm.name().eq_ignore_ascii_case(name)).ok_or_else(|| {
+            let names: Vec<_> = ALGORITHMS.iter().map(|algorithm| algorithm.name()).collect();
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unknown hash algorithm {}; expected one of {}", name, names.join(", ")),
+            )
+        })
+    }
+
+    // As given on the command line and in state files.
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "md5",
+            Algorithm::Sha1 => "sha1",
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Sha512 => "sha512",
+            Algorithm::Blake3 => "blake3",
+            Algorithm::Crc32 => "crc32",
+        }
+    }
+
+    // As printed in messages.
+    pub fn label(self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "MD5",
+            Algorithm::Sha1 => "SHA-1",
+            Algorithm::Sha256 => "SHA-256",
+            Algorithm::Sha512 => "SHA-512",
+            Algorithm::Blake3 => "BLAKE3",
+            Algorithm::Crc32 => "CRC32",
+        }
+    }
+
+    // Digest length in bytes.
+    pub fn len(self) -> usize {
+        match self {
+            Algorithm::Md5 => 16,
+            Algorithm::Sha1 => 20,
+            Algorithm::Sha256 | Algorithm::Blake3 => 32,
+            Algorithm::Sha512 => 64,
+            Algorithm::Crc32 => 4,
+        }
+    }
+
+    pub fn hasher(self) -> Box<dyn Hasher> {
+        match self {
+            Algorithm::Md5 => Box::new(Md5::new()),
+            Algorithm::Sha1 => Box::new(Sha1::new()),
+            Algorithm::Sha256 => Box::new(Sha256::new()),
+            Algorithm::Sha512 => Box::new(Sha512::new()),
+            Algorithm::Blake3 => Box::new(Blake3 { hasher: blake3::Hasher::new(), pool: None }),
+            Algorithm::Crc32 => Box::new(Crc32(crc32fast::Hasher::new())),
+        }
+    }
+
+    // Like hasher, with BLAKE3 hashing large updates on up to `threads`
+    // threads so it keeps up with fast downloads. The others run on one.
+    pub fn threaded_hasher(self, threads: usize) -> Box<dyn Hasher> {
+        match self {
+            Algorithm::Blake3 => Box::new(Blake3 { hasher: blake3::Hasher::new(), pool: hash_pool(threads) }),
+            algorithm => algorithm.hasher(),
+        }
+    }
+}
+
+// Anything the ordered bytes can be fed through to get a digest.
+pub trait Hasher: Send {
+    fn update(&mut self, data: &[u8]);
+
+    // The digest of the data so far; more can still be added after.
+    fn digest(&self) -> Vec<u8>;
+}
+
+impl<D: Digest + Clone + Send> Hasher for D {
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(self, data);
+    }
+
+    fn digest(&self) -> Vec<u8> {
+        self.clone().finalize().to_vec()
+    }
+}
+
+// Updates smaller than this are hashed on the calling thread; below it,
+// handing the chunks to the pool costs more than it saves.
+const BLAKE3_PARALLEL_MIN: usize = 128 * 1024;
+
+struct Blake3 {
+    hasher: blake3::Hasher,
+    // Where large updates are hashed, with --hash-threads threads.
+    pool: Option<rayon::ThreadPool>,
+}
+
+impl Hasher for Blake3 {
+    fn update(&mut self, data: &[u8]) {
+        match &self.pool {
+            Some(pool) if data.len() >= BLAKE3_PARALLEL_MIN => {
+                let hasher = &mut self.hasher;
+                pool.install(|| hasher.update_rayon(data));
+            }
+            _ => {
+                self.hasher.update(data);
+            }
+        }
+    }
+
+    fn digest(&self) -> Vec<u8> {
+        self.hasher.finalize().as_bytes().to_vec()
+    }
+}
+
+// A pool of `threads` threads for BLAKE3, or none for one thread or if it
+// cannot be started, in which case hashing stays on the calling thread.
+fn hash_pool(threads: usize) -> Option<rayon::ThreadPool> {
+    if threads <= 1 {
+        return None;
+    }
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(|i| format!("blake3-{}", i))
+        .build()
+        .map_err(|e| warn!("Cannot start {} hash threads, hashing on one: {}", threads, e))
+        .ok()
+}
+
+// CRC-32 as printed by crc32(1) and in ZIP files: big-endian.
+struct Crc32(crc32fast::Hasher);
+
+impl Hasher for Crc32 {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn digest(&self) -> Vec<u8> {
+        self.0.clone().finalize().to_be_bytes().to_vec()
+    }
+}
+
+// Threads --hash-threads defaults to: one per CPU.
+pub fn default_threads() -> usize {
+    std::thread::available_parallelism().map_or(1, usize::from)
+}
+
+pub fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// How --write-checksum lays out its line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumStyle {
+    // "<hex>  <name>", as sha256sum prints and checks with -c.
+    Gnu,
+    // "SHA256 (<name>) = <hex>", as with sha256sum --tag and BSD tools.
+    Bsd,
+}
+
+// One line for `name`. Like coreutils, a name with a backslash or newline is
+// escaped and the line marked with a leading backslash.
+pub fn checksum_line(algorithm: Algorithm, digest: &str, name: &str, style: ChecksumStyle) -> String {
+    let escaped = name.contains(['\\', '\n']);
+    let name = name.replace('\\', "\\\\").replace('\n', "\\n");
+    let marker = if escaped { "\\" } else { "" };
+    match style {
+        ChecksumStyle::Gnu => format!("{}{}  {}\n", marker, digest, name),
+        ChecksumStyle::Bsd => format!("{}{} ({}) = {}\n", marker, algorithm.name().to_uppercase(), name, digest),
+    }
+}
+
+// The checksum file for a download to `output`, e.g. "<output>.sha256".
+pub fn checksum_path(output: &Path, algorithm: Algorithm) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".");
+    path.push(algorithm.name());
+    PathBuf::from(path)
+}
@@ -0,0 +1,81 @@
+use std::io;
+
+use crate::error::DownloadError;
+
+// The header fields of a response in the order they arrived, trailers last.
+// Names keep the case the server sent and are compared case-insensitively.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeaderMap {
+    fields: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    // Parses "Name: value" lines, as in a head after its status line or in
+    // the trailer section of a chunked body. Lines without a colon are
+    // skipped.
+    pub fn parse(fields: &[u8]) -> io::Result<HeaderMap> {
+        let fields =
+            std::str::from_utf8(fields).map_err(|e| DownloadError::Parse(format!("Invalid UTF-8 in headers: {}", e)))?;
+        let fields = fields
+            .lines()
+            .filter_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                Some((name.trim().to_string(), value.trim().to_string()))
+            })
+            .collect();
+        Ok(HeaderMap { fields })
+    }
+
+    // Value of the first field with this name.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value)
+    }
+
+    // Values of every field with this name, for fields such as Set-Cookie
+    // that may not be combined into one line.
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.iter().filter(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value).collect()
+    }
+
+    // Every value of a list field such as Connection, joined with commas as
+    // repeated lines may be (RFC 9110 5.3).
+    pub fn get_joined(&self, name: &str) -> Option<String> {
+        let values = self.get_all(name);
+        (!values.is_empty()).then(|| values.join(", "))
+    }
+
+    // Whether the last transfer coding is chunked, which then frames the body
+    // whatever Content-Length says (RFC 9112 6.3).
+    pub fn is_chunked(&self) -> bool {
+        self.get_joined("transfer-encoding")
+            .is_some_and(|codings| codings.rsplit(',').next().is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked")))
+    }
+
+    // The length Content-Length gives the body. Repeated fields and lists
+    // must all name the same length; anything else is malformed rather than
+    // a length to guess from.
+    pub fn content_length(&self) -> io::Result<Option<usize>> {
+        let Some(values) = self.get_joined("content-length") else { return Ok(None) };
+        let mut length = None;
+        for value in values.split(',').map(str::trim) {
+            let parsed = value
+                .parse::<usize>()
+                .ok()
+                .filter(|_| value.bytes().all(|b| b.is_ascii_digit()))
+                .ok_or_else(|| DownloadError::Parse(format!("Malformed Content-Length: {}", values)))?;
+            if length.is_some_and(|length| length != parsed) {
+                return Err(DownloadError::Parse(format!("Conflicting Content-Length: {}", values)).into());
+            }
+            length = Some(parsed);
+        }
+        Ok(length)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.fields.push((name.to_string(), value.to_string()));
+    }
+}
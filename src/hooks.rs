@@ -0,0 +1,48 @@
+use std::sync::Arc;
+
+use crate::response::Response;
+use crate::url::Url;
+
+// A request about to be sent. Hooks may add, change or remove the extra
+// headers; Host, Range, Authorization and Connection are set around them.
+pub struct Request<'a> {
+    pub method: &'a str,
+    pub url: &'a Url,
+    pub headers: Vec<(String, String)>,
+}
+
+type RequestHook = Arc<dyn Fn(&mut Request) + Send + Sync>;
+type ResponseHook = Arc<dyn Fn(&Url, &Response) + Send + Sync>;
+
+// Interceptors registered on a Client, shared by its clones and run in
+// registration order. Request hooks run again for every retry and redirect.
+// Response hooks see every response, final or not: redirects, 401s and error
+// statuses reach them before they are followed, answered or retried. Bodies
+// are empty where only the head was read.
+#[derive(Clone, Default)]
+pub struct Hooks {
+    request: Vec<RequestHook>,
+    response: Vec<ResponseHook>,
+}
+
+impl Hooks {
+    pub fn on_request(&mut self, hook: impl Fn(&mut Request) + Send + Sync + 'static) {
+        self.request.push(Arc::new(hook));
+    }
+
+    pub fn on_response(&mut self, hook: impl Fn(&Url, &Response) + Send + Sync + 'static) {
+        self.response.push(Arc::new(hook));
+    }
+
+    pub fn request(&self, request: &mut Request) {
+        for hook in &self.request {
+            hook(request);
+        }
+    }
+
+    pub fn response(&self, url: &Url, response: &Response) {
+        for hook in &self.response {
+            hook(url, response);
+        }
+    }
+}
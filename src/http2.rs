@@ -0,0 +1,347 @@
+use std::io;
+
+use bytes::Bytes;
+use h2::client::SendRequest;
+use tokio::net::TcpStream;
+use tokio::runtime::Runtime;
+use tracing::{debug, debug_span, warn};
+
+use crate::auth::Auth;
+use crate::client::Client;
+#[cfg(unix)]
+use crate::connector::connect_unix;
+use crate::error::DownloadError;
+use crate::hooks::{Hooks, Request};
+use crate::integrity;
+use crate::metrics;
+use crate::queue::WorkQueue;
+use crate::range;
+use crate::store::Chunks;
+use crate::url::Url;
+use crate::ResourceInfo;
+
+// Size of the sub-ranges a worker multiplexes over its connection. Small
+// enough that the server never truncates them, large enough to keep the
+// number of streams reasonable.
+const PIECE_SIZE: usize = 64 * 1024;
+
+// Flow-control windows large enough that many streams can be in flight
+// without stalling on WINDOW_UPDATE round trips.
+const STREAM_WINDOW: u32 = 1024 * 1024;
+const CONNECTION_WINDOW: u32 = 16 * 1024 * 1024;
+
+// A single HTTP/2 connection driven by its own current-thread runtime, so it
+// can be used from a plain worker thread.
+pub struct Http2Connection {
+    runtime: Runtime,
+    send: SendRequest<Bytes>,
+    headers: Vec<(String, String)>,
+    if_range: Option<String>,
+    range_offset: usize,
+    auth: Auth,
+    hooks: Hooks,
+}
+
+impl Http2Connection {
+    // Returns Ok(None) when the server does not negotiate h2 over TLS, so the
+    // caller can fall back to HTTP/1.1.
+    pub fn connect(url: &Url, client: &Client) -> io::Result<Option<Http2Connection>> {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_io().build()?;
+        let send = runtime.block_on(handshake(url, client))?;
+        let mut headers = client.headers.clone();
+        if let Some(cookie) = client.cookie_header(url) {
+            headers.push(("cookie".to_string(), cookie));
+        }
+        Ok(send.map(|send| Http2Connection {
+            runtime,
+            send,
+            headers,
+            if_range: client.if_range.clone(),
+            range_offset: client.range_offset,
+            auth: client.auth.clone(),
+            hooks: client.hooks.clone(),
+        }))
+    }
+
+    // The connection's headers as the request hooks leave them, plus a fresh
+    // Authorization value, which for Digest changes with every request.
+    fn request_headers(&self, url: &Url) -> Vec<(String, String)> {
+        let mut request = Request { method: "GET", url, headers: self.headers.clone() };
+        self.hooks.request(&mut request);
+        let mut headers = request.headers;
+        if let Some(authorization) = self.auth.authorization("GET", url) {
+            headers.push(("authorization".to_string(), authorization));
+        }
+        headers
+    }
+
+    // Reads the resource headers from a GET response and cancels the stream
+    // before the body is transferred.
+    pub fn resource_info(&self, url: &Url) -> io::Result<ResourceInfo> {
+        self.runtime.block_on(async {
+            let request = build_request(url.to_string(), &self.request_headers(url), None, None)?;
+            let mut send = self.send.clone().ready().await.map_err(h2_error)?;
+            let (response, _) = send.send_request(request, true).map_err(h2_error)?;
+            let response = response.await.map_err(h2_error)?;
+            if !response.status().is_success() {
+                return Err(DownloadError::HttpStatus {
+                    code: response.status().as_u16(),
+                    message: format!("HTTP/2 probe returned status {}", response.status()),
+                }
+                .into());
+            }
+            let header = |name| response.headers().get(name).and_then(|value| value.to_str().ok());
+            let total_size = header(http::header::CONTENT_LENGTH)
+                .and_then(|value| value.trim().parse().ok())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Content-Length not found"))?;
+            let content_encoding = header(http::header::CONTENT_ENCODING).map(|value| value.trim().to_string());
+            let accept_ranges = header(http::header::ACCEPT_RANGES).map(|value| value.trim().to_string());
+            let validator = range::if_range_validator(header(http::header::ETAG), header(http::header::LAST_MODIFIED));
+            let repr_digest: Vec<String> = response
+                .headers()
+                .get_all("repr-digest")
+                .iter()
+                .filter_map(|value| value.to_str().ok().map(str::to_string))
+                .collect();
+            let content_md5 = response.headers().get("content-md5").and_then(|value| value.to_str().ok());
+            let digests = integrity::expected_digests(&repr_digest, content_md5);
+            let content_disposition = header(http::header::CONTENT_DISPOSITION).map(str::to_string);
+            Ok(ResourceInfo { total_size, content_encoding, accept_ranges, validator, digests, content_disposition })
+        })
+    }
+
+    // Asks for the first two bytes and drops the stream once the head is in:
+    // a 206 means ranges work, a 200 means the server ignored the Range.
+    pub fn probe_range_support(&self, url: &Url) -> io::Result<bool> {
+        self.runtime.block_on(async {
+            let (start, end) = (self.range_offset, 1 + self.range_offset);
+            let request = build_request(url.to_string(), &self.request_headers(url), None, Some((start, end)))?;
+            let mut send = self.send.clone().ready().await.map_err(h2_error)?;
+            let (response, _) = send.send_request(request, true).map_err(h2_error)?;
+            let response = response.await.map_err(h2_error)?;
+            Ok(response.status().as_u16() == 206)
+        })
+    }
+
+    // Issues one stream per range concurrently and returns the bodies in the
+    // same order. `end` is passed through exactly like download_chunk's.
+    pub fn fetch_ranges(&self, url: &Url, ranges: &[(usize, usize)]) -> Vec<io::Result<Vec<u8>>> {
+        self.runtime.block_on(async {
+            let tasks: Vec<_> = ranges
+                .iter()
+                .map(|&(start, end)| {
+                    let (start, end) = (start + self.range_offset, end + self.range_offset);
+                    let request = build_request(
+                        url.to_string(),
+                        &self.request_headers(url),
+                        self.if_range.as_deref(),
+                        Some((start, end)),
+                    );
+                    tokio::spawn(fetch(self.send.clone(), request, self.if_range.clone(), start, end))
+                })
+                .collect();
+            let mut results = Vec::with_capacity(tasks.len());
+            for task in tasks {
+                results.push(task.await.unwrap_or_else(|e| Err(io::Error::other(e))));
+            }
+            results
+        })
+    }
+}
+
+// Redirects are not followed here; they fail the probe so the HTTP/1.1 probe,
+// which follows them, runs instead.
+pub fn get_resource_info(url: &Url, client: &Client) -> io::Result<(Url, ResourceInfo)> {
+    let info = Http2Connection::connect(url, client)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "server did not negotiate h2"))?
+        .resource_info(url)?;
+    Ok((url.clone(), info))
+}
+
+// The HTTP/2 counterpart of the HTTP/1.1 range probe, for servers whose size
+// probe went over h2 and which may not speak HTTP/1.1 at all.
+pub fn probe_range_support(url: &Url, client: &Client) -> io::Result<bool> {
+    Http2Connection::connect(url, client)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "server did not negotiate h2"))?
+        .probe_range_support(url)
+}
+
+// Downloads ranges from the queue for one worker over a single HTTP/2
+// connection until it runs dry. Truncated pieces are re-requested from where
+// they stopped. Fails only if no HTTP/2 connection could be used at all, in
+// which case the range taken is given back, or the resource changed; pieces
+// lost otherwise are left as gaps for the main thread's fallback loop.
+pub fn fetch_range(thread: usize, url: &Url, client: &Client, queue: &WorkQueue, chunks: &Chunks) -> io::Result<()> {
+    let connection = Http2Connection::connect(url, client)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "server did not negotiate h2"))?;
+    queue.connected(thread, 1);
+    let mut first_round = true;
+
+    while let Some((start, range_end)) = queue.next() {
+        let _range = debug_span!("range", start, end = range_end).entered();
+        let mut pending: Vec<(usize, usize)> = (start..=range_end)
+            .step_by(PIECE_SIZE)
+            .map(|piece_start| (piece_start, (piece_start + PIECE_SIZE - 1).min(range_end)))
+            .collect();
+
+        while !pending.is_empty() {
+            debug!("requesting {} ranges over HTTP/2", pending.len());
+            queue.requested(thread, pending.len());
+            let results = connection.fetch_ranges(url, &pending.iter().map(|&(s, e)| (s, e + 1)).collect::<Vec<_>>());
+            if first_round && results.iter().all(|result| result.is_err()) {
+                queue.give_back(pending.into_iter());
+                return Err(results.into_iter().find_map(Result::err).unwrap_or_else(|| io::Error::other("HTTP/2 failed")));
+            }
+            first_round = false;
+
+            let mut next = Vec::new();
+            for ((piece_start, piece_end), result) in pending.into_iter().zip(results) {
+                match result {
+                    Ok(chunk) if !chunk.is_empty() => {
+                        let fetched_to = piece_start + chunk.len();
+                        queue.record(thread, piece_start, chunk.len());
+                        chunks.insert(piece_start, chunk)?;
+                        if fetched_to <= piece_end {
+                            next.push((fetched_to, piece_end));
+                        }
+                    }
+                    Ok(_) => {
+                        queue.failed(thread);
+                        metrics::METRICS.failed(url);
+                        warn!("received empty HTTP/2 chunk for {}-{}", piece_start, piece_end);
+                    }
+                    Err(e) if range::is_resource_changed(&e) => return Err(e),
+                    Err(e) => {
+                        queue.failed(thread);
+                        metrics::METRICS.failed(url);
+                        warn!("failed HTTP/2 download {}-{}: {}", piece_start, piece_end, e);
+                    }
+                }
+            }
+            pending = next;
+        }
+        debug!("completed over HTTP/2");
+    }
+    Ok(())
+}
+
+#[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+async fn handshake(url: &Url, client: &Client) -> io::Result<Option<SendRequest<Bytes>>> {
+    #[cfg(unix)]
+    if let Some(path) = client.connector.unix_socket() {
+        let stream = connect_unix(url, path)?;
+        stream.set_nonblocking(true)?;
+        return start(tokio::net::UnixStream::from_std(stream)?).await.map(Some);
+    }
+    // Through a proxy, h2 (and h2c prior knowledge) needs a CONNECT tunnel.
+    let tcp = match client.connector.proxy(url) {
+        Some(proxy) => proxy.tunnel(url)?,
+        None => client.connector.connect_direct(url)?,
+    };
+    client.connector.tune(&tcp)?;
+    tcp.set_nonblocking(true)?;
+    let tcp = TcpStream::from_std(tcp)?;
+    match url.scheme.as_str() {
+        // Plaintext uses prior knowledge (h2c); a server that only speaks
+        // HTTP/1.1 fails the first request, which triggers the fallback.
+        "http" => start(tcp).await.map(Some),
+        #[cfg(feature = "tls")]
+        "https" => {
+            let server_name = rustls::pki_types::ServerName::try_from(url.host.clone()).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid TLS server name {}: {}", url.host, e))
+            })?;
+            let tls = tokio_rustls::TlsConnector::from(client.connector.h2_tls_config())
+                .connect(server_name, tcp)
+                .await?;
+            if tls.get_ref().1.alpn_protocol() != Some(b"h2") {
+                return Ok(None);
+            }
+            start(tls).await.map(Some)
+        }
+        scheme => Err(io::Error::new(io::ErrorKind::Unsupported, format!("HTTP/2 is not supported for {}", scheme))),
+    }
+}
+
+async fn start<T>(io: T) -> io::Result<SendRequest<Bytes>>
+where
+    T: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    let (send, connection) = h2::client::Builder::new()
+        .initial_window_size(STREAM_WINDOW)
+        .initial_connection_window_size(CONNECTION_WINDOW)
+        .handshake(io)
+        .await
+        .map_err(h2_error)?;
+    // Connection errors surface through the individual streams.
+    tokio::spawn(async move {
+        let _ = connection.await;
+    });
+    Ok(send)
+}
+
+fn build_request(
+    uri: String,
+    headers: &[(String, String)],
+    if_range: Option<&str>,
+    range: Option<(usize, usize)>,
+) -> io::Result<http::Request<()>> {
+    let mut builder = http::Request::get(uri);
+    if let Some((start, end)) = range {
+        builder = builder.header("range", format!("bytes={}-{}", start, end));
+        if let Some(validator) = if_range {
+            builder = builder.header("if-range", validator);
+        }
+    }
+    for (name, value) in headers {
+        builder = builder.header(name.as_str(), value.as_str());
+    }
+    builder.body(()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+async fn fetch(
+    send: SendRequest<Bytes>,
+    request: io::Result<http::Request<()>>,
+    if_range: Option<String>,
+    start: usize,
+    end: usize,
+) -> io::Result<Vec<u8>> {
+    let request = request?;
+    let mut send = send.ready().await.map_err(h2_error)?;
+    let (response, _) = send.send_request(request, true).map_err(h2_error)?;
+    let response = response.await.map_err(h2_error)?;
+    if !response.status().is_success() {
+        return Err(DownloadError::HttpStatus {
+            code: response.status().as_u16(),
+            message: format!("HTTP/2 request failed with status {}", response.status()),
+        }
+        .into());
+    }
+
+    let status = response.status().as_u16();
+    let header = |name| response.headers().get(name).and_then(|value| value.to_str().ok()).map(str::to_string);
+    range::check_unchanged(
+        if_range.as_deref(),
+        status,
+        header(http::header::ETAG).as_deref(),
+        header(http::header::LAST_MODIFIED).as_deref(),
+    )?;
+    let content_type = header(http::header::CONTENT_TYPE);
+    let content_range = header(http::header::CONTENT_RANGE);
+
+    let mut body = response.into_body();
+    let mut data = Vec::new();
+    while let Some(frame) = body.data().await {
+        let frame = frame.map_err(h2_error)?;
+        let _ = body.flow_control().release_capacity(frame.len());
+        data.extend_from_slice(&frame);
+    }
+    range::validate_range(status, content_type.as_deref(), content_range.as_deref(), start, end, data)
+}
+
+fn h2_error(e: h2::Error) -> io::Error {
+    if e.is_io() {
+        e.into_io().unwrap_or_else(|| io::Error::other("HTTP/2 I/O error"))
+    } else {
+        io::Error::other(format!("HTTP/2 error: {}", e))
+    }
+}
@@ -0,0 +1,123 @@
+use std::io::{self, Write};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use tracing::info;
+
+use crate::error::DownloadError;
+use crate::hash::{Algorithm, Hasher};
+
+// Want-Repr-Digest value for --want-repr-digest: SHA-256, the digest this
+// client prints itself.
+pub const WANT_REPR_DIGEST: &str = "sha-256=1";
+
+// A digest of the whole representation announced by the server: Content-MD5
+// (RFC 1864) or RFC 9530 Repr-Digest. Both cover the bytes as sent, before any
+// Content-Encoding is undone.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpectedDigest {
+    // Header and algorithm, e.g. "Repr-Digest sha-256", for messages.
+    pub source: String,
+    algorithm: Algorithm,
+    value: Vec<u8>,
+}
+
+// The digests this client can check, from the values of every Repr-Digest
+// field and of Content-MD5. Repr-Digest algorithms RFC 9530 marks insecure
+// (md5, sha) and unknown ones are skipped, as are malformed values.
+pub fn expected_digests(repr_digest: &[impl AsRef<str>], content_md5: Option<&str>) -> Vec<ExpectedDigest> {
+    let mut digests = Vec::new();
+    for member in repr_digest.iter().flat_map(|value| value.as_ref().split(',')) {
+        let Some((key, value)) = member.split_once('=') else { continue };
+        let key = key.trim().to_lowercase();
+        let algorithm = match key.as_str() {
+            "sha-256" => Algorithm::Sha256,
+            "sha-512" => Algorithm::Sha512,
+            _ => continue,
+        };
+        // A structured-field byte sequence, ":base64:", maybe with parameters.
+        let value = value.split(';').next().unwrap_or("").trim();
+        let Some(encoded) = value.strip_prefix(':').and_then(|value| value.strip_suffix(':')) else { continue };
+        if let Ok(value) = BASE64.decode(encoded) {
+            digests.push(ExpectedDigest { source: format!("Repr-Digest {}", key), algorithm, value });
+        }
+    }
+    if let Some(value) = content_md5.and_then(|value| BASE64.decode(value.trim()).ok()) {
+        digests.push(ExpectedDigest { source: "Content-MD5".to_string(), algorithm: Algorithm::Md5, value });
+    }
+    digests.retain(|digest| digest.value.len() == digest.algorithm.len());
+    digests
+}
+
+// Compares in time that depends only on the lengths, so a digest supplied
+// with --expect cannot be probed byte by byte through timing.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+// Checks the hex digest of the data against --expect; `label` names the
+// algorithm in messages.
+pub fn check_expected(label: &str, expected: &str, actual: &str) -> io::Result<()> {
+    if !constant_time_eq(expected.as_bytes(), actual.as_bytes()) {
+        return Err(DownloadError::Integrity(format!("{} mismatch: expected {}, received data has {}", label, expected, actual)).into());
+    }
+    info!("Verified the expected {}", label);
+    Ok(())
+}
+
+// Passes the ordered, still encoded byte stream on to `inner` while hashing it
+// for each expected digest.
+pub struct Verifier<W: Write> {
+    inner: W,
+    checks: Vec<(ExpectedDigest, Box<dyn Hasher>)>,
+}
+
+impl<W: Write> Verifier<W> {
+    pub fn new(digests: Vec<ExpectedDigest>, inner: W) -> Verifier<W> {
+        let checks = digests
+            .into_iter()
+            .map(|digest| {
+                let hasher = digest.algorithm.hasher();
+                (digest, hasher)
+            })
+            .collect();
+        Verifier { inner, checks }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    // Compares every digest with what was received; a mismatch means the
+    // bytes are not the ones the server described.
+    pub fn finish(self) -> io::Result<W> {
+        for (expected, hasher) in self.checks {
+            let actual = hasher.digest();
+            if actual != expected.value {
+                return Err(DownloadError::Integrity(format!(
+                    "{} mismatch: server sent {}, received data has {}",
+                    expected.source,
+                    BASE64.encode(&expected.value),
+                    BASE64.encode(&actual)
+                ))
+                .into());
+            }
+            info!("Verified {}", expected.source);
+        }
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for Verifier<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        for (_, hasher) in &mut self.checks {
+            hasher.update(&buf[..written]);
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,1656 @@
+use std::io::{self, Read, Write};
+use std::env;
+use std::collections::BTreeMap;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use tracing::{debug, error, info, info_span, trace, warn};
+
+mod auth;
+mod bench;
+mod cache;
+mod callbacks;
+mod cassette;
+mod cancel;
+mod chunked;
+mod cli;
+mod client;
+mod completions;
+mod config;
+mod connector;
+mod cookies;
+mod deadline;
+mod decode;
+mod download;
+#[cfg(feature = "async")]
+mod downloader;
+mod error;
+#[cfg(feature = "event-loop")]
+mod event_loop;
+mod hash;
+mod headers;
+mod hooks;
+#[cfg(feature = "http2")]
+mod http2;
+mod integrity;
+mod log;
+mod metrics;
+mod mirror;
+mod multipart;
+mod netrc;
+mod output;
+mod pieces;
+mod pipeline;
+mod pool;
+mod progress;
+mod proxy;
+mod queue;
+mod ramp;
+mod range;
+mod request;
+mod resolver;
+mod response;
+mod resume;
+mod retry;
+mod stats;
+mod status;
+mod store;
+mod stream;
+mod transport;
+mod upload;
+mod url;
+mod warc;
+
+use auth::Auth;
+use cli::BodySource;
+use client::Client;
+use config::DownloadConfig;
+use connector::{Connector, KeepAlive};
+use decode::ContentDecoder;
+use error::DownloadError;
+use proxy::ProxySettings;
+use request::RequestBuilder;
+use resolver::DohResolver;
+use response::{BodyReader, Response};
+use transport::{Cancellable, Connection, Transport};
+use upload::Body;
+use url::Url;
+
+// cargo-fuzz builds with --cfg fuzzing; the targets in fuzz/ call in here.
+#[cfg(any(test, fuzzing))]
+pub mod fuzz;
+#[cfg(test)]
+mod test_server;
+#[cfg(test)]
+mod tests;
+
+// Each kind of failure exits with its own status (see error::exit_code), so
+// scripts can tell them apart, and a run stopped by --max-time or Ctrl-C
+// from a failure, to run it again with --continue.
+pub fn main() -> ExitCode {
+    let args: Vec<String> = env::args().collect();
+    // clap prints its own errors with the usage, and --help and --version
+    // the same way with a success status.
+    let usage_error = |e: io::Error| match e.get_ref().and_then(|e| e.downcast_ref::<clap::Error>()) {
+        Some(e) => {
+            let _ = e.print();
+            ExitCode::from(e.exit_code() as u8)
+        }
+        None => {
+            eprintln!("Error: {}", error::report(&e));
+            eprintln!("For more information, try '--help'.");
+            ExitCode::from(error::EXIT_USAGE)
+        }
+    };
+    let result = match cli::with_env(&args, |name| env::var(name).ok()).and_then(|args| cli::parse(&args)) {
+        Ok(cli::Mode::Completions { shell }) => {
+            let program = std::path::Path::new(&args[0]).file_name().and_then(|name| name.to_str()).unwrap_or("http_client");
+            io::stdout().write_all(&completions::script(shell, program))
+        }
+        Ok(mode) => match mode.resolve() {
+            Ok(parsed) => run(parsed),
+            Err(e) => return usage_error(e),
+        },
+        Err(e) => return usage_error(e),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", error::report(&e));
+            ExitCode::from(error::exit_code(&e))
+        }
+    }
+}
+
+fn run(parsed: cli::Args) -> io::Result<()> {
+    let cli::Args {
+        mut urls,
+        input_file,
+        mirrors,
+        max_concurrent_downloads,
+        num_threads,
+        tls,
+        http2,
+        asynchronous,
+        event_loop,
+        decompress,
+        compressed,
+        max_redirects,
+        cache,
+        pipeline_depth,
+        headers,
+        user_agent,
+        credentials,
+        bearer_token,
+        netrc,
+        ip_family,
+        socket_options,
+        connect_timeout,
+        read_timeout,
+        doh_url,
+        unix_socket,
+        request,
+        want_repr_digest,
+        output,
+        remote_name,
+        continue_download,
+        mmap,
+        sync,
+        piece_hashes,
+        max_buffer,
+        spill,
+        chunk_size,
+        read_size,
+        max_attempts,
+        byte_range,
+        bench,
+        probe,
+        checksums,
+        hashes,
+        hash_threads,
+        expect,
+        write_checksum,
+        strict,
+        stdout,
+        max_time,
+        stats,
+        stats_json,
+        json,
+        metrics_addr,
+        record,
+        replay,
+        warc,
+        log_level,
+    } = parsed;
+    // Messages go to stderr whenever stdout carries data: the downloaded
+    // bytes, a response body, a report or checksum lines.
+    let saved = output.is_some() || remote_name;
+    if stdout || json || probe || checksums.is_some() || (request.is_some() && !saved) {
+        status::to_stderr();
+    }
+    log::init(log_level);
+    if let Some(addr) = &metrics_addr {
+        metrics::serve(addr, &metrics::METRICS)?;
+    }
+
+    #[cfg(not(feature = "http2"))]
+    if http2 {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "HTTP/2 support is not compiled in; rebuild with the `http2` feature",
+        ));
+    }
+
+    #[cfg(not(all(unix, feature = "mmap")))]
+    if mmap {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "--mmap support is not compiled in; rebuild with the `mmap` feature on a Unix system",
+        ));
+    }
+
+    if let Some(path) = input_file {
+        let text = match path.to_str() {
+            Some("-") => io::read_to_string(io::stdin())?,
+            _ => std::fs::read_to_string(&path)
+                .map_err(|e| io::Error::new(e.kind(), format!("Cannot read {}: {}", path.display(), e)))?,
+        };
+        urls.extend(cli::parse_url_list(&text)?);
+    }
+    let Some(url) = urls.first().cloned() else {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "No URLs to download"));
+    };
+
+    let mut connector = Connector::new(&tls)?
+        .with_proxies(ProxySettings::from_env()?)
+        .with_ip_family(ip_family)
+        .with_socket_options(socket_options);
+    if let Some(path) = unix_socket {
+        connector = connector.with_unix_socket(path);
+    }
+    if let Some(doh_url) = doh_url {
+        let resolver = DohResolver::new(doh_url, Client::new(connector.clone()));
+        connector = connector.with_resolver(Arc::new(resolver));
+    }
+    if let Some(path) = record {
+        connector = connector.with_cassette(Arc::new(cassette::Cassette::record(&path)?));
+    }
+    if let Some(path) = replay {
+        connector = connector.with_cassette(Arc::new(cassette::Cassette::replay(&path)?));
+    }
+    if let Some(path) = warc {
+        connector = connector.with_cassette(Arc::new(cassette::Cassette::warc(&path)?));
+    }
+    // Every request and response head is logged through the hooks, like
+    // any hook a library user registers.
+    let mut client = Client::new(connector)
+        .on_request(|request| debug!("{} {}", request.method, request.url))
+        .on_response(|url, response| {
+            debug!("{} {} from {}", response.status, response.reason, url);
+            for (name, value) in response.headers.iter() {
+                trace!("{}: {}", name, value);
+            }
+        });
+    client.max_redirects = max_redirects;
+    client.read_size = read_size;
+    // .netrc is only read when neither --user nor a bearer token is given.
+    let netrc = match netrc.filter(|_| credentials.is_none() && bearer_token.is_none()) {
+        Some(path) => Some(netrc::Netrc::load(&path)?),
+        None => None,
+    };
+    client.auth = match bearer_token {
+        Some(token) => Auth::bearer(token, &url),
+        None => Auth::new(credentials, netrc),
+    };
+    if compressed {
+        client = client.header("Accept-Encoding", &decode::accept_encoding());
+    }
+    let cancel = cancel::CancellationToken::default();
+    let mut builder = DownloadConfig::builder()
+        .threads(num_threads)
+        .chunk_size(chunk_size)
+        .max_attempts(max_attempts)
+        .connect_timeout(connect_timeout)
+        .read_timeout(Some(read_timeout))
+        .max_time(max_time)
+        .hash_threads(hash_threads)
+        .expect(expect)
+        .output(output)
+        .remote_name(remote_name)
+        .stdout(stdout)
+        .continue_download(continue_download)
+        .byte_range(byte_range)
+        .mirrors(mirrors)
+        .http2(http2)
+        .event_loop(event_loop)
+        .decompress(decompress)
+        .pipeline_depth(pipeline_depth)
+        .want_repr_digest(want_repr_digest)
+        .mmap(mmap)
+        .sync(sync)
+        .piece_hashes(piece_hashes)
+        .max_buffer(max_buffer)
+        .spill(spill)
+        .write_checksum(write_checksum)
+        .strict(strict)
+        .stats(stats, stats_json)
+        .summary(json.then(Arc::default))
+        .cancellation(cancel.clone())
+        // Like the hooks, the callbacks report to the log, in the span of
+        // the thread or download they run in.
+        .on_progress(|done, total| trace!("{} of {} bytes downloaded", done, total))
+        .on_chunk_complete(|(start, end)| trace!("fetched bytes {}-{}", start, end))
+        .on_retry(|(start, end), e| warn!("failed to download {}-{}: {}, retrying", start, end, e))
+        .on_complete(|hash| debug!("download complete: {}", hash));
+    // An explicit -H User-Agent wins over the default and --user-agent.
+    let user_agent = user_agent.filter(|_| !headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("user-agent")));
+    if let Some(user_agent) = user_agent {
+        builder = builder.header("User-Agent", &user_agent);
+    }
+    for (name, value) in &headers {
+        builder = builder.header(name, value);
+    }
+    for algorithm in hashes {
+        builder = builder.hash(algorithm);
+    }
+    let mut config = builder.build()?;
+
+    if let Some(cli::Request { method, body }) = request {
+        let client = config.client(&client);
+        // Regular files have a Content-Length; stdin and pipes are chunked.
+        let response = match body {
+            Some(BodySource::File(path)) => {
+                let mut file = std::fs::File::open(&path)?;
+                let metadata = file.metadata()?;
+                let body = if metadata.is_file() { Body::Sized(&mut file, metadata.len()) } else { Body::Chunked(&mut file) };
+                upload::send(&url, &client, &method, body)?
+            }
+            Some(BodySource::Stdin) => upload::send(&url, &client, &method, Body::Chunked(&mut io::stdin().lock()))?,
+            None => upload::send(&url, &client, &method, Body::Empty)?,
+        };
+        let status = response.status_line();
+        info!("{} {}: {} {}", method, url, status.code, status.reason);
+        let output = match config.output.clone() {
+            None if config.remote_name => Some(output::remote_name(response.header("content-disposition"), &url)?),
+            output => output,
+        };
+        // A HEAD response has only its head to show.
+        let body = if method == "HEAD" {
+            let mut head = format!("{} {} {}\r\n", status.version, status.code, status.reason);
+            for (name, value) in response.headers.iter() {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+            head.into_bytes()
+        } else {
+            response.body
+        };
+        match &output {
+            Some(path) => {
+                let part = output::part_path(path);
+                std::fs::write(&part, &body)?;
+                output::commit(&part, path, config.sync)?;
+                info!("Saved {} bytes to {}", body.len(), path.display());
+            }
+            None => io::stdout().write_all(&body)?,
+        }
+        if !status.is_success() {
+            return Err(status.error(&url).into());
+        }
+        return Ok(());
+    }
+
+    if probe {
+        let client = config.client(&client);
+        let (url, info) = get_resource_info(&url, &client)?;
+        let ranges = match info.accept_ranges.as_deref() {
+            Some(units) => !units.eq_ignore_ascii_case("none"),
+            None => probe_range_support(&url, &client)?,
+        };
+        let mut out = io::stdout().lock();
+        writeln!(out, "URL: {}", url)?;
+        writeln!(out, "Size: {} bytes", info.total_size)?;
+        writeln!(out, "Ranges: {}", if ranges { "yes" } else { "no" })?;
+        writeln!(out, "Validator: {}", info.validator.as_deref().unwrap_or("none"))?;
+        writeln!(out, "Content-Encoding: {}", info.content_encoding.as_deref().unwrap_or("identity"))?;
+        return Ok(());
+    }
+    // In hash mode each digest also goes to stdout, as sha256sum prints it.
+    let algorithm = config.hashes[0];
+    let print_checksum = |url: &Url, hash: &str| match checksums {
+        Some(style) => io::stdout().write_all(hash::checksum_line(algorithm, hash, &url.to_string(), style).as_bytes()),
+        None => Ok(()),
+    };
+
+    let cache = Mutex::new(match cache {
+        Some(path) => Some(cache::Cache::load(&path)?),
+        None => None,
+    });
+    // Downloads run one at a time can take turns on the progress line.
+    if log_level >= tracing::Level::INFO && (max_concurrent_downloads == 1 || urls.len() == 1) {
+        status::start_progress();
+    }
+    if let Some(bench) = bench {
+        return bench::run(&url, &client, config, &bench);
+    }
+    // From here the first Ctrl-C stops the downloads as --max-time does,
+    // saving how far they got.
+    #[cfg(unix)]
+    cancel::cancel_on_interrupt(&cancel);
+    if let [url] = urls.as_slice() {
+        metrics::METRICS.download_started();
+        let summary = config.summary.clone();
+        let result = if stdout {
+            // The data reaches stdout through the same stream an embedder
+            // would read it from.
+            let config = DownloadConfig { stdout: false, ..config };
+            // Locked a chunk at a time, so that no other thread waits on
+            // stdout for the whole download.
+            let mut stream = download_stream(url, &client, &config, asynchronous);
+            stream
+                .by_ref()
+                .try_for_each(|bytes| io::stdout().lock().write_all(&bytes?))
+                .and_then(|()| io::stdout().flush())
+                .and_then(|()| stream.finish())
+        } else if asynchronous {
+            download_async(url, &client, &config)
+        } else {
+            download(url, &client, &config, &cache)
+        };
+        metrics::METRICS.download_finished(&result);
+        if let Some(slot) = &summary {
+            println!("{}", download_json(url, slot, &result));
+            return result.map(drop);
+        }
+        return print_checksum(url, &result?);
+    }
+
+    // Several files in flight split the threads between them.
+    let active = max_concurrent_downloads.min(urls.len());
+    config.num_threads = config.num_threads.map(|num_threads| (num_threads / active).max(1));
+    config.max_threads = (ramp::MAX_THREADS / active).max(1);
+    // A failed download does not stop the rest of a batch.
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::new());
+    thread::scope(|scope| {
+        for _ in 0..active {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(url) = urls.get(i) else { break };
+                info!("Downloading {} of {}: {}", i + 1, urls.len(), url);
+                // Tells apart the messages of downloads running at once.
+                let _download = info_span!("download", n = i + 1).entered();
+                metrics::METRICS.download_started();
+                // Each download of the batch leaves its own summary.
+                let config = DownloadConfig { summary: config.summary.as_ref().map(|_| Arc::default()), ..config.clone() };
+                let result = download(url, &client, &config, &cache);
+                metrics::METRICS.download_finished(&result);
+                if let Err(e) = &result {
+                    error!("Download of {} failed: {}", url, e);
+                }
+                let json = config.summary.as_ref().map(|slot| download_json(url, slot, &result));
+                results.lock().unwrap().push((i, result, json));
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(i, _, _)| *i);
+    let jsons: Option<Vec<String>> = results.iter_mut().map(|(_, _, json)| json.take()).collect();
+    let results: Vec<_> = results.into_iter().map(|(i, result, _)| (&urls[i], result)).collect();
+    let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+    if let Some(jsons) = &jsons {
+        println!("{}", stats::batch_json(jsons, failed));
+    }
+    info!("Summary: {} of {} downloads succeeded", results.len() - failed, results.len());
+    for (url, result) in &results {
+        match result {
+            Ok(hash) => info!("{}  {}", hash, url),
+            Err(e) => info!("FAILED  {}: {}", url, e),
+        }
+    }
+    for (url, result) in results.iter().filter(|_| jsons.is_none()) {
+        if let Ok(hash) = result {
+            print_checksum(url, hash)?;
+        }
+    }
+    if cancel.is_cancelled() {
+        return Err(cancel.cancelled(format!("{} of {} downloads finished", results.len() - failed, results.len())));
+    }
+    if let Some(deadline) = config.deadline.filter(|_| {
+        results.iter().any(|(_, result)| result.as_ref().is_err_and(deadline::is_max_time_exceeded))
+    }) {
+        return Err(deadline.exceeded(format!("{} of {} downloads finished", results.len() - failed, results.len())));
+    }
+    // A batch exits with the status of its first failure.
+    let total = results.len();
+    if let Some(e) = results.into_iter().find_map(|(_, result)| result.err()) {
+        return Err(error::context(e, &format!("{} of {} downloads failed, the first", failed, total)));
+    }
+    Ok(())
+}
+
+// The --json object for the download of `url` that left `slot`.
+fn download_json(url: &Url, slot: &stats::SummarySlot, result: &io::Result<String>) -> String {
+    match (result, slot.lock().unwrap().as_ref()) {
+        (Ok(_), Some(summary)) => summary.json(),
+        (Err(e), _) => stats::failure_json(&url.to_string(), e),
+        // Downloads that succeed always fill it.
+        (Ok(hash), None) => format!("{{\"url\":{},\"hash\":{}}}", stats::json_string(&url.to_string()), stats::json_string(hash)),
+    }
+}
+
+// Downloads and verifies one URL, returning the hex digest of its data with
+// the first algorithm.
+fn download(url: &Url, client: &Client, config: &DownloadConfig, cache: &Mutex<Option<cache::Cache>>) -> io::Result<String> {
+    if config.event_loop {
+        return download_event_loop(url, client, config);
+    }
+    #[cfg_attr(not(feature = "http2"), allow(unused_variables))]
+    let DownloadConfig {
+        num_threads,
+        max_threads,
+        http2,
+        decompress,
+        pipeline_depth,
+        want_repr_digest,
+        remote_name,
+        continue_download,
+        mmap,
+        piece_hashes,
+        max_buffer,
+        spill,
+        chunk_size,
+        max_attempts,
+        byte_range,
+        write_checksum,
+        strict,
+        stdout,
+        deadline,
+        stats,
+        ..
+    } = *config;
+    if let Some(deadline) = &deadline {
+        deadline.check(&format!("downloading {}", url))?;
+    }
+    config.cancel.check(&format!("downloading {}", url))?;
+    let started = Instant::now();
+    let hashes = &config.hashes;
+    let output = config.output.clone();
+    let mut client = config.client(client);
+    let cache_key = url.to_string();
+    // Only the SHA-256 of a whole resource is cached, so a download to a
+    // file or stdout, of a --range, or with other --hash algorithms, always
+    // runs.
+    let sha256_only = hashes[..] == [hash::Algorithm::Sha256] && byte_range.is_none();
+    let cached = cache
+        .lock()
+        .unwrap()
+        .as_ref()
+        .filter(|_| output.is_none() && !remote_name && !stdout && sha256_only)
+        .and_then(|cache| cache.get(&cache_key, decompress).cloned());
+    if let Some(entry) = cached {
+        match probe_not_modified(url, &client, &entry.validator) {
+            Ok(true) => {
+                info!("Not modified since the cached download ({})", entry.validator);
+                info!("Final message - SHA-256 hash of the downloaded data: {}", entry.hash);
+                if let Some((algorithm, expected)) = &config.expect {
+                    integrity::check_expected(algorithm.label(), expected, &entry.hash)?;
+                }
+                if let Some(slot) = &config.summary {
+                    *slot.lock().unwrap() = Some(stats::Summary {
+                        url: url.to_string(),
+                        total_size: 0,
+                        connections: Vec::new(),
+                        main: stats::Connection::default(),
+                        elapsed: started.elapsed(),
+                        digests: vec![(hash::Algorithm::Sha256, entry.hash.clone())],
+                        failures: Vec::new(),
+                        pieces: Vec::new(),
+                    });
+                }
+                config.callbacks.complete(&entry.hash);
+                return Ok(entry.hash.clone());
+            }
+            Ok(false) => info!("Resource modified since the cached download"),
+            Err(e) => warn!("Conditional request failed, downloading: {}", e),
+        }
+    }
+    // The digest is only asked for on requests for the whole resource, not
+    // on every range request.
+    let probe_client = if want_repr_digest {
+        client.clone().header("Want-Repr-Digest", integrity::WANT_REPR_DIGEST)
+    } else {
+        client.clone()
+    };
+    #[cfg(feature = "http2")]
+    let probed = if http2 {
+        http2::get_resource_info(url, &probe_client)
+            .map_err(|e| warn!("HTTP/2 size probe failed, using HTTP/1.1: {}", e))
+            .ok()
+    } else {
+        None
+    };
+    #[cfg(not(feature = "http2"))]
+    let probed = None;
+    #[cfg(feature = "http2")]
+    let over_http2 = probed.is_some();
+    let (url, info) = match probed {
+        Some(probed) => probed,
+        None => get_resource_info(url, &probe_client)?,
+    };
+    let total_size = info.total_size;
+    info!("Total size to download: {} bytes", total_size);
+    client.if_range = info.validator.clone();
+
+    let ranges_supported = match info.accept_ranges.as_deref() {
+        Some(units) => !units.eq_ignore_ascii_case("none"),
+        // An h2-only server cannot answer the HTTP/1.1 probe.
+        #[cfg(feature = "http2")]
+        None if over_http2 => http2::probe_range_support(&url, &client)?,
+        // Many servers honor ranges without advertising them.
+        None => probe_range_support(&url, &client)?,
+    };
+    // A --range is downloaded like a resource of its own: the ranges, the
+    // hashing and the file all start at its first byte, which the requests
+    // are shifted by.
+    let total_size = match byte_range {
+        Some(_) if !ranges_supported => {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "Server does not support range requests, which --range needs"));
+        }
+        Some((first, _)) if first >= total_size => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("--range starts at byte {}, past the end of the {} bytes", first, total_size),
+            ));
+        }
+        Some((first, last)) => {
+            let last = last.map_or(total_size - 1, |last| last.min(total_size - 1));
+            client.range_offset = first;
+            info!("Downloading bytes {}-{}: {} bytes", first, last, last - first + 1);
+            last - first + 1
+        }
+        None => total_size,
+    };
+    // Without a thread count, connections are added while they help.
+    let auto_threads = ranges_supported && num_threads.is_none();
+    let num_threads = if ranges_supported { num_threads.unwrap_or(1) } else { 0 };
+    if auto_threads {
+        info!("Choosing the number of threads automatically, up to {}", max_threads);
+    } else if ranges_supported {
+        info!("Using {} threads", num_threads);
+    } else {
+        info!("Server does not support range requests, downloading in a single stream");
+    }
+    // Mirrors only take range requests, next to the primary.
+    let mut sources = vec![mirror::Source { url: url.clone(), client: client.clone() }];
+    if ranges_supported {
+        sources.extend(mirror::probe(&config.mirrors, &client, &info));
+    }
+    let sources = Arc::new(sources);
+
+    let encoding = if decompress { info.content_encoding.as_deref() } else { None };
+    if let Some(encoding) = encoding {
+        info!("Decoding Content-Encoding: {}", encoding);
+    }
+    // -O names the file after the probe, which may have been redirected.
+    let output = match output {
+        None if remote_name => Some(output::remote_name(info.content_disposition.as_deref(), &url)?),
+        output => output,
+    };
+    // The file only gets its name once it is complete and verified, so
+    // nothing ever sees a partial download under it.
+    let part = output.as_deref().map(output::part_path);
+    // Undecoded ranges can go straight to their offset in the output file;
+    // decoded bytes have offsets of their own, so those stay in memory and
+    // reach the file in order.
+    let in_place = part.as_deref().filter(|_| ranges_supported && encoding.is_none());
+    // A download of the whole resource in place with a validator can be
+    // resumed; the state does not record a --range.
+    let fresh = match (in_place, &info.validator) {
+        (Some(_), Some(validator)) if byte_range.is_none() => Some(resume::State::new(url.to_string(), validator.clone(), total_size, hashes[0])),
+        _ => None,
+    };
+    let saved = match (in_place, &fresh) {
+        (Some(path), Some(fresh)) if continue_download => resume::State::load(path, fresh)?,
+        _ => None,
+    };
+    if continue_download && fresh.is_none() {
+        info!("This download cannot be resumed, starting from the beginning");
+    }
+    if mmap && in_place.is_none() {
+        info!("--mmap only applies to undecoded range downloads, writing the file in order");
+    }
+    let written = saved.as_ref().map_or(&[][..], |saved| &saved.written);
+    let (chunks, mut inbox) = match in_place {
+        #[cfg(all(unix, feature = "mmap"))]
+        Some(path) if mmap => (store::Chunks::mapped(path, total_size, written)?, store::Inbox::default()),
+        Some(path) => (store::Chunks::file(path, total_size, written)?, store::Inbox::default()),
+        None => {
+            let (chunks, inbox) = store::Chunks::memory(max_buffer);
+            (chunks, inbox.spill_over(spill))
+        }
+    };
+    let chunks = Arc::new(chunks);
+    let mut sink = output::Sink::new(part.as_deref().filter(|_| in_place.is_none()))?.with_algorithms(hashes, config.hash_threads);
+    if stdout {
+        sink = sink.with_stdout();
+    }
+    if let Some(sender) = &config.stream {
+        sink = sink.with_stream(sender.clone());
+    }
+    if let Some(size) = piece_hashes {
+        sink = sink.with_piece_hashes(size);
+    }
+    let decoder = ContentDecoder::new(encoding, &mut sink)?;
+    // Digests the server sent describe the whole resource, not a slice.
+    let mut digests = if byte_range.is_none() { info.digests.clone() } else { Vec::new() };
+    let mut bytes_hashed = 0;
+    let piece_size = chunks.piece_size();
+
+    // What the main thread fetches itself, for --stats.
+    let mut main = stats::Connection::default();
+    let single_transport = Cancellable::new(&probe_client.connector, &config.cancel);
+    let mut single_stream = None;
+    if !ranges_supported {
+        if let Some(deadline) = &deadline {
+            deadline.check("the single stream")?;
+        }
+        config.cancel.check("the single stream")?;
+        let requested = Instant::now();
+        main.requests += 1;
+        main.connects += 1;
+        metrics::METRICS.requested(1);
+        metrics::METRICS.connected(1);
+        let response = open_stream(&url, &probe_client, &single_transport);
+        main.elapsed += requested.elapsed();
+        match response {
+            Ok((response, mut body)) => {
+                // A full response may carry the digests itself.
+                if digests.is_empty() {
+                    let headers = &response.headers;
+                    digests = integrity::expected_digests(&headers.get_all("repr-digest"), headers.get("content-md5"));
+                }
+                // Digests sent as trailers come after the body, too late to
+                // hash it with their algorithms as it arrives, so a body that
+                // announces trailers is read whole first.
+                if digests.is_empty() && response.header("trailer").is_some() {
+                    let requested = Instant::now();
+                    let mut data = Vec::new();
+                    let trailers = body.read_to_end(&mut data).and_then(|_| body.finish());
+                    main.elapsed += requested.elapsed();
+                    main.received += data.len();
+                    metrics::METRICS.received(data.len());
+                    info!("Single stream fetched {} bytes", data.len());
+                    config.callbacks.chunk_complete(0, data.len());
+                    config.callbacks.progress(data.len(), total_size);
+                    match trailers {
+                        Ok((trailers, _)) => {
+                            digests = integrity::expected_digests(&trailers.get_all("repr-digest"), trailers.get("content-md5"));
+                        }
+                        Err(e) => {
+                            main.failed += 1;
+                            metrics::METRICS.failed(&url);
+                            warn!("Single stream download failed: {}", e);
+                        }
+                    }
+                    chunks.insert(0, data)?;
+                } else {
+                    single_stream = Some(body);
+                }
+            }
+            Err(e) => {
+                main.failed += 1;
+                metrics::METRICS.failed(&url);
+                warn!("Single stream download failed: {}", e);
+            }
+        }
+    }
+    if want_repr_digest && byte_range.is_none() && !digests.iter().any(|digest| digest.source.starts_with("Repr-Digest")) {
+        warn!("Warning: the server sent no Repr-Digest to verify the download against");
+    }
+    let mut verifier = integrity::Verifier::new(digests, decoder);
+
+    // Otherwise the single stream is hashed a piece at a time as it arrives,
+    // so the body never has to fit in memory. Whatever it fails to deliver
+    // is fetched below like any other gap.
+    if let Some(mut body) = single_stream {
+        let requested = Instant::now();
+        while deadline.filter(|deadline| deadline.passed(Instant::now())).is_none() && !config.cancel.is_cancelled() {
+            let start = main.received;
+            let mut piece = pool::POOL.take();
+            match (&mut body).take(store::PIECE_SIZE as u64).read_to_end(&mut piece) {
+                Ok(0) => break,
+                Ok(len) => {
+                    main.received += len;
+                    metrics::METRICS.received(len);
+                    config.callbacks.chunk_complete(start, len);
+                    config.callbacks.progress(main.received, total_size);
+                    chunks.insert(start, piece)?;
+                    chunks.drain(&mut inbox, &mut verifier, &mut bytes_hashed, total_size)?;
+                }
+                // Stopped at the top of the loop below, with how far it got.
+                Err(e) if cancel::is_cancelled(&e) => break,
+                Err(e) => {
+                    main.failed += 1;
+                    metrics::METRICS.failed(&url);
+                    warn!("Single stream download failed: {}", e);
+                    break;
+                }
+            }
+        }
+        main.elapsed += requested.elapsed();
+        info!("Single stream fetched {} bytes", main.received);
+    }
+
+    // The bytes an interrupted run hashed are hashed and verified again, and
+    // must still be the same.
+    if let (Some(saved), store::Chunks::File(file)) = (&saved, chunks.as_ref()) {
+        let written: usize = saved.written.iter().map(|(start, end)| end - start).sum();
+        info!("Resuming with {} of {} bytes already downloaded", written, total_size);
+        file.drain(&mut verifier, &mut bytes_hashed, saved.hashed)?;
+        let (_, digest) = verifier.get_mut().get_mut().progress();
+        if bytes_hashed != saved.hashed || hash::hex(&digest) != saved.digest {
+            return Err(DownloadError::Integrity(format!(
+                "{} changed since the interrupted run; download again without --continue",
+                file.path().display()
+            ))
+            .into());
+        }
+    }
+
+    // Only what an interrupted run did not already write is fetched.
+    let missing = if total_size > 0 { chunks.missing(0, total_size - 1) } else { Vec::new() };
+    let queue = Arc::new(
+        queue::WorkQueue::new(&missing, if auto_threads { max_threads } else { num_threads }, chunk_size)
+            .with_max_attempts(max_attempts)
+            .with_callbacks(config.callbacks.clone())
+            .with_cancellation(config.cancel.clone()),
+    );
+    let already = total_size - missing.iter().map(|(start, end)| end + 1 - start).sum::<usize>();
+    let spawn_thread = |i: usize| {
+        let sources = Arc::clone(&sources);
+        let chunks = Arc::clone(&chunks);
+        let queue = Arc::clone(&queue);
+        let download = tracing::Span::current();
+        thread::spawn(move || {
+            let _thread = info_span!(parent: &download, "thread", id = i).entered();
+            let started = Instant::now();
+            metrics::METRICS.connection_opened();
+            // Threads take turns on the sources. One that finds a mirror
+            // serving another version drops it for the primary.
+            let mut source = i % sources.len();
+            let result = loop {
+                let mirror::Source { url, client } = &sources[source];
+                match fetch_ranges(i, url, client, http2, pipeline_depth, &queue, &chunks) {
+                    Err(e) if range::is_resource_changed(&e) && source != 0 => {
+                        warn!("dropping mirror {}: {}", url, e);
+                        source = 0;
+                    }
+                    result => break result,
+                }
+            };
+            queue.finished(i, started.elapsed());
+            metrics::METRICS.connection_closed();
+            result
+        })
+    };
+    let mut handles: Vec<_> = (0..num_threads).map(spawn_thread).collect();
+    let release = Release { queue: &queue, chunks: &chunks };
+    let mut ramp = auto_threads.then(|| ramp::Ramp::new(Instant::now(), max_threads));
+    let mut progress = status::showing_progress().then(|| progress::Progress::new(total_size, already, Instant::now()));
+
+    // Chunks that are in order are hashed while the threads run, as soon as
+    // they arrive, except that a mapping is hashed in one pass at the end, and
+    // the progress is saved regularly for --continue.
+    let mut state = fresh;
+    let mut checkpoint = Instant::now();
+    let mut reported = None;
+    while handles.iter().any(|handle| !handle.is_finished()) {
+        if let Some(ramp) = &mut ramp {
+            if ramp.grow(queue.received(), Instant::now()) {
+                handles.push(spawn_thread(ramp.threads() - 1));
+            }
+        }
+        // A thread that has stopped may have left the next range unfetched
+        // until the end, so the others must not wait for it.
+        if handles.iter().any(|handle| handle.is_finished()) {
+            chunks.release();
+        }
+        if !mmap {
+            chunks.drain(&mut inbox, &mut verifier, &mut bytes_hashed, total_size)?;
+        }
+        if let (Some(state), store::Chunks::File(file)) = (&mut state, chunks.as_ref()) {
+            if checkpoint.elapsed() >= resume::CHECKPOINT_INTERVAL {
+                state.checkpoint(file, verifier.get_mut().get_mut().progress())?;
+                checkpoint = Instant::now();
+            }
+        }
+        // Threads still waiting on a request are left to it, as the run ends.
+        let timed_out = deadline.filter(|deadline| deadline.passed(Instant::now()));
+        if timed_out.is_some() || config.cancel.is_cancelled() {
+            queue.stop();
+            status::clear_progress();
+            let stop = |reason| match timed_out {
+                Some(deadline) => deadline.exceeded(reason),
+                None => config.cancel.cancelled(reason),
+            };
+            let downloaded = already + queue.received();
+            return Err(interrupted(stop, state.as_mut(), &chunks, verifier.get_mut().get_mut().progress(), downloaded, total_size));
+        }
+        if let Some(line) = progress.as_mut().and_then(|progress| {
+            progress.update(queue.received(), &queue.received_by_thread(), Instant::now())
+        }) {
+            status::show_progress(&line);
+        }
+        let downloaded = already + queue.received() + main.received;
+        if reported != Some(downloaded) {
+            config.callbacks.progress(downloaded, total_size);
+            reported = Some(downloaded);
+        }
+        inbox.wait(resume::POLL_INTERVAL)?;
+    }
+    status::clear_progress();
+
+    let mut changed = None;
+    for handle in handles {
+        match handle.join() {
+            Ok(Ok(())) => debug!("Thread joined successfully"),
+            Ok(Err(e)) => changed = Some(e),
+            Err(e) => error!("Thread panicked: {:?}", e),
+        }
+    }
+    // Hashing bytes from two versions of the file would give a hash that
+    // matches neither, so stop instead.
+    if let Some(e) = changed {
+        return Err(e);
+    }
+
+    // What the threads gave up on is fetched here. A gap that still fails
+    // ends the download, unless --no-strict has it filled with zeros.
+    let transport = Cancellable::new(&client.connector, &config.cancel);
+    let mut keep_alive = KeepAlive::default();
+    let mut failures = (0, 0);
+    // The main thread's failed requests by start, for --stats and --json.
+    let mut main_failures: BTreeMap<usize, stats::RangeFailure> = BTreeMap::new();
+    let mut zero_filled = Vec::new();
+    while bytes_hashed < total_size {
+        chunks.drain(&mut inbox, &mut verifier, &mut bytes_hashed, total_size)?;
+
+        if bytes_hashed < total_size {
+            let timed_out = deadline.filter(|deadline| deadline.passed(Instant::now()));
+            if timed_out.is_some() || config.cancel.is_cancelled() {
+                let stop = |reason| match timed_out {
+                    Some(deadline) => deadline.exceeded(reason),
+                    None => config.cancel.cancelled(reason),
+                };
+                let downloaded = already + queue.received() + main.received;
+                let progress = verifier.get_mut().get_mut().progress();
+                return Err(interrupted(stop, state.as_mut(), &chunks, progress, downloaded, total_size));
+            }
+            let start = bytes_hashed;
+            let end = chunks.gap_end(&inbox, start, total_size).min(start.saturating_add(piece_size - 1));
+            info!("Main thread fetching missing range: bytes={}-{}", start, end);
+            let requested = Instant::now();
+            main.requests += 1;
+            metrics::METRICS.requested(1);
+            let response = download_chunk(&url, &client, &transport, &mut keep_alive, start, end + 1).and_then(non_empty);
+            main.elapsed += requested.elapsed();
+            let opened = keep_alive.take_opened();
+            main.connects += opened;
+            metrics::METRICS.connected(opened);
+            match response {
+                Ok(chunk) => {
+                    main.received += chunk.len();
+                    metrics::METRICS.received(chunk.len());
+                    config.callbacks.chunk_complete(start, chunk.len());
+                    config.callbacks.progress(already + queue.received() + main.received, total_size);
+                    chunks.insert(start, chunk)?
+                }
+                Err(e) if range::is_resource_changed(&e) => return Err(e),
+                // Stopped at the top of the loop, with how far it got.
+                Err(e) if cancel::is_cancelled(&e) => {}
+                Err(e) => {
+                    main.failed += 1;
+                    metrics::METRICS.failed(&url);
+                    failures = if failures.0 == start { (start, failures.1 + 1) } else { (start, 1) };
+                    main_failures.insert(start, stats::RangeFailure { start, end, attempts: failures.1, error: e.to_string() });
+                    if failures.1 < queue.max_attempts() && error::retryable(&e) {
+                        config.callbacks.retry((start, end), &e);
+                    } else if strict {
+                        return Err(error::context(e, &download::cannot_download(start, end, failures.1)));
+                    } else {
+                        warn!("Warning: filling bytes {}-{} with zeros after {} attempts: {}", start, end, failures.1, e);
+                        chunks.insert(start, vec![0; end - start + 1])?;
+                        zero_filled.push((start, end));
+                    }
+                }
+            }
+        }
+    }
+
+    verifier.finish()?.finish()?;
+    let written = sink.written();
+    let pieces = sink.take_pieces();
+    let mut digests = download::hex_digests(sink)?;
+    // The file is closed before it is renamed.
+    drop(release);
+    drop(chunks);
+    download::finish(config, &digests, part.as_deref(), output.as_deref(), written, bytes_hashed)?;
+    if !zero_filled.is_empty() {
+        let ranges: Vec<_> = zero_filled.iter().map(|(start, end)| format!("{}-{}", start, end)).collect();
+        warn!("Warning: the data and its hash include zeros for bytes {} that could not be downloaded", ranges.join(", "));
+    }
+    if stats || config.stats_json.is_some() || config.summary.is_some() {
+        let mut failures = queue.failures();
+        failures.extend(main_failures.into_values());
+        let summary = stats::Summary {
+            url: url.to_string(),
+            total_size,
+            connections: queue.connections(),
+            main,
+            elapsed: started.elapsed(),
+            digests: digests.clone(),
+            failures,
+            pieces: pieces.clone().unwrap_or_default(),
+        };
+        if stats {
+            for line in summary.lines() {
+                info!("{}", line);
+            }
+        }
+        if let Some(path) = &config.stats_json {
+            std::fs::write(path, summary.json() + "\n")?;
+            info!("Wrote {}", path.display());
+        }
+        if let Some(slot) = &config.summary {
+            *slot.lock().unwrap() = Some(summary);
+        }
+    }
+    // Named like the file, for checking it in its own directory.
+    if let (Some(style), Some(output)) = (write_checksum, &output) {
+        let name = output.file_name().unwrap_or_default().to_string_lossy();
+        for (algorithm, digest) in &digests {
+            let path = hash::checksum_path(output, *algorithm);
+            std::fs::write(&path, hash::checksum_line(*algorithm, digest, &name, style))?;
+            info!("Wrote {}", path.display());
+        }
+    }
+    // The builder only takes piece hashes with a file to put them next to.
+    if let (Some(size), Some(pieces), Some(output)) = (piece_hashes, pieces, &output) {
+        let path = pieces::manifest_path(output);
+        std::fs::write(&path, pieces::manifest(size, &pieces))?;
+        info!("Wrote {} piece hashes to {}", pieces.len(), path.display());
+    }
+
+    // A hash over zero-filled bytes is not the resource's, so it is not cached.
+    if bytes_hashed != total_size {
+        warn!("Warning: Hashed {} bytes, expected {}", bytes_hashed, total_size);
+    } else if let (Some(cache), Some(validator)) =
+        (cache.lock().unwrap().as_mut().filter(|_| sha256_only && zero_filled.is_empty()), info.validator)
+    {
+        cache.insert(cache::Entry { url: cache_key, decoded: decompress, validator, hash: digests[0].1.clone() });
+        cache.save()?;
+    }
+    config.callbacks.complete(&digests[0].1);
+    Ok(digests.swap_remove(0).1)
+
+}
+
+// --async: the same download on a tokio runtime of its own, one task per
+// connection.
+#[cfg(feature = "async")]
+fn download_async(url: &Url, client: &Client, config: &DownloadConfig) -> io::Result<String> {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    runtime.block_on(downloader::Downloader::new(url.clone(), client, config.clone()).run())
+}
+
+#[cfg(not(feature = "async"))]
+fn download_async(_url: &Url, _client: &Client, _config: &DownloadConfig) -> io::Result<String> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "--async is not compiled in; rebuild with the `async` feature"))
+}
+
+#[cfg(feature = "event-loop")]
+fn download_event_loop(url: &Url, client: &Client, config: &DownloadConfig) -> io::Result<String> {
+    event_loop::download(url, client, config)
+}
+
+#[cfg(not(feature = "event-loop"))]
+fn download_event_loop(_url: &Url, _client: &Client, _config: &DownloadConfig) -> io::Result<String> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "--event-loop is not compiled in; rebuild with the `event-loop` feature"))
+}
+
+// Starts downloading `url` on a thread of its own, on a tokio runtime with
+// `asynchronous`, and returns its data in order as it is hashed. Nothing is
+// cached, as the bytes are the point.
+pub fn download_stream(url: &Url, client: &Client, config: &DownloadConfig, asynchronous: bool) -> stream::ByteStream {
+    let (url, client, mut config) = (url.clone(), client.clone(), config.clone());
+    stream::ByteStream::spawn(move |sender| {
+        config.stream = Some(sender);
+        if asynchronous {
+            download_async(&url, &client, &config)
+        } else {
+            download(&url, &client, &config, &Mutex::new(None))
+        }
+    })
+}
+
+// The error a download stopped at --max-time or by cancellation ends with,
+// made by `stop` once how far it got is saved for --continue, given the
+// hashing progress.
+fn interrupted(
+    stop: impl FnOnce(String) -> io::Error,
+    state: Option<&mut resume::State>,
+    chunks: &store::Chunks,
+    progress: (u64, Vec<u8>),
+    downloaded: usize,
+    total_size: usize,
+) -> io::Error {
+    let reason = format!("{} of {} bytes downloaded", downloaded, total_size);
+    match (state, chunks) {
+        (Some(state), store::Chunks::File(file)) => match state.checkpoint(file, progress) {
+            Ok(()) => stop(format!("{}; resume with --continue", reason)),
+            Err(e) => e,
+        },
+        _ => stop(reason),
+    }
+}
+
+// Lets the threads go however the main thread leaves the download, an error
+// and a cancellation included: the queue hands out no more ranges, and no
+// thread is kept waiting for room under --max-buffer.
+struct Release<'a> {
+    queue: &'a queue::WorkQueue,
+    chunks: &'a store::Chunks,
+}
+
+impl Drop for Release<'_> {
+    fn drop(&mut self) {
+        self.queue.stop();
+        self.chunks.release();
+    }
+}
+
+// Downloads ranges from the queue from one server until it runs dry.
+#[cfg_attr(not(feature = "http2"), allow(unused_variables))]
+fn fetch_ranges(
+    i: usize,
+    url: &Url,
+    client: &Client,
+    http2: bool,
+    pipeline_depth: usize,
+    queue: &queue::WorkQueue,
+    chunks: &store::Chunks,
+) -> io::Result<()> {
+    let transport = Cancellable::new(&client.connector, queue.cancellation());
+    let mut keep_alive = KeepAlive::default();
+    // HTTP/2 and pipelining take ranges from the queue themselves, to
+    // keep their connection; if they give up, what they did not get
+    // to is left in the queue for the loop below.
+    #[cfg(feature = "http2")]
+    if http2 {
+        match http2::fetch_range(i, url, client, queue, chunks) {
+            Ok(()) => return Ok(()),
+            Err(e) if range::is_resource_changed(&e) => return Err(e),
+            Err(e) => warn!("falling back to HTTP/1.1: {}", e),
+        }
+    }
+    if pipeline_depth > 1 {
+        match pipeline::fetch_range(i, url, client, pipeline_depth, queue, chunks) {
+            Ok(()) => return Ok(()),
+            Err(e) if range::is_resource_changed(&e) => return Err(e),
+            Err(e) => warn!("falling back to one request at a time: {}", e),
+        }
+    }
+    while let Some((start, range_end)) = queue.next() {
+        let _range = tracing::debug_span!("range", start, end = range_end).entered();
+        let mut current_start = start;
+        while current_start <= range_end {
+            debug!("requesting bytes={}-{}", current_start, range_end);
+            queue.requested(i, 1);
+            let response = download_chunk(url, client, &transport, &mut keep_alive, current_start, range_end + 1).and_then(non_empty);
+            queue.connected(i, keep_alive.take_opened());
+            match response {
+                Ok(chunk) => {
+                    let chunk_size = chunk.len();
+                    queue.record(i, current_start, chunk_size);
+                    chunks.insert(current_start, chunk)?;
+                    current_start += chunk_size;
+                }
+                Err(e) if range::is_resource_changed(&e) => {
+                    queue.give_back(std::iter::once((current_start, range_end)));
+                    return Err(e);
+                }
+                // The download is ending; what is left stays for --continue.
+                Err(e) if cancel::is_cancelled(&e) => return Ok(()),
+                // Asking again for what the server refused or sent corrupt
+                // would only fail the same way.
+                Err(e) if !error::retryable(&e) => {
+                    queue.failed(i);
+                    metrics::METRICS.failed(url);
+                    warn!("giving up on {}-{}: {}", current_start, range_end, e);
+                    break;
+                }
+                // What is left of the range goes back to the queue for a
+                // retry, rather than being skipped; the retry callback
+                // reports it.
+                Err(e) if queue.retry((current_start, range_end), &e) => {
+                    queue.failed(i);
+                    metrics::METRICS.failed(url);
+                    break;
+                }
+                Err(e) => {
+                    queue.failed(i);
+                    metrics::METRICS.failed(url);
+                    warn!(
+                        "giving up on {}-{} after {} attempts: {}",
+                        current_start,
+                        range_end,
+                        queue.max_attempts(),
+                        e
+                    );
+                    break;
+                }
+            }
+        }
+        if current_start > range_end {
+            debug!("completed");
+        }
+    }
+    Ok(())
+}
+
+// What the size probe learned about the resource.
+pub struct ResourceInfo {
+    pub total_size: usize,
+    pub content_encoding: Option<String>,
+    pub accept_ranges: Option<String>,
+    // Strong ETag, else Last-Modified: sent as If-Range on every range request.
+    pub validator: Option<String>,
+    // Content-MD5 and Repr-Digest, checked against the received bytes.
+    pub digests: Vec<integrity::ExpectedDigest>,
+    // Suggests the file name for -O.
+    pub content_disposition: Option<String>,
+}
+
+// Probes the resource, following redirects. Returns the final URL, which is
+// the one the range requests must go to.
+// HEAD is tried first so the body is never transferred; servers that reject
+// it (405, 501) or omit Content-Length get a GET whose body is not read.
+pub fn get_resource_info(url: &Url, client: &Client) -> io::Result<(Url, ResourceInfo)> {
+    let head = send_request(url, client, &client.connector, None, "HEAD", None, false)
+        .and_then(|(url, response)| Ok((url, parse_resource_info(&response)?)));
+    match head {
+        Ok(probed) => Ok(probed),
+        Err(e) => {
+            warn!("HEAD probe failed, retrying with GET: {}", e);
+            let (url, response) = send_request(url, client, &client.connector, None, "GET", None, false)?;
+            Ok((url, parse_resource_info(&response)?))
+        }
+    }
+}
+
+pub fn parse_resource_info(response: &Response) -> io::Result<ResourceInfo> {
+    let headers = &response.headers;
+    let total_size = headers
+        .content_length()?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Content-Length not found"))?;
+    Ok(ResourceInfo {
+        total_size,
+        content_encoding: headers.get("content-encoding").map(str::to_string),
+        accept_ranges: headers.get("accept-ranges").map(str::to_string),
+        validator: range::if_range_validator(headers.get("etag"), headers.get("last-modified")),
+        digests: integrity::expected_digests(&headers.get_all("repr-digest"), headers.get("content-md5")),
+        content_disposition: headers.get("content-disposition").map(str::to_string),
+    })
+}
+
+pub fn process_chunks<W: Write>(
+    output: &mut W,
+    bytes_hashed: &mut usize,
+    chunk_buffer: &mut BTreeMap<usize, Vec<u8>>,
+    total_size: usize,
+) -> io::Result<()> {
+    // Each chunk is taken out of the map as it is written, not copied.
+    while let Some(entry) = chunk_buffer.first_entry() {
+        let start = *entry.key();
+        if start < *bytes_hashed {
+            pool::POOL.give(entry.remove()); // Overlap, discard
+        } else if start == *bytes_hashed {
+            if *bytes_hashed + entry.get().len() > total_size {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Chunk exceeds total size"
+                ));
+            }
+            let chunk = entry.remove();
+            output.write_all(&chunk)?;
+            *bytes_hashed += chunk.len();
+            trace!("Hashed chunk starting at {}, size {}, now at {}", start, chunk.len(), *bytes_hashed);
+            pool::POOL.give(chunk);
+        } else {
+            break; // Gap, wait for missing chunk
+        }
+    }
+
+    if *bytes_hashed > total_size {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Hashed bytes exceed total size"));
+    }
+
+    Ok(())
+}
+
+// Reads of a response head are kept small: what they take past its end is
+// copied out again as the start of the body.
+const HEAD_READ_SIZE: usize = 4096;
+
+// Reads up to the end of the response headers. Returns the headers and any
+// body bytes that arrived in the same reads.
+pub fn read_head<R: Read>(stream: &mut R) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let mut buffer = Vec::new();
+    let header_end = loop {
+        // Only the bytes just read, and the three before them, can complete
+        // the blank line.
+        let len = buffer.len();
+        let searched = len.saturating_sub(3);
+        buffer.resize(len + HEAD_READ_SIZE, 0);
+        let bytes_read = stream.read(&mut buffer[len..]);
+        buffer.truncate(len + *bytes_read.as_ref().unwrap_or(&0));
+        if bytes_read? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed prematurely"));
+        }
+        if let Some(pos) = buffer[searched..].windows(4).position(|w| w == b"\r\n\r\n") {
+            break searched + pos + 4;
+        }
+    };
+
+    let body = buffer.split_off(header_end);
+    Ok((buffer, body))
+}
+
+// Reads `size` bytes from `stream` straight onto the end of `buffer`, or
+// what is left of it, returning how many; 0 at end of stream.
+fn read_into_tail<R: Read>(stream: &mut R, buffer: &mut Vec<u8>, size: usize) -> io::Result<usize> {
+    buffer.reserve(size);
+    stream.take(size as u64).read_to_end(buffer)
+}
+
+pub fn read_response<C: Connection>(stream: &mut C, read_size: usize) -> io::Result<Response> {
+    read_message(stream, read_size).map(|(response, _)| response)
+}
+
+// Reads one response and also returns the bytes received past its end. On a
+// pipelined connection those belong to the next response. Trailer fields of a
+// chunked body are appended to the headers, so lookups see them too. The body
+// is read `read_size` bytes at a time.
+pub fn read_message<R: Read>(stream: &mut R, read_size: usize) -> io::Result<(Response, Vec<u8>)> {
+    let (head, body) = read_head(stream)?;
+    let mut response = Response::parse(&head, body)?;
+    let mut body = response.body_reader(&mut *stream)?;
+    let mut data = pool::POOL.take();
+    while read_into_tail(&mut body, &mut data, read_size)? > 0 {}
+    let (trailers, excess) = body.finish()?;
+    response.body = data;
+    for (name, value) in trailers.iter() {
+        response.headers.append(name, value);
+    }
+    Ok((response, excess))
+}
+
+// Fetches bytes start..end over connections from `transport`, the client's
+// Connector outside the tests.
+pub fn download_chunk<T: Transport>(
+    url: &Url,
+    client: &Client,
+    transport: &T,
+    keep_alive: &mut KeepAlive<T::Connection>,
+    start: usize,
+    end: usize,
+) -> io::Result<Vec<u8>> {
+    let mut attempt = 0;
+    loop {
+        let (_, response) =
+            send_request(url, client, transport, Some(&mut *keep_alive), "GET", Some((start, end)), true)?;
+        match range_body(client, response, start, end) {
+            Err(e)
+                if matches!(DownloadError::of(&e), Some(DownloadError::RangeMismatch(_) | DownloadError::Parse(_)))
+                    && attempt < range::MISMATCH_RETRIES =>
+            {
+                attempt += 1;
+                warn!("Re-requesting {}-{} after bad range response: {}", start, end, e);
+            }
+            result => return result,
+        }
+    }
+}
+
+// An empty answer to a range request moves nothing forward, so it counts as
+// a failure like any other.
+pub fn non_empty(chunk: Vec<u8>) -> io::Result<Vec<u8>> {
+    if chunk.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Empty response to a range request"));
+    }
+    Ok(chunk)
+}
+
+// Checks the response to a `Range: bytes=start-end` request and returns the
+// bytes that belong at `start`.
+pub fn range_body(client: &Client, response: Response, start: usize, end: usize) -> io::Result<Vec<u8>> {
+    let Response { status, headers, body, .. } = response;
+    range::check_unchanged(client.if_range.as_deref(), status, headers.get("etag"), headers.get("last-modified"))?;
+    let (start, end) = (start + client.range_offset, end + client.range_offset);
+    range::validate_range(status, headers.get("content-type"), headers.get("content-range"), start, end, body)
+}
+
+// Repeats the size probe as a conditional request with the cached validator;
+// true when the server answers 304 Not Modified.
+pub fn probe_not_modified(url: &Url, client: &Client, validator: &str) -> io::Result<bool> {
+    let (name, value) = cache::conditional_header(validator);
+    let client = client.clone().header(name, value);
+    let (_, response) = match send_request(url, &client, &client.connector, None, "HEAD", None, false) {
+        Ok(response) => response,
+        Err(e) => {
+            warn!("HEAD probe failed, retrying with GET: {}", e);
+            send_request(url, &client, &client.connector, None, "GET", None, false)?
+        }
+    };
+    Ok(response.status == 304)
+}
+
+// Asks for the first two bytes and reads only the response head: a 206 means
+// ranges work, a 200 means the server ignored the Range header. The URL must
+// already be the final one after redirects.
+pub fn probe_range_support(url: &Url, client: &Client) -> io::Result<bool> {
+    let mut stream = client.connector.connect(url)?;
+    stream.write_all(format_request("GET", url, client, Some((0, 1)), false).as_bytes())?;
+    let (head, _) = read_head(&mut stream)?;
+    // The body is not wanted; the server may stop sending it.
+    let _ = stream.shutdown();
+    let response = Response::parse(&head, Vec::new())?;
+    client.handle_response(url, &response);
+    Ok(response.status == 206)
+}
+
+// Sends a GET for the whole resource over a connection from `transport` and
+// reads only the head, leaving the body to be read as it arrives. The URL
+// must already be the final one after redirects.
+pub fn open_stream<T: Transport>(url: &Url, client: &Client, transport: &T) -> io::Result<(Response, BodyReader<T::Connection>)> {
+    let mut stream = transport.connect(url)?;
+    stream.write_all(format_request("GET", url, client, None, false).as_bytes())?;
+    let (head, body) = read_head(&mut stream)?;
+    let mut response = Response::parse(&head, body)?;
+    client.handle_response(url, &response);
+    let status = response.status_line();
+    if !status.is_success() {
+        return Err(status.error(url).into());
+    }
+    let body = response.body_reader(stream)?;
+    Ok((response, body))
+}
+
+// Sends a GET (optionally with a Range) and follows up to client.max_redirects
+// redirects. Returns the URL that finally answered with its response; any
+// status other than 2xx after redirects is an error.
+pub fn fetch(url: &Url, client: &Client, range: Option<(usize, usize)>) -> io::Result<(Url, Response)> {
+    send_request(url, client, &client.connector, None, "GET", range, true)
+}
+
+// The redirect loop behind fetch and the size probe. With `read_body` false
+// only the response head is read and the connection is dropped, which is
+// required for HEAD (its Content-Length describes a body that never comes).
+// Requests that read the body may go over a kept-alive connection instead of
+// a fresh one per request.
+fn send_request<T: Transport>(
+    url: &Url,
+    client: &Client,
+    transport: &T,
+    mut keep_alive: Option<&mut KeepAlive<T::Connection>>,
+    method: &str,
+    range: Option<(usize, usize)>,
+    read_body: bool,
+) -> io::Result<(Url, Response)> {
+    let mut url = url.clone();
+    let mut redirects = 0;
+    let mut authenticated = false;
+    let mut waits = 0;
+    loop {
+        let response = match keep_alive.as_deref_mut() {
+            Some(keep_alive) if read_body => exchange_keep_alive(keep_alive, transport, client, &url, method, range)?,
+            _ => {
+                let mut stream = transport.connect(&url)?;
+                stream.write_all(format_request(method, &url, client, range, false).as_bytes())?;
+                if read_body {
+                    read_response(&mut stream, client.read_size)?
+                } else {
+                    let (head, _) = read_head(&mut stream)?;
+                    let _ = stream.shutdown();
+                    Response::parse(&head, Vec::new())?
+                }
+            }
+        };
+        client.handle_response(&url, &response);
+        let status = response.status_line();
+        // 304 only answers the conditional probe, which asked for it.
+        if status.is_success() || status.code == 304 {
+            return Ok((url, response));
+        }
+        // One retry per URL answers the challenge; a second 401 means the
+        // credentials were rejected.
+        if status.code == 401
+            && !authenticated
+            && client.auth.challenge(&url, &response.headers.get_all("www-authenticate"))
+        {
+            authenticated = true;
+            continue;
+        }
+        // Rate limiting and maintenance: wait as long as the server asks.
+        if matches!(status.code, 429 | 503) && waits < retry::MAX_WAITS {
+            if let Some(delay) = retry::delay(&response.headers).filter(|&delay| delay <= retry::MAX_DELAY) {
+                waits += 1;
+                warn!("{} {} from {}, retrying in {:.1}s", status.code, status.reason, url, delay.as_secs_f64());
+                thread::sleep(delay);
+                continue;
+            }
+        }
+        if !status.is_redirect() {
+            // Error pages must never be mistaken for resource data.
+            return Err(status.error(&url).into());
+        }
+        let location = response
+            .header("location")
+            .ok_or_else(|| DownloadError::Parse(format!("Redirect {} without a Location header", status.code)))?;
+        if redirects == client.max_redirects {
+            return Err(io::Error::other(format!("Too many redirects (limit {})", client.max_redirects)));
+        }
+        redirects += 1;
+        authenticated = false;
+        url = url.join(location)?;
+        info!("Redirected ({}) to {}", status.code, url);
+    }
+}
+
+// Sends one request over the worker's kept-alive connection. A reused socket
+// may have been closed by the server in the meantime, so a failure on one is
+// retried once on a fresh connection.
+fn exchange_keep_alive<T: Transport>(
+    keep_alive: &mut KeepAlive<T::Connection>,
+    transport: &T,
+    client: &Client,
+    url: &Url,
+    method: &str,
+    range: Option<(usize, usize)>,
+) -> io::Result<Response> {
+    loop {
+        let (mut stream, reused) = keep_alive.take(transport, url)?;
+        let response = stream
+            .write_all(format_request(method, url, client, range, true).as_bytes())
+            .and_then(|()| read_response(&mut stream, client.read_size));
+        match response {
+            Ok(response) => {
+                if can_reuse(&response) {
+                    keep_alive.put_back(url, stream);
+                }
+                return Ok(response);
+            }
+            Err(e) if reused => debug!("Kept-alive connection to {} failed, reconnecting: {}", url.origin(), e),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// A connection can carry the next request only if the server did not ask to
+// close it and the body's end was marked by its framing rather than by the
+// connection closing.
+pub fn can_reuse(response: &Response) -> bool {
+    let headers = &response.headers;
+    let http11 = response.version == "HTTP/1.1";
+    let close = headers
+        .get_joined("connection")
+        .is_some_and(|value| value.split(',').any(|token| token.trim().eq_ignore_ascii_case("close")));
+    let complete = headers.content_length().ok().flatten() == Some(response.body.len());
+    http11 && !close && (headers.is_chunked() || complete)
+}
+
+pub fn format_request(
+    method: &str,
+    url: &Url,
+    client: &Client,
+    range: Option<(usize, usize)>,
+    keep_alive: bool,
+) -> String {
+    build_request(method, url, client, range, keep_alive).build()
+}
+
+// The head every HTTP/1.1 request to `url` shares: the range, credentials,
+// extra headers and cookies, and whether the connection is kept open.
+// Uploads add their body's framing before building it.
+pub fn build_request(
+    method: &str,
+    url: &Url,
+    client: &Client,
+    range: Option<(usize, usize)>,
+    keep_alive: bool,
+) -> RequestBuilder {
+    let proxy = client.connector.forward_proxy(url);
+    let target = if proxy.is_some() { url.to_string() } else { url.request_target() };
+    let mut request = RequestBuilder::new(method, &target).host(url);
+    if let Some((start, end)) = range {
+        let (start, end) = (start + client.range_offset, end + client.range_offset);
+        request = request.header("Range", &format!("bytes={}-{}", start, end));
+        if let Some(validator) = &client.if_range {
+            request = request.header("If-Range", validator);
+        }
+    }
+    if let Some(value) = client.auth.authorization(method, url) {
+        request = request.header("Authorization", &value);
+    }
+    if let Some(value) = proxy.and_then(|proxy| proxy.authorization()) {
+        request = request.header("Proxy-Authorization", value);
+    }
+    request
+        .headers(client.header_fields(method, url))
+        .header("Connection", if keep_alive { "keep-alive" } else { "close" })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusLine {
+    pub version: String,
+    pub code: u16,
+    pub reason: String,
+}
+
+impl StatusLine {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.code)
+    }
+
+    pub fn is_redirect(&self) -> bool {
+        matches!(self.code, 301 | 302 | 303 | 307 | 308)
+    }
+
+    // What a response with this status fails with when data was wanted.
+    pub fn error(&self, url: &Url) -> DownloadError {
+        DownloadError::HttpStatus {
+            code: self.code,
+            message: format!("HTTP error {} {} for {}", self.code, self.reason, url),
+        }
+    }
+}
+
+// Parses "HTTP/1.1 206 Partial Content" from the first line of the headers.
+pub fn parse_status_line(headers: &[u8]) -> io::Result<StatusLine> {
+    let malformed = || io::Error::from(DownloadError::Parse("Malformed status line".to_string()));
+    let line = headers.split(|&b| b == b'\n').next().unwrap_or_default();
+    let line = std::str::from_utf8(line).map_err(|_| malformed())?.trim_end();
+
+    let mut parts = line.splitn(3, ' ');
+    let version = parts.next().filter(|v| v.starts_with("HTTP/")).ok_or_else(malformed)?;
+    let code = parts
+        .next()
+        .filter(|code| code.len() == 3)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(malformed)?;
+    let reason = parts.next().unwrap_or("");
+    Ok(StatusLine { version: version.to_string(), code, reason: reason.to_string() })
+}
@@ -0,0 +1,188 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Metadata, Subscriber};
+
+use crate::status;
+
+// Collects the events that pass the level chosen with -q/-v/-vv and writes
+// them one per line, after the spans they happened in, e.g.
+// "thread{id=2}:range{start=0 end=1048575}: requesting bytes=0-1048575".
+pub struct Logger {
+    max: LevelFilter,
+    spans: Mutex<HashMap<u64, SpanData>>,
+    next_id: AtomicU64,
+    // Where the lines go: the status output, or a test's buffer.
+    write: fn(Level, &str),
+}
+
+struct SpanData {
+    name: &'static str,
+    fields: String,
+    parent: Option<u64>,
+    // Handles to the span still alive; it is forgotten at zero.
+    refs: usize,
+}
+
+thread_local! {
+    // The spans this thread is in, innermost last.
+    static ENTERED: RefCell<Vec<u64>> = const { RefCell::new(Vec::new()) };
+}
+
+// Sends the events of every thread to the status output.
+pub fn init(max: LevelFilter) {
+    let _ = tracing::subscriber::set_global_default(Logger::new(max, status::log));
+}
+
+impl Logger {
+    pub fn new(max: LevelFilter, write: fn(Level, &str)) -> Logger {
+        Logger { max, spans: Mutex::new(HashMap::new()), next_id: AtomicU64::new(1), write }
+    }
+
+    // "outer{a=1}:inner{b=2}" for the span and the spans around it.
+    fn context(&self, span: Option<u64>) -> String {
+        let spans = self.spans.lock().unwrap();
+        let mut names = Vec::new();
+        let mut next = span;
+        while let Some(data) = next.and_then(|id| spans.get(&id)) {
+            names.push(if data.fields.is_empty() {
+                data.name.to_string()
+            } else {
+                format!("{}{{{}}}", data.name, data.fields)
+            });
+            next = data.parent;
+        }
+        names.reverse();
+        names.join(":")
+    }
+}
+
+fn current() -> Option<u64> {
+    ENTERED.with(|entered| entered.borrow().last().copied())
+}
+
+impl Subscriber for Logger {
+    // The thread and download spans are kept with -q, so that warnings
+    // still say where they come from.
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.max >= *metadata.level() || (metadata.is_span() && *metadata.level() <= Level::INFO)
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(self.max.max(LevelFilter::INFO))
+    }
+
+    fn new_span(&self, attributes: &Attributes<'_>) -> Id {
+        let parent = if attributes.is_contextual() {
+            current()
+        } else {
+            attributes.parent().map(Id::into_u64)
+        };
+        let mut fields = Fields::default();
+        attributes.record(&mut fields);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let data = SpanData { name: attributes.metadata().name(), fields: fields.text, parent, refs: 1 };
+        self.spans.lock().unwrap().insert(id, data);
+        Id::from_u64(id)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        if let Some(data) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            let mut fields = Fields { text: std::mem::take(&mut data.fields), message: None };
+            values.record(&mut fields);
+            data.fields = fields.text;
+        }
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let span = if event.is_contextual() { current() } else { event.parent().map(Id::into_u64) };
+        let level = *event.metadata().level();
+        (self.write)(level, &line(level, self.max, &self.context(span), fields));
+    }
+
+    fn enter(&self, span: &Id) {
+        ENTERED.with(|entered| entered.borrow_mut().push(span.into_u64()));
+    }
+
+    fn exit(&self, span: &Id) {
+        ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            if let Some(position) = entered.iter().rposition(|&id| id == span.into_u64()) {
+                entered.remove(position);
+            }
+        });
+    }
+
+    fn clone_span(&self, span: &Id) -> Id {
+        if let Some(data) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            data.refs += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: Id) -> bool {
+        let mut spans = self.spans.lock().unwrap();
+        match spans.get_mut(&span.into_u64()) {
+            Some(data) if data.refs > 1 => {
+                data.refs -= 1;
+                false
+            }
+            Some(_) => {
+                spans.remove(&span.into_u64());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// The level is only named when -v asks for more than the usual messages, so
+// that those read as before.
+fn line(level: Level, max: LevelFilter, context: &str, fields: Fields) -> String {
+    let mut line = String::new();
+    if max > LevelFilter::INFO {
+        let _ = write!(line, "{:5} ", level);
+    }
+    if !context.is_empty() {
+        let _ = write!(line, "{}: ", context);
+    }
+    line.push_str(&fields.message.unwrap_or_default());
+    if !fields.text.is_empty() {
+        let _ = write!(line, " {}", fields.text);
+    }
+    line
+}
+
+// The message of an event, and its other fields as "name=value".
+#[derive(Default)]
+struct Fields {
+    text: String,
+    message: Option<String>,
+}
+
+impl Visit for Fields {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.message = Some(format!("{:?}", value));
+        } else {
+            if !self.text.is_empty() {
+                self.text.push(' ');
+            }
+            let _ = write!(self.text, "{}={:?}", field.name(), value);
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record_debug(field, &format_args!("{}", value));
+    }
+}
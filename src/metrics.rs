@@ -0,0 +1,165 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use crate::read_head;
+use crate::url::Url;
+
+// How long a scrape may take to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Counters and gauges for --metrics-addr, kept for the whole run whether or
+// not anything scrapes them.
+pub struct Metrics {
+    bytes: AtomicUsize,
+    requests: AtomicUsize,
+    // Connections opened for those, fewer when kept alive.
+    connects: AtomicUsize,
+    retries: AtomicUsize,
+    // Worker threads running, each holding a connection.
+    connections: AtomicUsize,
+    downloads_active: AtomicUsize,
+    downloads_succeeded: AtomicUsize,
+    downloads_failed: AtomicUsize,
+    // Failed requests by host.
+    errors: Mutex<BTreeMap<String, usize>>,
+}
+
+pub static METRICS: Metrics = Metrics::new();
+
+impl Metrics {
+    pub const fn new() -> Metrics {
+        Metrics {
+            bytes: AtomicUsize::new(0),
+            requests: AtomicUsize::new(0),
+            connects: AtomicUsize::new(0),
+            retries: AtomicUsize::new(0),
+            connections: AtomicUsize::new(0),
+            downloads_active: AtomicUsize::new(0),
+            downloads_succeeded: AtomicUsize::new(0),
+            downloads_failed: AtomicUsize::new(0),
+            errors: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn received(&self, bytes: usize) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn requested(&self, requests: usize) {
+        self.requests.fetch_add(requests, Ordering::Relaxed);
+    }
+
+    pub fn connected(&self, connections: usize) {
+        self.connects.fetch_add(connections, Ordering::Relaxed);
+    }
+
+    // A request to `url` that failed and is retried or given up on.
+    pub fn failed(&self, url: &Url) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+        *self.errors.lock().unwrap().entry(url.host.clone()).or_insert(0) += 1;
+    }
+
+    pub fn connection_opened(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn download_started(&self) {
+        self.downloads_active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn download_finished<T>(&self, result: &io::Result<T>) {
+        self.downloads_active.fetch_sub(1, Ordering::Relaxed);
+        let finished = if result.is_ok() { &self.downloads_succeeded } else { &self.downloads_failed };
+        finished.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // The Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, usize)]| {
+            let _ = writeln!(text, "# HELP http_client_{} {}", name, help);
+            let _ = writeln!(text, "# TYPE http_client_{} {}", name, kind);
+            for (labels, value) in samples {
+                let _ = writeln!(text, "http_client_{}{} {}", name, labels, value);
+            }
+        };
+        let value = |counter: &AtomicUsize| vec![(String::new(), counter.load(Ordering::Relaxed))];
+        metric("bytes_downloaded_total", "counter", "Bytes received from servers.", &value(&self.bytes));
+        metric("requests_total", "counter", "Range and single-stream requests sent.", &value(&self.requests));
+        metric("connects_total", "counter", "Connections opened for those requests.", &value(&self.connects));
+        metric("retries_total", "counter", "Requests that failed and were retried or given up on.", &value(&self.retries));
+        metric("active_connections", "gauge", "Worker threads downloading ranges.", &value(&self.connections));
+        metric("downloads_active", "gauge", "Downloads in progress.", &value(&self.downloads_active));
+        metric(
+            "downloads_total",
+            "counter",
+            "Downloads finished, by result.",
+            &[
+                ("{result=\"succeeded\"}".to_string(), self.downloads_succeeded.load(Ordering::Relaxed)),
+                ("{result=\"failed\"}".to_string(), self.downloads_failed.load(Ordering::Relaxed)),
+            ],
+        );
+        let errors: Vec<_> = self
+            .errors
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(host, count)| (format!("{{host=\"{}\"}}", label_value(host)), *count))
+            .collect();
+        metric("errors_total", "counter", "Failed requests, by host.", &errors);
+        text
+    }
+}
+
+fn label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+// Serves `metrics` at /metrics on `addr` from a background thread for as long
+// as the run lasts. Returns the address bound, which tells the port when 0
+// was asked for.
+pub fn serve(addr: &str, metrics: &'static Metrics) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)
+        .map_err(|e| io::Error::new(e.kind(), format!("Cannot listen on {} for metrics: {}", addr, e)))?;
+    let local = listener.local_addr()?;
+    info!("Serving metrics at http://{}/metrics", local);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            if let Err(e) = stream.and_then(|stream| answer(stream, metrics)) {
+                warn!("Metrics request failed: {}", e);
+            }
+        }
+    });
+    Ok(local)
+}
+
+fn answer(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let (headers, _) = read_head(&mut stream)?;
+    let request_line = String::from_utf8_lossy(&headers).lines().next().unwrap_or_default().to_string();
+    let mut parts = request_line.split(' ');
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        (Some("GET"), _) => ("404 Not Found", "Not found; metrics are at /metrics\n".to_string()),
+        _ => ("405 Method Not Allowed", "Only GET is supported\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes())
+}
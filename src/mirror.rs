@@ -0,0 +1,68 @@
+use std::io;
+
+use tracing::{info, warn};
+
+use crate::client::Client;
+use crate::url::Url;
+use crate::{get_resource_info, probe_range_support, ResourceInfo};
+
+// A server to request ranges from: the URL given first, or a mirror of it.
+pub struct Source {
+    pub url: Url,
+    // Carries this server's own validator in If-Range.
+    pub client: Client,
+}
+
+// Why a mirror's probe shows it does not hold the same file as the primary,
+// if it does not.
+pub fn mismatch(primary: &ResourceInfo, mirror: &ResourceInfo) -> Option<String> {
+    if mirror.total_size != primary.total_size {
+        return Some(format!("size {} differs from {}", mirror.total_size, primary.total_size));
+    }
+    if mirror.content_encoding != primary.content_encoding {
+        return Some(format!(
+            "Content-Encoding {} differs from {}",
+            mirror.content_encoding.as_deref().unwrap_or("none"),
+            primary.content_encoding.as_deref().unwrap_or("none")
+        ));
+    }
+    // ETags are compared when both servers send one; modification dates
+    // commonly differ between copies of the same file.
+    match (&primary.validator, &mirror.validator) {
+        (Some(primary), Some(mirror)) if primary.starts_with('"') && mirror.starts_with('"') && primary != mirror => {
+            Some(format!("ETag {} differs from {}", mirror, primary))
+        }
+        _ => None,
+    }
+}
+
+// Probes each mirror and keeps those that agree with the primary's probe and
+// serve ranges.
+pub fn probe(mirrors: &[Url], client: &Client, primary: &ResourceInfo) -> Vec<Source> {
+    let mut client = client.clone();
+    client.if_range = None;
+    let mut sources = Vec::new();
+    for mirror in mirrors {
+        let result = get_resource_info(mirror, &client).and_then(|(url, info)| {
+            let ranges_supported = match info.accept_ranges.as_deref() {
+                Some(units) => !units.eq_ignore_ascii_case("none"),
+                None => probe_range_support(&url, &client)?,
+            };
+            let reason = mismatch(primary, &info).or_else(|| (!ranges_supported).then(|| "no range support".to_string()));
+            if let Some(reason) = reason {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, reason));
+            }
+            let mut client = client.clone();
+            client.if_range = info.validator;
+            Ok(Source { url, client })
+        });
+        match result {
+            Ok(source) => {
+                info!("Using mirror {}", source.url);
+                sources.push(source);
+            }
+            Err(e) => warn!("Ignoring mirror {}: {}", mirror, e),
+        }
+    }
+    sources
+}
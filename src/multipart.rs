@@ -0,0 +1,95 @@
+use std::io;
+
+use crate::error::DownloadError;
+use crate::headers::HeaderMap;
+use crate::range::{parse_content_range, ContentRange};
+
+// One body part of a multipart/byteranges response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Part {
+    pub range: ContentRange,
+    pub data: Vec<u8>,
+}
+
+// Returns the boundary of a `multipart/byteranges` Content-Type, or None for
+// any other media type.
+pub fn boundary(content_type: &str) -> Option<String> {
+    let mut params = content_type.split(';');
+    if !params.next()?.trim().eq_ignore_ascii_case("multipart/byteranges") {
+        return None;
+    }
+    params.find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim().eq_ignore_ascii_case("boundary").then(|| value.trim().trim_matches('"').to_string())
+    })
+}
+
+// Splits a multipart/byteranges body into its parts (RFC 9110 14.6). A body
+// cut short by the connection yields the parts received so far, the last one
+// possibly with less data than its Content-Range announces.
+pub fn parse(body: &[u8], boundary: &str) -> io::Result<Vec<Part>> {
+    let delimiter = format!("\r\n--{}", boundary);
+    let delimiter = delimiter.as_bytes();
+    // The first delimiter may open the body without a preceding CRLF.
+    let mut pos = if body.starts_with(&delimiter[2..]) {
+        delimiter.len() - 2
+    } else {
+        find(body, delimiter).ok_or_else(|| invalid("multipart body has no boundary delimiter"))? + delimiter.len()
+    };
+
+    let mut parts = Vec::new();
+    loop {
+        if body[pos..].starts_with(b"--") {
+            return Ok(parts);
+        }
+        // Skip transport padding after the delimiter.
+        let line_end = match find(&body[pos..], b"\r\n") {
+            Some(line_end) => line_end,
+            None => return Ok(parts),
+        };
+        pos += line_end + 2;
+
+        let head_len = if body[pos..].starts_with(b"\r\n") {
+            0
+        } else {
+            match find(&body[pos..], b"\r\n\r\n") {
+                Some(head_len) => head_len + 2,
+                None => return Ok(parts),
+            }
+        };
+        let range = part_content_range(&body[pos..pos + head_len])?;
+        pos += head_len + 2;
+
+        let (data, next) = match find(&body[pos..], delimiter) {
+            Some(len) => (&body[pos..pos + len], Some(pos + len + delimiter.len())),
+            None => (&body[pos..], None),
+        };
+        let expected = range.last - range.first + 1;
+        if next.is_some() && data.len() != expected {
+            return Err(invalid(&format!(
+                "multipart part {}-{} carries {} bytes",
+                range.first, range.last, data.len()
+            )));
+        }
+        // A truncated final part may end in the start of a delimiter.
+        parts.push(Part { range, data: data[..data.len().min(expected)].to_vec() });
+        match next {
+            Some(next) => pos = next,
+            None => return Ok(parts),
+        }
+    }
+}
+
+fn part_content_range(head: &[u8]) -> io::Result<ContentRange> {
+    let headers = HeaderMap::parse(head)?;
+    let value = headers.get("content-range").ok_or_else(|| invalid("multipart part without Content-Range"))?;
+    parse_content_range(value).ok_or_else(|| invalid(&format!("Malformed Content-Range: {}", value)))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+fn invalid(message: &str) -> io::Error {
+    DownloadError::Parse(message.to_string()).into()
+}
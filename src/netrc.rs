@@ -0,0 +1,120 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::auth::Credentials;
+
+// Logins from a .netrc file, in file order. A `default` entry has no host.
+#[derive(Debug, Default)]
+pub struct Netrc {
+    machines: Vec<(Option<String>, Credentials)>,
+}
+
+impl Netrc {
+    // A missing file has no logins, like a missing --cache file.
+    pub fn load(path: &Path) -> io::Result<Netrc> {
+        match fs::read_to_string(path) {
+            Ok(text) => Ok(Netrc::parse(&text)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Netrc::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Understands machine, default, login and password; account is ignored and
+    // macdef bodies are skipped up to the blank line that ends them.
+    pub fn parse(text: &str) -> Netrc {
+        let mut machines = Vec::new();
+        let mut current: Option<(Option<String>, Credentials)> = None;
+        let mut tokens = Tokens { rest: text };
+        while let Some(token) = tokens.next() {
+            match token.as_str() {
+                "machine" | "default" => {
+                    machines.extend(current.take());
+                    let host = if token == "machine" { tokens.next() } else { None };
+                    current = Some((host, Credentials { user: String::new(), password: String::new() }));
+                }
+                "login" => {
+                    if let (Some((_, credentials)), Some(user)) = (current.as_mut(), tokens.next()) {
+                        credentials.user = user;
+                    }
+                }
+                "password" => {
+                    if let (Some((_, credentials)), Some(password)) = (current.as_mut(), tokens.next()) {
+                        credentials.password = password;
+                    }
+                }
+                "account" => {
+                    tokens.next();
+                }
+                "macdef" => tokens.skip_macro(),
+                _ => {}
+            }
+        }
+        machines.extend(current);
+        machines.retain(|(_, credentials)| !credentials.user.is_empty());
+        Netrc { machines }
+    }
+
+    // The first entry for `host`, else the default entry.
+    pub fn lookup(&self, host: &str) -> Option<Credentials> {
+        let find = |wanted: Option<&str>| {
+            self.machines.iter().find(|(machine, _)| match (machine, wanted) {
+                (Some(machine), Some(host)) => machine.eq_ignore_ascii_case(host),
+                (None, None) => true,
+                _ => false,
+            })
+        };
+        find(Some(host)).or_else(|| find(None)).map(|(_, credentials)| credentials.clone())
+    }
+}
+
+// $NETRC, else ~/.netrc, as curl looks it up.
+pub fn default_path() -> Option<PathBuf> {
+    env::var_os("NETRC")
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".netrc")))
+}
+
+// Whitespace-separated words; a double-quoted word may contain spaces and
+// backslash escapes.
+struct Tokens<'a> {
+    rest: &'a str,
+}
+
+impl Tokens<'_> {
+    fn next(&mut self) -> Option<String> {
+        self.rest = self.rest.trim_start();
+        if self.rest.is_empty() {
+            return None;
+        }
+        let mut word = String::new();
+        if let Some(quoted) = self.rest.strip_prefix('"') {
+            let mut chars = quoted.char_indices();
+            self.rest = "";
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => word.extend(chars.next().map(|(_, escaped)| escaped)),
+                    '"' => {
+                        self.rest = &quoted[i + 1..];
+                        break;
+                    }
+                    c => word.push(c),
+                }
+            }
+        } else {
+            let end = self.rest.find(char::is_whitespace).unwrap_or(self.rest.len());
+            word.push_str(&self.rest[..end]);
+            self.rest = &self.rest[end..];
+        }
+        Some(word)
+    }
+
+    fn skip_macro(&mut self) {
+        self.rest = match self.rest.find("\n\n") {
+            Some(end) => &self.rest[end + 2..],
+            None => "",
+        };
+    }
+}
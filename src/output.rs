@@ -0,0 +1,261 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::hash::{Algorithm, Hasher};
+use crate::pieces::{Piece, PieceHasher};
+use crate::stream::Sender;
+use crate::url::{percent_decode, Url};
+
+// Longest file name most file systems accept, in bytes.
+const MAX_NAME_LEN: usize = 255;
+
+// Receives the ordered, decoded bytes: they are always hashed, and with -o
+// also written to the file unless the chunks were written in place. With
+// --tee they go to stdout as well, and to a ByteStream for an embedder.
+pub struct Sink {
+    // Every algorithm is fed the same writes, so one pass over the data
+    // gives all the digests.
+    hashers: Vec<(Algorithm, Box<dyn Hasher>)>,
+    file: Option<BufWriter<File>>,
+    stdout: Option<BufWriter<io::Stdout>>,
+    stream: Option<Sender>,
+    written: u64,
+    pieces: Option<PieceHasher>,
+}
+
+impl Sink {
+    // Creates (or truncates) the output file up front, so a bad path fails
+    // before anything is downloaded.
+    pub fn new(path: Option<&Path>) -> io::Result<Sink> {
+        let file = match path {
+            Some(path) => {
+                let file = File::create(path)
+                    .map_err(|e| io::Error::new(e.kind(), format!("Cannot create {}: {}", path.display(), e)))?;
+                Some(BufWriter::new(file))
+            }
+            None => None,
+        };
+        Ok(Sink {
+            hashers: vec![(Algorithm::Sha256, Algorithm::Sha256.hasher())],
+            file,
+            stdout: None,
+            stream: None,
+            written: 0,
+            pieces: None,
+        })
+    }
+
+    // Hashes with `algorithms` instead of SHA-256, for --hash, those that
+    // can on up to `threads` threads.
+    pub fn with_algorithms(mut self, algorithms: &[Algorithm], threads: usize) -> Sink {
+        self.hashers = algorithms.iter().map(|&algorithm| (algorithm, algorithm.threaded_hasher(threads))).collect();
+        self
+    }
+
+    // Also writes the bytes to stdout, for --tee.
+    pub fn with_stdout(mut self) -> Sink {
+        self.stdout = Some(BufWriter::new(io::stdout()));
+        self
+    }
+
+    // Also sends the bytes to a ByteStream, for download_stream.
+    pub fn with_stream(mut self, sender: Sender) -> Sink {
+        self.stream = Some(sender);
+        self
+    }
+
+    // Also hashes the bytes in pieces of `size`, for --piece-hashes.
+    pub fn with_piece_hashes(mut self, size: u64) -> Sink {
+        self.pieces = Some(PieceHasher::new(size));
+        self
+    }
+
+    // The piece digests, once everything has been written.
+    pub fn take_pieces(&mut self) -> Option<Vec<Piece>> {
+        self.pieces.take().map(PieceHasher::finish)
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    // Bytes hashed so far and their digest with the first algorithm, for
+    // the --continue state.
+    pub fn progress(&self) -> (u64, Vec<u8>) {
+        (self.written, self.hashers[0].1.digest())
+    }
+
+    // Flushes the file and returns each algorithm's digest of everything
+    // written, in the order they were given.
+    pub fn finish(self) -> io::Result<Vec<(Algorithm, Vec<u8>)>> {
+        if let Some(file) = self.file {
+            file.into_inner().map_err(|e| e.into_error())?;
+        }
+        if let Some(mut stdout) = self.stdout {
+            stdout.flush()?;
+        }
+        Ok(self.hashers.into_iter().map(|(algorithm, hasher)| (algorithm, hasher.digest())).collect())
+    }
+}
+
+impl Write for Sink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(file) = &mut self.file {
+            file.write_all(buf)?;
+        }
+        if let Some(stdout) = &mut self.stdout {
+            stdout.write_all(buf)?;
+        }
+        if let Some(stream) = &mut self.stream {
+            stream.write_all(buf)?;
+        }
+        for (_, hasher) in &mut self.hashers {
+            hasher.update(buf);
+        }
+        if let Some(pieces) = &mut self.pieces {
+            pieces.update(buf);
+        }
+        self.written += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(file) = &mut self.file {
+            file.flush()?;
+        }
+        match &mut self.stdout {
+            Some(stdout) => stdout.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+// Where a download to `path` is written until it is complete.
+pub fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+// Gives the finished `part` file its final name in one step, replacing any
+// older file there. With `sync` the data is on disk before the rename and the
+// rename itself before returning, so a power loss leaves either the old file
+// or the complete new one.
+pub fn commit(part: &Path, path: &Path, sync: bool) -> io::Result<()> {
+    if sync {
+        // Any handle will do: fsync covers everything written to the file,
+        // through the threads' handles and a mapping alike.
+        OpenOptions::new().write(true).open(part)?.sync_all()?;
+    }
+    fs::rename(part, path)
+        .map_err(|e| io::Error::new(e.kind(), format!("Cannot rename {} to {}: {}", part.display(), path.display(), e)))?;
+    if sync {
+        sync_dir(path)?;
+    }
+    Ok(())
+}
+
+// Makes the directory entry for `path` durable.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+// Windows has no handle to sync a directory through; NTFS journals renames.
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+// The file name for -O: Content-Disposition's filename* (RFC 6266), then its
+// filename, then the last segment of the URL path. The server picks these, so
+// only a bare name in the current directory is ever used.
+pub fn remote_name(content_disposition: Option<&str>, url: &Url) -> io::Result<PathBuf> {
+    let params = content_disposition.map(disposition_params).unwrap_or_default();
+    let param = |name: &str| params.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value);
+    let from_header = param("filename*").and_then(|value| ext_value(value)).and_then(|name| sanitize(&name));
+    let from_header = from_header.or_else(|| param("filename").and_then(|name| sanitize(name)));
+    let segment = url.path.rsplit('/').next().unwrap_or("");
+    from_header
+        .or_else(|| sanitize(&String::from_utf8_lossy(&percent_decode(segment))))
+        .map(PathBuf::from)
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("Cannot derive a file name from {}; use -o", url))
+        })
+}
+
+// The parameters after the disposition type, with quoted-string values
+// unescaped; ';' inside quotes does not split.
+fn disposition_params(value: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut chars = value.chars().peekable();
+    // Skip the disposition type.
+    for c in chars.by_ref() {
+        if c == ';' {
+            break;
+        }
+    }
+    while chars.peek().is_some() {
+        let mut key = String::new();
+        for c in chars.by_ref() {
+            if c == '=' || c == ';' {
+                break;
+            }
+            key.push(c);
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => value.extend(chars.next()),
+                    c => value.push(c),
+                }
+            }
+        }
+        for c in chars.by_ref() {
+            if c == ';' {
+                break;
+            }
+            value.push(c);
+        }
+        params.push((key.trim().to_string(), value.trim().to_string()));
+    }
+    params
+}
+
+// Decodes an RFC 8187 ext-value, charset'language'percent-encoded, in UTF-8
+// or ISO-8859-1.
+fn ext_value(value: &str) -> Option<String> {
+    let mut parts = value.splitn(3, '\'');
+    let (charset, _language, encoded) = (parts.next()?, parts.next()?, parts.next()?);
+    let bytes = percent_decode(encoded);
+    if charset.eq_ignore_ascii_case("utf-8") {
+        String::from_utf8(bytes).ok()
+    } else if charset.eq_ignore_ascii_case("iso-8859-1") {
+        Some(bytes.into_iter().map(char::from).collect())
+    } else {
+        None
+    }
+}
+
+// Reduces a suggested name to a plain file name: no directories (either kind
+// of separator), no control characters, and no leading dots, which also rules
+// out "." and "..". None when nothing usable is left.
+fn sanitize(name: &str) -> Option<String> {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or("");
+    let name: String = name.chars().filter(|c| !c.is_control()).collect();
+    let name = name.trim().trim_start_matches('.');
+    let mut end = name.len().min(MAX_NAME_LEN);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    let name = name[..end].trim_end();
+    (!name.is_empty()).then(|| name.to_string())
+}
@@ -0,0 +1,72 @@
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+// One piece of the download and the SHA-256 of its bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Piece {
+    pub offset: u64,
+    pub length: u64,
+    pub sha256: String,
+}
+
+// Hashes the ordered bytes in consecutive pieces of a fixed size, for
+// --piece-hashes. With the manifest, a damaged copy of the file can be
+// checked piece by piece and only the bad regions fetched again.
+pub struct PieceHasher {
+    size: u64,
+    hasher: Sha256,
+    // Start and length so far of the piece being hashed.
+    offset: u64,
+    filled: u64,
+    pieces: Vec<Piece>,
+}
+
+impl PieceHasher {
+    pub fn new(size: u64) -> PieceHasher {
+        PieceHasher { size, hasher: Sha256::new(), offset: 0, filled: 0, pieces: Vec::new() }
+    }
+
+    pub fn update(&mut self, mut buf: &[u8]) {
+        while !buf.is_empty() {
+            let take = buf.len().min((self.size - self.filled) as usize);
+            self.hasher.update(&buf[..take]);
+            self.filled += take as u64;
+            buf = &buf[take..];
+            if self.filled == self.size {
+                self.close();
+            }
+        }
+    }
+
+    // The digests of all pieces, the last one possibly shorter.
+    pub fn finish(mut self) -> Vec<Piece> {
+        if self.filled > 0 {
+            self.close();
+        }
+        self.pieces
+    }
+
+    fn close(&mut self) {
+        let sha256 = format!("{:x}", std::mem::take(&mut self.hasher).finalize());
+        self.pieces.push(Piece { offset: self.offset, length: self.filled, sha256 });
+        self.offset += self.filled;
+        self.filled = 0;
+    }
+}
+
+// A comment naming the piece size, then "<offset> <length> <sha256>" per piece.
+pub fn manifest(size: u64, pieces: &[Piece]) -> String {
+    let mut text = format!("# sha256 of {}-byte pieces\n", size);
+    for piece in pieces {
+        text.push_str(&format!("{} {} {}\n", piece.offset, piece.length, piece.sha256));
+    }
+    text
+}
+
+// The manifest of a download to `output` is kept next to it.
+pub fn manifest_path(output: &Path) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".pieces");
+    PathBuf::from(path)
+}
@@ -0,0 +1,159 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+
+use tracing::warn;
+
+use crate::client::Client;
+use crate::connector::Stream;
+use crate::metrics;
+use crate::queue::WorkQueue;
+use crate::store::Chunks;
+use crate::url::Url;
+use crate::{can_reuse, format_request, range, range_body, read_message};
+
+// Size of the sub-ranges kept in flight, as for HTTP/2: small enough that the
+// server never truncates them.
+const PIECE_SIZE: usize = 64 * 1024;
+
+// A connection plus bytes already read from it that belong to responses not
+// parsed yet.
+struct Pipelined {
+    stream: Stream,
+    pending: Vec<u8>,
+}
+
+impl Read for Pipelined {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            return self.stream.read(buf);
+        }
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+// Downloads ranges from the queue for one worker with up to `depth` range
+// requests in flight on one keep-alive connection; the responses come back in
+// request order. Truncated pieces are re-queued from where they stopped and
+// requests left unanswered when the server closes the connection go out again
+// on a new one. Fails when a connection makes no progress at all, giving back
+// what it holds, so the caller can fall back to one request at a time.
+pub fn fetch_range(
+    thread: usize,
+    url: &Url,
+    client: &Client,
+    depth: usize,
+    queue: &WorkQueue,
+    chunks: &Chunks,
+) -> io::Result<()> {
+    let mut work = Work { thread, queue, pieces: VecDeque::new() };
+    loop {
+        if !work.refill() {
+            return Ok(());
+        }
+        let mut fetched = 0;
+        let mut in_flight = VecDeque::new();
+        let result = exchange(url, client, depth, &mut work, &mut in_flight, chunks, &mut fetched);
+        while let Some(piece) = in_flight.pop_back() {
+            work.pieces.push_front(piece);
+        }
+        match result {
+            Err(e) if range::is_resource_changed(&e) => return Err(e),
+            result if fetched == 0 => {
+                queue.give_back(work.pieces.into_iter());
+                return Err(result.err().unwrap_or_else(|| io::Error::other("pipelined connection made no progress")));
+            }
+            Err(e) => {
+                queue.failed(thread);
+                metrics::METRICS.failed(url);
+                warn!("reconnecting pipelined connection: {}", e);
+            }
+            Ok(()) => {}
+        }
+    }
+}
+
+// The pieces a worker holds, topped up from the shared queue one range at a
+// time.
+struct Work<'a> {
+    thread: usize,
+    queue: &'a WorkQueue,
+    pieces: VecDeque<(usize, usize)>,
+}
+
+impl Work<'_> {
+    // Splits the next range from the queue into pieces if none are left;
+    // false once both are empty.
+    fn refill(&mut self) -> bool {
+        if self.pieces.is_empty() {
+            let Some((start, range_end)) = self.queue.next() else { return false };
+            self.pieces.extend(
+                (start..=range_end)
+                    .step_by(PIECE_SIZE)
+                    .map(|piece_start| (piece_start, (piece_start + PIECE_SIZE - 1).min(range_end))),
+            );
+        }
+        true
+    }
+}
+
+// Runs one connection until the work runs out, the server stops keeping it
+// alive, or a response fails. `fetched` counts the bytes it got.
+fn exchange(
+    url: &Url,
+    client: &Client,
+    depth: usize,
+    work: &mut Work,
+    in_flight: &mut VecDeque<(usize, usize)>,
+    chunks: &Chunks,
+    fetched: &mut usize,
+) -> io::Result<()> {
+    let mut connection = Pipelined { stream: client.connector.connect(url)?, pending: Vec::new() };
+    work.queue.connected(work.thread, 1);
+    loop {
+        let mut requests = String::new();
+        let sent = in_flight.len();
+        while in_flight.len() < depth {
+            if !work.refill() {
+                break;
+            }
+            let Some((piece_start, piece_end)) = work.pieces.pop_front() else { break };
+            requests.push_str(&format_request("GET", url, client, Some((piece_start, piece_end + 1)), true));
+            in_flight.push_back((piece_start, piece_end));
+        }
+        connection.stream.write_all(requests.as_bytes())?;
+        work.queue.requested(work.thread, in_flight.len() - sent);
+
+        let Some(&(piece_start, piece_end)) = in_flight.front() else { return Ok(()) };
+        let (response, excess) = read_message(&mut connection, client.read_size)?;
+        connection.pending.splice(0..0, excess);
+        in_flight.pop_front();
+        client.handle_response(url, &response);
+
+        let reusable = can_reuse(&response);
+        match range_body(client, response, piece_start, piece_end + 1) {
+            Ok(chunk) if !chunk.is_empty() => {
+                let fetched_to = piece_start + chunk.len();
+                *fetched += chunk.len();
+                work.queue.record(work.thread, piece_start, chunk.len());
+                chunks.insert(piece_start, chunk)?;
+                if fetched_to <= piece_end {
+                    work.pieces.push_back((fetched_to, piece_end));
+                }
+            }
+            Ok(_) => {
+                work.pieces.push_back((piece_start, piece_end));
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "empty pipelined response"));
+            }
+            Err(e) => {
+                work.pieces.push_back((piece_start, piece_end));
+                return Err(e);
+            }
+        }
+        if !reusable {
+            return Ok(());
+        }
+    }
+}
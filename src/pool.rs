@@ -0,0 +1,57 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+// How much capacity the pool keeps for reuse at most. A buffer given back
+// past that is freed, so one large single-stream body does not stay
+// allocated for the rest of the run.
+pub const POOL_LIMIT: usize = 64 * 1024 * 1024;
+
+// Body and chunk buffers given back once their bytes are hashed or written,
+// for the next read to fill instead of allocating a fresh Vec. At high
+// thread counts every range would otherwise allocate and free its own.
+pub struct BufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    limit: usize,
+    // Buffers taken that had to be allocated, and that were reused.
+    allocated: AtomicUsize,
+    reused: AtomicUsize,
+}
+
+pub static POOL: BufferPool = BufferPool::new(POOL_LIMIT);
+
+impl BufferPool {
+    pub const fn new(limit: usize) -> BufferPool {
+        BufferPool { buffers: Mutex::new(Vec::new()), limit, allocated: AtomicUsize::new(0), reused: AtomicUsize::new(0) }
+    }
+
+    // An empty buffer, with the capacity of one given back if there is one.
+    pub fn take(&self) -> Vec<u8> {
+        match self.buffers.lock().unwrap().pop() {
+            Some(buffer) => {
+                self.reused.fetch_add(1, Ordering::Relaxed);
+                buffer
+            }
+            None => {
+                self.allocated.fetch_add(1, Ordering::Relaxed);
+                Vec::new()
+            }
+        }
+    }
+
+    pub fn give(&self, mut buffer: Vec<u8>) {
+        if buffer.capacity() == 0 {
+            return;
+        }
+        buffer.clear();
+        let mut buffers = self.buffers.lock().unwrap();
+        let held: usize = buffers.iter().map(Vec::capacity).sum();
+        if held + buffer.capacity() <= self.limit {
+            buffers.push(buffer);
+        }
+    }
+
+    // Buffers allocated and reused so far.
+    pub fn counts(&self) -> (usize, usize) {
+        (self.allocated.load(Ordering::Relaxed), self.reused.load(Ordering::Relaxed))
+    }
+}
@@ -0,0 +1,113 @@
+use std::time::{Duration, Instant};
+
+// How often the progress line is redrawn.
+const REFRESH: Duration = Duration::from_millis(250);
+
+// Time constant of the speed averages, in seconds: a lasting change in speed
+// shows within a few seconds, while the pauses between responses do not.
+const SMOOTHING: f64 = 3.0;
+
+// The progress line of one download: how much has arrived, the speed, the
+// time left at that speed, and the speed of each connection.
+pub struct Progress {
+    total: usize,
+    // Bytes an interrupted run already downloaded.
+    already: usize,
+    received: usize,
+    by_thread: Vec<usize>,
+    // Exponentially weighted moving averages, in bytes per second.
+    speed: Option<f64>,
+    thread_speeds: Vec<Option<f64>>,
+    last: Instant,
+}
+
+impl Progress {
+    pub fn new(total: usize, already: usize, now: Instant) -> Progress {
+        Progress { total, already, received: 0, by_thread: Vec::new(), speed: None, thread_speeds: Vec::new(), last: now }
+    }
+
+    // Takes the bytes received so far, in all and by thread. Returns the
+    // line to draw at most every REFRESH.
+    pub fn update(&mut self, received: usize, by_thread: &[usize], now: Instant) -> Option<String> {
+        let seconds = now.duration_since(self.last).as_secs_f64();
+        if seconds < REFRESH.as_secs_f64() {
+            return None;
+        }
+        let weight = 1.0 - (-seconds / SMOOTHING).exp();
+        let average = |speed: Option<f64>, bytes: usize| {
+            let rate = bytes as f64 / seconds;
+            Some(speed.map_or(rate, |speed| speed + weight * (rate - speed)))
+        };
+        self.speed = average(self.speed, received.saturating_sub(self.received));
+        self.by_thread.resize(by_thread.len(), 0);
+        self.thread_speeds.resize(by_thread.len(), None);
+        for ((speed, last), &count) in self.thread_speeds.iter_mut().zip(&mut self.by_thread).zip(by_thread) {
+            // A thread not started yet has no speed to show.
+            if count > 0 {
+                *speed = average(*speed, count.saturating_sub(*last));
+            }
+            *last = count;
+        }
+        self.received = received;
+        self.last = now;
+        Some(self.line())
+    }
+
+    // E.g. " 45.2% 9.0 MiB of 20.0 MiB, 3.1 MiB/s, ETA 0:04 [812K 790K 1.1M 406K]"
+    // with the connections' speeds per second in brackets.
+    fn line(&self) -> String {
+        let done = (self.already + self.received).min(self.total);
+        let percent = if self.total == 0 { 100.0 } else { done as f64 * 100.0 / self.total as f64 };
+        let speed = self.speed.unwrap_or(0.0);
+        let eta = if done == self.total {
+            duration(0.0)
+        } else if speed > 0.0 {
+            duration((self.total - done) as f64 / speed)
+        } else {
+            "--:--".to_string()
+        };
+        let mut line =
+            format!("{:5.1}% {} of {}, {}/s, ETA {}", percent, bytes(done as f64), bytes(self.total as f64), bytes(speed), eta);
+        let connections: Vec<_> = self.thread_speeds.iter().flatten().map(|&speed| short_bytes(speed)).collect();
+        if !connections.is_empty() {
+            line.push_str(&format!(" [{}]", connections.join(" ")));
+        }
+        line
+    }
+}
+
+pub fn bytes(bytes: f64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024.0 {
+        return format!("{:.0} B", bytes);
+    }
+    let mut value = bytes / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+// At most four characters, to fit a dozen connections on a line.
+fn short_bytes(bytes: f64) -> String {
+    match bytes {
+        bytes if bytes < 1024.0 => format!("{:.0}B", bytes),
+        bytes if bytes < 1000.0 * 1024.0 => format!("{:.0}K", bytes / 1024.0),
+        bytes if bytes < 10.0 * 1024.0 * 1024.0 => format!("{:.1}M", bytes / (1024.0 * 1024.0)),
+        bytes if bytes < 1000.0 * 1024.0 * 1024.0 => format!("{:.0}M", bytes / (1024.0 * 1024.0)),
+        bytes => format!("{:.1}G", bytes / (1024.0 * 1024.0 * 1024.0)),
+    }
+}
+
+// "m:ss", or "h:mm:ss" from an hour on.
+fn duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}:{:02}:{:02}", hours, minutes, seconds)
+    } else {
+        format!("{}:{:02}", minutes, seconds)
+    }
+}
@@ -0,0 +1,168 @@
+use std::env;
+use std::io::{self, Write};
+use std::net::{IpAddr, TcpStream};
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+
+use crate::error::DownloadError;
+use crate::request::RequestBuilder;
+use crate::url::{percent_decode, split_host_port, Url};
+use crate::{parse_status_line, read_head};
+
+// Port used when a proxy URL does not name one, as in curl.
+const DEFAULT_PROXY_PORT: u16 = 1080;
+
+// An HTTP proxy, with the Proxy-Authorization built from its URL's userinfo.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Proxy {
+    host: String,
+    port: u16,
+    authorization: Option<String>,
+}
+
+impl Proxy {
+    // Accepts "[http://][user:password@]host[:port][/]"; other proxy schemes
+    // are rejected.
+    pub fn parse(value: &str) -> io::Result<Proxy> {
+        let invalid = |reason: &str| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid proxy {:?}: {}", value, reason))
+        };
+        let rest = match value.split_once("://") {
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case("http") => rest,
+            Some(_) => return Err(invalid("only http:// proxies are supported")),
+            None => value,
+        };
+        let authority = &rest[..rest.find(['/', '?']).unwrap_or(rest.len())];
+        let (userinfo, host_port) = match authority.rsplit_once('@') {
+            Some((userinfo, host_port)) => (Some(userinfo), host_port),
+            None => (None, authority),
+        };
+        let (host, port) = split_host_port(host_port).map_err(|e| invalid(&e.to_string()))?;
+        let port = port.unwrap_or(DEFAULT_PROXY_PORT);
+        if host.is_empty() {
+            return Err(invalid("missing host"));
+        }
+        let authorization = userinfo.map(|userinfo| {
+            let (user, password) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+            format!("Basic {}", BASE64.encode(format!("{}:{}", String::from_utf8_lossy(&percent_decode(user)), String::from_utf8_lossy(&percent_decode(password)))))
+        });
+        Ok(Proxy { host: host.to_string(), port, authorization })
+    }
+
+    pub fn authorization(&self) -> Option<&str> {
+        self.authorization.as_deref()
+    }
+
+    // A plain connection to the proxy, for requests it forwards itself.
+    pub fn connect(&self) -> io::Result<TcpStream> {
+        TcpStream::connect((self.host.as_str(), self.port))
+    }
+
+    // A connection tunnelled to `url`'s host with CONNECT, ready for TLS or
+    // any other protocol.
+    pub fn tunnel(&self, url: &Url) -> io::Result<TcpStream> {
+        let mut tcp = self.connect()?;
+        let target = url.socket_addr();
+        let mut request = RequestBuilder::new("CONNECT", &target).header("Host", &target);
+        if let Some(value) = &self.authorization {
+            request = request.header("Proxy-Authorization", value);
+        }
+        tcp.write_all(request.build().as_bytes())?;
+        let (head, _) = read_head(&mut tcp)?;
+        let status = parse_status_line(&head)?;
+        if !status.is_success() {
+            return Err(DownloadError::HttpStatus {
+                code: status.code,
+                message: format!(
+                    "Proxy {}:{} refused CONNECT to {}: {} {}",
+                    self.host, self.port, target, status.code, status.reason
+                ),
+            }
+            .into());
+        }
+        Ok(tcp)
+    }
+}
+
+// Proxies taken from the environment the way curl reads it.
+#[derive(Debug, Default)]
+pub struct ProxySettings {
+    http: Option<Proxy>,
+    https: Option<Proxy>,
+    // Lowercased no_proxy entries.
+    no_proxy: Vec<String>,
+}
+
+impl ProxySettings {
+    pub fn from_env() -> io::Result<ProxySettings> {
+        ProxySettings::from_vars(|name| env::var(name).ok())
+    }
+
+    // Only the lowercase http_proxy is read: HTTP_PROXY can be set by a CGI
+    // request's Proxy header. HTTPC_PROXY, this client's own, wins over all
+    // of them.
+    pub fn from_vars(var: impl Fn(&str) -> Option<String>) -> io::Result<ProxySettings> {
+        let lookup = |names: &[&str]| names.iter().find_map(|name| var(name).filter(|value| !value.trim().is_empty()));
+        let all = lookup(&["HTTPC_PROXY", "all_proxy", "ALL_PROXY"]);
+        let http = lookup(&["HTTPC_PROXY", "http_proxy"]).or_else(|| all.clone());
+        let https = lookup(&["HTTPC_PROXY", "https_proxy", "HTTPS_PROXY"]).or(all);
+        let no_proxy = lookup(&["no_proxy", "NO_PROXY"]).unwrap_or_default();
+        Ok(ProxySettings {
+            http: http.as_deref().map(|value| Proxy::parse(value.trim())).transpose()?,
+            https: https.as_deref().map(|value| Proxy::parse(value.trim())).transpose()?,
+            no_proxy: no_proxy
+                .split(',')
+                .map(|entry| entry.trim().to_lowercase())
+                .filter(|entry| !entry.is_empty())
+                .collect(),
+        })
+    }
+
+    // The proxy for requests to `url`, unless no_proxy exempts its host.
+    pub fn for_url(&self, url: &Url) -> Option<&Proxy> {
+        let proxy = match url.scheme.as_str() {
+            "http" => self.http.as_ref(),
+            "https" => self.https.as_ref(),
+            _ => None,
+        }?;
+        if self.bypass(&url.host) {
+            None
+        } else {
+            Some(proxy)
+        }
+    }
+
+    // no_proxy entries are "*", domain names, which also cover their
+    // subdomains, IP addresses, and CIDR networks.
+    fn bypass(&self, host: &str) -> bool {
+        let host = host.to_lowercase();
+        let ip = host.parse::<IpAddr>().ok();
+        self.no_proxy.iter().any(|entry| {
+            if entry == "*" {
+                return true;
+            }
+            if let Some(ip) = ip {
+                return match entry.split_once('/') {
+                    Some((network, bits)) => in_network(ip, network, bits),
+                    None => entry.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() == Ok(ip),
+                };
+            }
+            let domain = entry.trim_start_matches('.');
+            host == domain || host.strip_suffix(domain).is_some_and(|prefix| prefix.ends_with('.'))
+        })
+    }
+}
+
+fn in_network(ip: IpAddr, network: &str, bits: &str) -> bool {
+    let (Ok(network), Ok(bits)) = (network.parse::<IpAddr>(), bits.parse::<u32>()) else { return false };
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) if bits <= 32 => {
+            (u32::from(ip) ^ u32::from(network)).checked_shr(32 - bits).unwrap_or(0) == 0
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) if bits <= 128 => {
+            (u128::from(ip) ^ u128::from(network)).checked_shr(128 - bits).unwrap_or(0) == 0
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::callbacks::Callbacks;
+use crate::cancel::CancellationToken;
+use crate::metrics::METRICS;
+use crate::stats::{Connection, RangeFailure};
+
+// Ranges per thread the download is split into at least, so a thread that
+// finishes early has work left to take over from slower ones.
+const UNITS_PER_THREAD: usize = 4;
+
+// Failed requests for the same bytes before the threads leave them to the
+// main thread, which makes the last attempts.
+pub const MAX_ATTEMPTS: usize = 5;
+
+// The ranges still to download, shared by all threads. Each thread takes the
+// next one whenever it is done with its current one, so a slow connection
+// holds up at most the range it is on rather than a fixed share of the file.
+// Ranges are inclusive, like the threads' requests.
+pub struct WorkQueue {
+    pending: Mutex<VecDeque<(usize, usize)>>,
+    // Bytes the threads have received, for measuring throughput.
+    received: AtomicUsize,
+    // What each thread did, for the progress line and --stats.
+    by_thread: Vec<Counters>,
+    // Failed requests so far by the offset they started at.
+    failures: Mutex<HashMap<usize, RangeFailure>>,
+    max_attempts: usize,
+    // Set when the download is given up, e.g. at --max-time.
+    stopped: AtomicBool,
+    // Told about each chunk and retry of the threads.
+    callbacks: Callbacks,
+    // Stops the threads like `stopped`, and also their requests.
+    cancel: CancellationToken,
+}
+
+impl WorkQueue {
+    // Splits `ranges` into units of at most `max_unit` bytes, smaller for
+    // small files so every thread gets several. Threads are numbered below
+    // `threads`.
+    pub fn new(ranges: &[(usize, usize)], threads: usize, max_unit: usize) -> WorkQueue {
+        let total: usize = ranges.iter().map(|(start, end)| end + 1 - start).sum();
+        let unit = total.div_ceil(threads.max(1) * UNITS_PER_THREAD).clamp(1, max_unit.max(1));
+        let pending = ranges
+            .iter()
+            .flat_map(|&(start, end)| (start..=end).step_by(unit).map(move |unit_start| (unit_start, (unit_start + unit - 1).min(end))))
+            .collect();
+        WorkQueue {
+            pending: Mutex::new(pending),
+            received: AtomicUsize::new(0),
+            by_thread: (0..threads.max(1)).map(|_| Counters::default()).collect(),
+            failures: Mutex::new(HashMap::new()),
+            max_attempts: MAX_ATTEMPTS,
+            stopped: AtomicBool::new(false),
+            callbacks: Callbacks::default(),
+            cancel: CancellationToken::default(),
+        }
+    }
+
+    // Gives up on bytes after `attempts` failed requests instead.
+    pub fn with_max_attempts(mut self, attempts: usize) -> WorkQueue {
+        self.max_attempts = attempts;
+        self
+    }
+
+    pub fn with_callbacks(mut self, callbacks: Callbacks) -> WorkQueue {
+        self.callbacks = callbacks;
+        self
+    }
+
+    pub fn with_cancellation(mut self, cancel: CancellationToken) -> WorkQueue {
+        self.cancel = cancel;
+        self
+    }
+
+    pub fn cancellation(&self) -> &CancellationToken {
+        &self.cancel
+    }
+
+    pub fn max_attempts(&self) -> usize {
+        self.max_attempts
+    }
+
+    pub fn next(&self) -> Option<(usize, usize)> {
+        if self.stopped.load(Ordering::Relaxed) || self.cancel.is_cancelled() {
+            return None;
+        }
+        self.pending.lock().unwrap().pop_front()
+    }
+
+    // Hands out no more ranges, so the threads finish with the ones they
+    // are on.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    // Returns ranges a thread gave up on, first in line for the next taker.
+    pub fn give_back(&self, ranges: impl DoubleEndedIterator<Item = (usize, usize)>) {
+        let mut pending = self.pending.lock().unwrap();
+        for range in ranges.rev() {
+            pending.push_front(range);
+        }
+    }
+
+    // Puts back a range whose request failed with `error`, first in line,
+    // so the next idle thread retries it, likely on another connection.
+    // False once the bytes at its start have failed max_attempts times.
+    pub fn retry(&self, range: (usize, usize), error: &io::Error) -> bool {
+        let mut failures = self.failures.lock().unwrap();
+        let failure = failures.entry(range.0).or_insert(RangeFailure { start: range.0, end: range.1, attempts: 0, error: String::new() });
+        failure.end = range.1;
+        failure.attempts += 1;
+        failure.error = error.to_string();
+        if failure.attempts >= self.max_attempts {
+            return false;
+        }
+        drop(failures);
+        self.give_back(std::iter::once(range));
+        self.callbacks.retry(range, error);
+        true
+    }
+
+    // A thread received `bytes` at offset `start`.
+    pub fn record(&self, thread: usize, start: usize, bytes: usize) {
+        self.received.fetch_add(bytes, Ordering::Relaxed);
+        METRICS.received(bytes);
+        if let Some(counters) = self.by_thread.get(thread) {
+            counters.received.fetch_add(bytes, Ordering::Relaxed);
+        }
+        self.callbacks.chunk_complete(start, bytes);
+    }
+
+    pub fn requested(&self, thread: usize, requests: usize) {
+        METRICS.requested(requests);
+        if let Some(counters) = self.by_thread.get(thread) {
+            counters.requests.fetch_add(requests, Ordering::Relaxed);
+        }
+    }
+
+    // Connections `thread` opened for its requests.
+    pub fn connected(&self, thread: usize, connections: usize) {
+        METRICS.connected(connections);
+        if let Some(counters) = self.by_thread.get(thread) {
+            counters.connects.fetch_add(connections, Ordering::Relaxed);
+        }
+    }
+
+    pub fn failed(&self, thread: usize) {
+        if let Some(counters) = self.by_thread.get(thread) {
+            counters.failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // How long the thread ran, once it is done.
+    pub fn finished(&self, thread: usize, elapsed: Duration) {
+        if let Some(counters) = self.by_thread.get(thread) {
+            *counters.elapsed.lock().unwrap() = elapsed;
+        }
+    }
+
+    pub fn received(&self) -> usize {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    pub fn received_by_thread(&self) -> Vec<usize> {
+        self.by_thread.iter().map(|counters| counters.received.load(Ordering::Relaxed)).collect()
+    }
+
+    // The bytes whose requests failed, by start.
+    pub fn failures(&self) -> Vec<RangeFailure> {
+        let mut failures: Vec<_> = self.failures.lock().unwrap().values().cloned().collect();
+        failures.sort_by_key(|failure| failure.start);
+        failures
+    }
+
+    // The threads that were started, in order.
+    pub fn connections(&self) -> Vec<Connection> {
+        self.by_thread
+            .iter()
+            .enumerate()
+            .map(|(thread, counters)| Connection {
+                thread,
+                received: counters.received.load(Ordering::Relaxed),
+                requests: counters.requests.load(Ordering::Relaxed),
+                connects: counters.connects.load(Ordering::Relaxed),
+                failed: counters.failed.load(Ordering::Relaxed),
+                elapsed: *counters.elapsed.lock().unwrap(),
+            })
+            .filter(|connection| connection.requests > 0)
+            .collect()
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    received: AtomicUsize,
+    requests: AtomicUsize,
+    connects: AtomicUsize,
+    // Requests that failed, whether retried or given up on.
+    failed: AtomicUsize,
+    elapsed: Mutex<Duration>,
+}
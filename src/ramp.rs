@@ -0,0 +1,60 @@
+use std::time::{Duration, Instant};
+
+use tracing::{debug, info};
+
+// Most connections an automatic thread count opens for one download.
+pub const MAX_THREADS: usize = 16;
+
+// How long each thread count runs before its throughput is measured: long
+// enough for a new connection to get up to speed.
+const INTERVAL: Duration = Duration::from_secs(1);
+
+// How much faster the transfer must get for another connection to be worth it.
+const MIN_GAIN: f64 = 0.1;
+
+// Chooses the thread count when none is given: the download starts on one
+// connection and opens another after every interval as long as throughput
+// keeps rising, then settles.
+pub struct Ramp {
+    threads: usize,
+    max_threads: usize,
+    best_rate: f64,
+    received: usize,
+    since: Instant,
+    settled: bool,
+}
+
+impl Ramp {
+    pub fn new(now: Instant, max_threads: usize) -> Ramp {
+        Ramp { threads: 1, max_threads, best_rate: 0.0, received: 0, since: now, settled: false }
+    }
+
+    // Called regularly with the bytes received so far; true when one more
+    // thread should be started.
+    pub fn grow(&mut self, received: usize, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.since);
+        if self.settled || elapsed < INTERVAL {
+            return false;
+        }
+        let rate = received.saturating_sub(self.received) as f64 / elapsed.as_secs_f64();
+        self.received = received;
+        self.since = now;
+        // Nothing arrived yet, e.g. a slow first response: nothing to compare.
+        if rate == 0.0 {
+            return false;
+        }
+        if rate <= self.best_rate * (1.0 + MIN_GAIN) || self.threads >= self.max_threads {
+            info!("Settled on {} threads at {:.0} bytes/s", self.threads, rate.max(self.best_rate));
+            self.settled = true;
+            return false;
+        }
+        debug!("{:.0} bytes/s with {} threads, adding one", rate, self.threads);
+        self.best_rate = rate;
+        self.threads += 1;
+        true
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+}
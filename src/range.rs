@@ -0,0 +1,144 @@
+use std::io;
+
+use crate::error::DownloadError;
+use crate::multipart;
+
+// How many times download_chunk re-requests a range whose response does not
+// line up with what was asked for before giving up.
+pub const MISMATCH_RETRIES: usize = 3;
+
+pub fn is_resource_changed(e: &io::Error) -> bool {
+    matches!(DownloadError::of(e), Some(DownloadError::ResourceChanged(_)))
+}
+
+// Picks the If-Range value from the probe: a strong ETag, else Last-Modified.
+// Weak ETags are not allowed in If-Range (RFC 9110 13.1.5).
+pub fn if_range_validator(etag: Option<&str>, last_modified: Option<&str>) -> Option<String> {
+    match etag {
+        Some(etag) if !etag.starts_with("W/") => Some(etag.to_string()),
+        _ => last_modified.map(str::to_string),
+    }
+}
+
+// With If-Range, a 200 means the server has a new representation; a 206
+// carrying a different validator means it ignored If-Range but changed anyway.
+pub fn check_unchanged(
+    if_range: Option<&str>,
+    status: u16,
+    etag: Option<&str>,
+    last_modified: Option<&str>,
+) -> io::Result<()> {
+    let validator = match if_range {
+        Some(validator) => validator,
+        None => return Ok(()),
+    };
+    let current = if validator.starts_with('"') { etag } else { last_modified };
+    let reason = match (status, current) {
+        (200, _) => format!("server sent the full body for If-Range {}", validator),
+        (206, Some(current)) if current != validator => format!("validator {} is now {}", validator, current),
+        _ => return Ok(()),
+    };
+    Err(DownloadError::ResourceChanged(reason).into())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentRange {
+    pub first: usize,
+    pub last: usize,
+    pub complete_length: Option<usize>,
+}
+
+// Parses "bytes 0-499/1234" (or "bytes 0-499/*").
+pub fn parse_content_range(value: &str) -> Option<ContentRange> {
+    let spec = value.trim().strip_prefix("bytes ")?;
+    let (range, complete_length) = spec.split_once('/')?;
+    let (first, last) = range.split_once('-')?;
+    let first = first.trim().parse().ok()?;
+    let last = last.trim().parse().ok()?;
+    let complete_length = match complete_length.trim() {
+        "*" => None,
+        length => Some(length.parse().ok()?),
+    };
+    (first <= last).then_some(ContentRange { first, last, complete_length })
+}
+
+// Checks a response to a `Range: bytes=start-end` request and returns the
+// bytes that belong at `start`, at most `end - start` of them (the request
+// end is treated as exclusive). Servers that over-deliver, e.g. by honoring
+// the inclusive end of RFC 9110, are trimmed; responses whose offsets do not
+// match the request are rejected so they never reach the hasher.
+// multipart/byteranges replies are placed by each part's own Content-Range.
+pub fn validate_range(
+    status: u16,
+    content_type: Option<&str>,
+    content_range: Option<&str>,
+    start: usize,
+    end: usize,
+    mut body: Vec<u8>,
+) -> io::Result<Vec<u8>> {
+    let wanted = end.saturating_sub(start);
+    match status {
+        // The whole representation: cut the requested window out of it.
+        200 => {
+            let from = start.min(body.len());
+            let to = end.min(body.len());
+            body.truncate(to);
+            body.drain(..from);
+            Ok(body)
+        }
+        206 => {
+            if let Some(boundary) = content_type.and_then(multipart::boundary) {
+                return assemble_parts(multipart::parse(&body, &boundary)?, start, wanted);
+            }
+            if let Some(value) = content_range {
+                let range = parse_content_range(value)
+                    .ok_or_else(|| DownloadError::Parse(format!("Malformed Content-Range: {}", value)))?;
+                if range.first != start {
+                    return Err(DownloadError::RangeMismatch(format!(
+                        "Content-Range {}-{} does not start at requested offset {}",
+                        range.first, range.last, start
+                    ))
+                    .into());
+                }
+                if body.len() > range.last - range.first + 1 {
+                    return Err(DownloadError::RangeMismatch(format!(
+                        "Body of {} bytes exceeds Content-Range {}-{}",
+                        body.len(),
+                        range.first,
+                        range.last
+                    ))
+                    .into());
+                }
+            }
+            body.truncate(wanted);
+            Ok(body)
+        }
+        code => Err(DownloadError::RangeMismatch(format!("Unexpected status {} for a range request", code)).into()),
+    }
+}
+
+// Joins the parts that run contiguously from `start`, in offset order. Bytes
+// after the first gap are dropped; the caller re-requests from where the
+// returned data ends.
+fn assemble_parts(mut parts: Vec<multipart::Part>, start: usize, wanted: usize) -> io::Result<Vec<u8>> {
+    parts.sort_by_key(|part| part.range.first);
+    if !parts.iter().any(|part| part.range.first <= start && start <= part.range.last) {
+        return Err(DownloadError::RangeMismatch(format!("No multipart part covers requested offset {}", start)).into());
+    }
+    let mut body = Vec::new();
+    for part in parts {
+        let offset = start + body.len();
+        if part.range.first > offset {
+            break;
+        }
+        let skip = offset - part.range.first;
+        if skip < part.data.len() {
+            body.extend_from_slice(&part.data[skip..]);
+        }
+        if part.data.len() < part.range.last - part.range.first + 1 {
+            break; // Truncated part
+        }
+    }
+    body.truncate(wanted);
+    Ok(body)
+}
@@ -0,0 +1,84 @@
+use crate::upload::Body;
+use crate::url::Url;
+
+// An HTTP/1.1 request head, put together field by field and written out with
+// CRLF line endings and the blank line that ends it. Whatever a caller, a
+// hook or a --header passes in, the result is one request line and one line
+// per field: nothing can end the head early or slip in a field of its own.
+#[derive(Debug, Clone)]
+pub struct RequestBuilder {
+    method: String,
+    target: String,
+    headers: Vec<(String, String)>,
+}
+
+impl RequestBuilder {
+    // `target` is the path and query, or the absolute URL for a proxy, or
+    // host:port for CONNECT. The method must be a token, as parse_method
+    // makes sure of for -X.
+    pub fn new(method: &str, target: &str) -> RequestBuilder {
+        RequestBuilder { method: method.to_string(), target: target.to_string(), headers: Vec::new() }
+    }
+
+    // Host, with the port only when it is not the scheme's default and IPv6
+    // literals in brackets.
+    pub fn host(self, url: &Url) -> RequestBuilder {
+        self.header("Host", &url.host_header())
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> RequestBuilder {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn headers(mut self, headers: impl IntoIterator<Item = (String, String)>) -> RequestBuilder {
+        self.headers.extend(headers);
+        self
+    }
+
+    // The fields that frame a body sent after the head.
+    pub fn body(self, body: &Body) -> RequestBuilder {
+        match body {
+            Body::Empty => self,
+            Body::Sized(_, length) => self.header("Content-Length", &length.to_string()),
+            Body::Chunked(_) => self.header("Transfer-Encoding", "chunked"),
+        }
+    }
+
+    pub fn build(&self) -> String {
+        let mut head = format!("{} {} HTTP/1.1\r\n", self.method, encode_target(&self.target));
+        for (name, value) in &self.headers {
+            // A field without a valid name cannot be sent at all.
+            if is_token(name.trim()) {
+                head.push_str(&format!("{}: {}\r\n", name.trim(), sanitize(value)));
+            }
+        }
+        head.push_str("\r\n");
+        head
+    }
+}
+
+// Methods and field names are tokens (RFC 9110 5.6.2).
+pub fn is_token(value: &str) -> bool {
+    !value.is_empty() && value.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+// CR, LF and NUL inside a value would end the field; they are sent as spaces,
+// as RFC 9110 5.5 has recipients treat them.
+fn sanitize(value: &str) -> String {
+    value.trim().chars().map(|c| if matches!(c, '\r' | '\n' | '\0') { ' ' } else { c }).collect()
+}
+
+// Spaces and control characters would split or end the request line, so they
+// are percent-encoded.
+fn encode_target(target: &str) -> String {
+    let mut encoded = String::with_capacity(target.len());
+    for c in target.chars() {
+        if c == ' ' || c.is_ascii_control() {
+            encoded.push_str(&format!("%{:02X}", c as u8));
+        } else {
+            encoded.push(c);
+        }
+    }
+    encoded
+}
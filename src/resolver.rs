@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL;
+use base64::Engine;
+
+use crate::client::Client;
+use crate::fetch;
+use crate::url::Url;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+
+// Turns host names into addresses for the Connector. IP literals never reach
+// a resolver.
+pub trait Resolver: Send + Sync {
+    fn resolve(&self, host: &str) -> io::Result<Vec<IpAddr>>;
+}
+
+// getaddrinfo, through the standard library.
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        Ok((host, 0).to_socket_addrs()?.map(|addr| addr.ip()).collect())
+    }
+}
+
+// DNS over HTTPS (RFC 8484): A and AAAA queries sent as GET requests in DNS
+// wire format. Answers are cached for their TTL.
+pub struct DohResolver {
+    url: Url,
+    // Connects to the DoH server itself with the system resolver.
+    client: Client,
+    cache: Mutex<HashMap<String, (Vec<IpAddr>, Instant)>>,
+}
+
+impl DohResolver {
+    pub fn new(url: Url, client: Client) -> DohResolver {
+        DohResolver { url, client: client.header("Accept", "application/dns-message"), cache: Mutex::default() }
+    }
+
+    fn query(&self, host: &str, record_type: u16) -> io::Result<Vec<(IpAddr, u32)>> {
+        let separator = if self.url.query.is_some() { '&' } else { '?' };
+        let message = BASE64_URL.encode(dns_query(host, record_type)?);
+        let url = Url::parse(&format!("{}{}dns={}", self.url, separator, message))?;
+        let (_, response) = fetch(&url, &self.client, None)?;
+        parse_dns_answer(&response.body)
+    }
+}
+
+impl Resolver for DohResolver {
+    fn resolve(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        let host = host.to_lowercase();
+        if let Some((addrs, expires)) = self.cache.lock().unwrap().get(&host) {
+            if Instant::now() < *expires {
+                return Ok(addrs.clone());
+            }
+        }
+        // IPv6 first, as getaddrinfo usually orders them; either query may
+        // fail as long as the other answers.
+        let answers: Vec<io::Result<Vec<(IpAddr, u32)>>> =
+            [TYPE_AAAA, TYPE_A].iter().map(|&record_type| self.query(&host, record_type)).collect();
+        let mut records = Vec::new();
+        let mut last_error = None;
+        for answer in answers {
+            match answer {
+                Ok(answer) => records.extend(answer),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        if records.is_empty() {
+            return Err(last_error.unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("DNS over HTTPS found no address for {}", host))
+            }));
+        }
+        let ttl = records.iter().map(|&(_, ttl)| ttl).min().unwrap_or(0);
+        let addrs: Vec<IpAddr> = records.into_iter().map(|(addr, _)| addr).collect();
+        let expires = Instant::now() + Duration::from_secs(u64::from(ttl));
+        self.cache.lock().unwrap().insert(host, (addrs.clone(), expires));
+        Ok(addrs)
+    }
+}
+
+// A recursive query for one record type. The ID is 0, as RFC 8484 suggests,
+// so identical queries can be cached by HTTP caches.
+pub fn dns_query(host: &str, record_type: u16) -> io::Result<Vec<u8>> {
+    let mut message = vec![0, 0, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid host name: {}", host)));
+        }
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    message.extend_from_slice(&record_type.to_be_bytes());
+    message.extend_from_slice(&1u16.to_be_bytes());
+    Ok(message)
+}
+
+// The A and AAAA records in the answer section with their TTLs. CNAMEs are
+// skipped; the server includes the records they lead to.
+pub fn parse_dns_answer(message: &[u8]) -> io::Result<Vec<(IpAddr, u32)>> {
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, "Malformed DNS response");
+    let u16_at = |pos: usize| message.get(pos..pos + 2).map(|b| u16::from_be_bytes([b[0], b[1]])).ok_or_else(malformed);
+    if message.len() < 12 {
+        return Err(malformed());
+    }
+    match message[3] & 0x0f {
+        0 => {}
+        3 => return Ok(Vec::new()),
+        rcode => return Err(io::Error::other(format!("DNS server returned error code {}", rcode))),
+    }
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(message, pos).ok_or_else(malformed)? + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(message, pos).ok_or_else(malformed)?;
+        let record_type = u16_at(pos)?;
+        let ttl = message.get(pos + 4..pos + 8).ok_or_else(malformed)?;
+        let ttl = u32::from_be_bytes([ttl[0], ttl[1], ttl[2], ttl[3]]);
+        let length = usize::from(u16_at(pos + 8)?);
+        let data = message.get(pos + 10..pos + 10 + length).ok_or_else(malformed)?;
+        match (record_type, <[u8; 4]>::try_from(data), <[u8; 16]>::try_from(data)) {
+            (TYPE_A, Ok(octets), _) => records.push((IpAddr::V4(Ipv4Addr::from(octets)), ttl)),
+            (TYPE_AAAA, _, Ok(octets)) => records.push((IpAddr::V6(Ipv6Addr::from(octets)), ttl)),
+            _ => {}
+        }
+        pos += 10 + length;
+    }
+    Ok(records)
+}
+
+// Position just past the (possibly compressed) name starting at `pos`.
+fn skip_name(message: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let length = *message.get(pos)?;
+        match length {
+            0 => return Some(pos + 1),
+            length if length & 0xc0 == 0xc0 => return Some(pos + 2),
+            length => pos += 1 + usize::from(length),
+        }
+    }
+}
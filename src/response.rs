@@ -0,0 +1,96 @@
+use std::io::{self, Chain, Cursor, Read, Take};
+use std::mem;
+
+use crate::chunked;
+use crate::headers::HeaderMap;
+use crate::{parse_status_line, StatusLine};
+
+// A response read off an HTTP/1.1 connection. The body is empty when only
+// the head was read, as for HEAD requests and probes.
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub version: String,
+    pub status: u16,
+    pub reason: String,
+    pub headers: HeaderMap,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    // Parses a head as read_head returns it, status line first.
+    pub fn parse(head: &[u8], body: Vec<u8>) -> io::Result<Response> {
+        let StatusLine { version, code, reason } = parse_status_line(head)?;
+        let fields = head.iter().position(|&b| b == b'\n').map_or(&[][..], |end| &head[end + 1..]);
+        Ok(Response { version, status: code, reason, headers: HeaderMap::parse(fields)?, body })
+    }
+
+    pub fn status_line(&self) -> StatusLine {
+        StatusLine { version: self.version.clone(), code: self.status, reason: self.reason.clone() }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    // Hands the body over to a reader of `stream`, the connection the head
+    // came from, so it can be used as it arrives instead of held whole. The
+    // bytes that came with the head move into the reader and are read first.
+    pub fn body_reader<R: Read>(&mut self, stream: R) -> io::Result<BodyReader<R>> {
+        let buffered = mem::take(&mut self.body);
+        // Transfer-Encoding takes precedence over Content-Length (RFC 9112 6.3).
+        if self.headers.is_chunked() {
+            return Ok(BodyReader::Chunked(chunked::Decoder::new(stream, buffered)));
+        }
+        let body = Cursor::new(buffered).chain(stream);
+        Ok(match self.headers.content_length()? {
+            Some(len) => BodyReader::Sized(body.take(len as u64)),
+            None => BodyReader::Close(body),
+        })
+    }
+}
+
+// A response body read off its connection, ending where the head's framing
+// says or earlier if the connection closes.
+pub enum BodyReader<R> {
+    Chunked(chunked::Decoder<R>),
+    Sized(Take<Chain<Cursor<Vec<u8>>, R>>),
+    // Neither framing is present: the body runs until the server closes the
+    // connection, as multipart/byteranges replies often do.
+    Close(Chain<Cursor<Vec<u8>>, R>),
+}
+
+impl<R: Read> BodyReader<R> {
+    // Once the body has been read: its trailer fields, and the bytes read
+    // past its end, which on a pipelined connection belong to the next
+    // response.
+    pub fn finish(self) -> io::Result<(HeaderMap, Vec<u8>)> {
+        match self {
+            BodyReader::Chunked(decoder) => {
+                let (trailers, excess) = decoder.finish();
+                Ok((HeaderMap::parse(&trailers)?, excess))
+            }
+            BodyReader::Sized(body) => {
+                let (buffered, _) = body.into_inner().into_inner();
+                let position = buffered.position() as usize;
+                Ok((HeaderMap::default(), buffered.into_inner().split_off(position)))
+            }
+            BodyReader::Close(_) => Ok((HeaderMap::default(), Vec::new())),
+        }
+    }
+}
+
+impl<R: Read> Read for BodyReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let result = match self {
+            BodyReader::Chunked(body) => body.read(out),
+            BodyReader::Sized(body) => body.read(out),
+            BodyReader::Close(body) => body.read(out),
+        };
+        match result {
+            // TLS peers that drop the connection without close_notify
+            // truncate the body just like a plain TCP close does.
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+            result => result,
+        }
+    }
+}
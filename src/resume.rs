@@ -0,0 +1,138 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use tracing::info;
+
+use crate::hash::{self, Algorithm};
+use crate::store::FileChunks;
+
+// How long the main thread waits for a chunk before checking on the threads,
+// which is also how often it hashes what is in order in a file; and how often
+// it records the progress.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(20);
+pub const CHECKPOINT_INTERVAL: Duration = Duration::from_millis(500);
+
+// Progress of a download to a file, kept next to it as "<file>.state" so
+// --continue can pick up after an interrupted run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct State {
+    pub url: String,
+    // The If-Range validator: a resumed download must be of the same version.
+    pub validator: String,
+    pub total_size: usize,
+    // Ranges in the file, end exclusive.
+    pub written: Vec<(usize, usize)>,
+    // Hex digest of the first `hashed` bytes. The hasher's internal state
+    // cannot be saved, so a resumed run hashes them again and compares.
+    pub hashed: usize,
+    pub algorithm: Algorithm,
+    pub digest: String,
+}
+
+impl State {
+    pub fn new(url: String, validator: String, total_size: usize, algorithm: Algorithm) -> State {
+        State { url, validator, total_size, written: Vec::new(), hashed: 0, algorithm, digest: String::new() }
+    }
+
+    // The saved state for `output` if it describes the same download as
+    // `fresh` and the file is still there.
+    pub fn load(output: &Path, fresh: &State) -> io::Result<Option<State>> {
+        let text = match fs::read_to_string(state_path(output)) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                info!("No saved state for {}, starting from the beginning", output.display());
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+        let reason = match parse(&text) {
+            None => "the state file is malformed",
+            Some(saved) if !saved.same_download(fresh) => "the resource changed",
+            Some(saved) if saved.algorithm != fresh.algorithm => "it was hashed with another --hash",
+            Some(saved) => match fs::metadata(output) {
+                Ok(metadata) if metadata.len() == saved.total_size as u64 => return Ok(Some(saved)),
+                Ok(_) => "the file does not have the recorded size",
+                Err(e) if e.kind() == io::ErrorKind::NotFound => "the file is missing",
+                Err(e) => return Err(e),
+            },
+        };
+        info!("Cannot resume {}: {}, starting from the beginning", output.display(), reason);
+        Ok(None)
+    }
+
+    fn same_download(&self, other: &State) -> bool {
+        self.url == other.url && self.validator == other.validator && self.total_size == other.total_size
+    }
+
+    // Records what `file` holds and how much of it is hashed, given as the
+    // count and digest, and saves that next to it.
+    pub fn checkpoint(&mut self, file: &FileChunks, (hashed, digest): (u64, Vec<u8>)) -> io::Result<()> {
+        self.written = file.written();
+        self.hashed = hashed as usize;
+        self.digest = hash::hex(&digest);
+        self.save(&state_path(file.path()))
+    }
+
+    // Written to a temporary file and renamed over the old state, so an
+    // interruption never leaves a torn one behind.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let written: Vec<String> = self.written.iter().map(|(start, end)| format!("{}-{}", start, end)).collect();
+        let line = format!(
+            "{}\t{}\t{}\t{}\t{}\t{}:{}\n",
+            self.url,
+            self.validator,
+            self.total_size,
+            written.join(","),
+            self.hashed,
+            self.algorithm.name(),
+            self.digest
+        );
+        fs::write(&temp, line)?;
+        fs::rename(&temp, path)
+    }
+}
+
+// Once the download is complete the state is no longer needed.
+pub fn remove(output: &Path) -> io::Result<()> {
+    match fs::remove_file(state_path(output)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+pub fn state_path(output: &Path) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".state");
+    PathBuf::from(path)
+}
+
+fn parse(text: &str) -> Option<State> {
+    let mut fields = text.trim_end_matches('\n').split('\t');
+    let url = fields.next()?.to_string();
+    let validator = fields.next()?.to_string();
+    let total_size = fields.next()?.parse().ok()?;
+    let mut written = Vec::new();
+    for range in fields.next()?.split(',').filter(|range| !range.is_empty()) {
+        let (start, end) = range.split_once('-')?;
+        let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+        if start >= end || end > total_size {
+            return None;
+        }
+        written.push((start, end));
+    }
+    let hashed = fields.next()?.parse().ok()?;
+    // Older state files hold a bare SHA-256.
+    let field = fields.next()?;
+    let (algorithm, digest) = match field.split_once(':') {
+        Some((name, digest)) => (Algorithm::parse(name).ok()?, digest.to_string()),
+        None => (Algorithm::Sha256, field.to_string()),
+    };
+    match fields.next() {
+        None if hashed <= total_size => Some(State { url, validator, total_size, written, hashed, algorithm, digest }),
+        _ => None,
+    }
+}
@@ -0,0 +1,68 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::headers::HeaderMap;
+
+// Times one request waits out a 429 or 503 before the error is returned.
+pub const MAX_WAITS: usize = 10;
+
+// Longest Retry-After honored; a server asking for more is treated as down.
+pub const MAX_DELAY: Duration = Duration::from_secs(120);
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+// The wait a response's Retry-After asks for. An HTTP-date is measured from
+// the response's Date header when it has one, so a skewed local clock does
+// not matter; a date in the past means retrying at once.
+pub fn delay(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get("retry-after")?;
+    let now = headers.get("date").and_then(parse_http_date).unwrap_or_else(SystemTime::now);
+    retry_after(value, now)
+}
+
+// Parses a Retry-After value: delay-seconds or an HTTP-date.
+pub fn retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) {
+        return Some(Duration::from_secs(value.parse().unwrap_or(u64::MAX)));
+    }
+    Some(parse_http_date(value)?.duration_since(now).unwrap_or(Duration::ZERO))
+}
+
+// Accepts the three HTTP-date formats of RFC 9110 5.6.7:
+// "Sun, 06 Nov 1994 08:49:37 GMT", "Sunday, 06-Nov-94 08:49:37 GMT" and
+// "Sun Nov  6 08:49:37 1994".
+pub fn parse_http_date(value: &str) -> Option<SystemTime> {
+    let mut tokens = value.split(|c: char| c.is_whitespace() || c == '-').filter(|token| !token.is_empty());
+    tokens.next()?;
+    let (day, month, year, time) = match tokens.collect::<Vec<_>>()[..] {
+        [day, month, year, time, "GMT"] => (day, month, year, time),
+        [month, day, time, year] => (day, month, year, time),
+        _ => return None,
+    };
+    let month = MONTHS.iter().position(|&name| name == month)? as i64 + 1;
+    let day: i64 = day.parse().ok().filter(|day| (1..=31).contains(day))?;
+    let mut year: i64 = year.parse().ok()?;
+    // Two-digit RFC 850 years, read as the nearest past century.
+    if year < 100 {
+        year += if year < 70 { 2000 } else { 1900 };
+    }
+    let mut hms = time.split(':').map(|part| part.parse::<i64>().ok());
+    let (Some(Some(hour)), Some(Some(minute)), Some(Some(second)), None) = (hms.next(), hms.next(), hms.next(), hms.next())
+    else {
+        return None;
+    };
+    if hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let seconds = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(seconds).ok()?))
+}
+
+// Days since 1970-01-01 of a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    era * 146097 + year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year - 719468
+}
@@ -0,0 +1,215 @@
+use std::fmt::Write;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::hash::Algorithm;
+use crate::pieces::Piece;
+use crate::progress;
+
+// What one thread did over the download.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Connection {
+    pub thread: usize,
+    pub received: usize,
+    pub requests: usize,
+    // Connections opened for them; fewer than requests when kept alive.
+    pub connects: usize,
+    // Requests that failed, whether retried or given up on.
+    pub failed: usize,
+    pub elapsed: Duration,
+}
+
+impl Connection {
+    // Bytes per second while the thread ran.
+    fn speed(&self) -> f64 {
+        speed(self.received, self.elapsed)
+    }
+}
+
+// Bytes whose requests failed: how often, and the last error.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RangeFailure {
+    pub start: usize,
+    pub end: usize,
+    pub attempts: usize,
+    pub error: String,
+}
+
+// Where a download leaves its Summary for --json.
+pub type SummarySlot = Arc<Mutex<Option<Summary>>>;
+
+// The --stats summary of one download, to tune the thread count and the
+// chunk sizes with, and the --json result.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary {
+    pub url: String,
+    pub total_size: usize,
+    pub connections: Vec<Connection>,
+    // The main thread's own requests: a single stream, or the gaps the
+    // threads left.
+    pub main: Connection,
+    // From the size probe to the final hash.
+    pub elapsed: Duration,
+    // Hex digests of the data, the first algorithm's first.
+    pub digests: Vec<(Algorithm, String)>,
+    // By start, those of the threads before those of the main thread.
+    pub failures: Vec<RangeFailure>,
+    // The --piece-hashes digests, if asked for.
+    pub pieces: Vec<Piece>,
+}
+
+impl Summary {
+    fn all(&self) -> impl Iterator<Item = &Connection> {
+        self.connections.iter().chain(std::iter::once(&self.main))
+    }
+
+    // Bytes received in this run, without those an interrupted run left.
+    fn received(&self) -> usize {
+        self.all().map(|connection| connection.received).sum()
+    }
+
+    fn requests(&self) -> usize {
+        self.all().map(|connection| connection.requests).sum()
+    }
+
+    fn connects(&self) -> usize {
+        self.all().map(|connection| connection.connects).sum()
+    }
+
+    fn failed(&self) -> usize {
+        self.all().map(|connection| connection.failed).sum()
+    }
+
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "Statistics: {} bytes received in {:.2} s ({}/s), {} requests on {} connections, {} retries",
+            self.received(),
+            self.elapsed.as_secs_f64(),
+            progress::bytes(speed(self.received(), self.elapsed)),
+            self.requests(),
+            self.connects(),
+            self.failed()
+        )];
+        for connection in &self.connections {
+            lines.push(format!(
+                "  thread {}: {} bytes, {} requests on {} connections, {} retries, {}/s over {:.2} s",
+                connection.thread,
+                connection.received,
+                connection.requests,
+                connection.connects,
+                connection.failed,
+                progress::bytes(connection.speed()),
+                connection.elapsed.as_secs_f64()
+            ));
+        }
+        if self.main.requests > 0 {
+            lines.push(format!(
+                "  main thread: {} bytes, {} requests on {} connections, {} retries",
+                self.main.received, self.main.requests, self.main.connects, self.main.failed
+            ));
+        }
+        for failure in &self.failures {
+            lines.push(format!(
+                "  bytes {}-{} failed {} times, last: {}",
+                failure.start, failure.end, failure.attempts, failure.error
+            ));
+        }
+        lines
+    }
+
+    // One object with the same figures, speeds in bytes per second.
+    pub fn json(&self) -> String {
+        let fields = |connection: &Connection| {
+            format!(
+                "\"bytes\":{},\"requests\":{},\"connections\":{},\"retries\":{},\"seconds\":{:.3},\"bytes_per_second\":{:.0}",
+                connection.received,
+                connection.requests,
+                connection.connects,
+                connection.failed,
+                connection.elapsed.as_secs_f64(),
+                connection.speed()
+            )
+        };
+        let threads: Vec<_> = self
+            .connections
+            .iter()
+            .map(|connection| format!("{{\"thread\":{},{}}}", connection.thread, fields(connection)))
+            .collect();
+        let digests: Vec<_> =
+            self.digests.iter().map(|(algorithm, digest)| format!("{}:{}", json_string(algorithm.name()), json_string(digest))).collect();
+        let failures: Vec<_> = self
+            .failures
+            .iter()
+            .map(|failure| {
+                format!(
+                    "{{\"start\":{},\"end\":{},\"attempts\":{},\"error\":{}}}",
+                    failure.start,
+                    failure.end,
+                    failure.attempts,
+                    json_string(&failure.error)
+                )
+            })
+            .collect();
+        let pieces: Vec<_> = self
+            .pieces
+            .iter()
+            .map(|piece| format!("{{\"offset\":{},\"length\":{},\"sha256\":{}}}", piece.offset, piece.length, json_string(&piece.sha256)))
+            .collect();
+        format!(
+            "{{\"url\":{},\"total_size\":{},\"bytes\":{},\"requests\":{},\"connections\":{},\"retries\":{},\"seconds\":{:.3},\"bytes_per_second\":{:.0},\"hashes\":{{{}}},\"pieces\":[{}],\"failures\":[{}],\"threads\":[{}],\"main\":{{{}}}}}",
+            json_string(&self.url),
+            self.total_size,
+            self.received(),
+            self.requests(),
+            self.connects(),
+            self.failed(),
+            self.elapsed.as_secs_f64(),
+            speed(self.received(), self.elapsed),
+            digests.join(","),
+            pieces.join(","),
+            failures.join(","),
+            threads.join(","),
+            fields(&self.main)
+        )
+    }
+}
+
+// The --json object for a download of `url` that failed with `error`.
+pub fn failure_json(url: &str, error: &io::Error) -> String {
+    format!("{{\"url\":{},\"error\":{}}}", json_string(url), json_string(&error.to_string()))
+}
+
+// The --json object for a run of several downloads, given theirs.
+pub fn batch_json(downloads: &[String], failed: usize) -> String {
+    format!(
+        "{{\"downloads\":[{}],\"succeeded\":{},\"failed\":{}}}",
+        downloads.join(","),
+        downloads.len() - failed,
+        failed
+    )
+}
+
+fn speed(bytes: usize, elapsed: Duration) -> f64 {
+    if elapsed.is_zero() {
+        0.0
+    } else {
+        bytes as f64 / elapsed.as_secs_f64()
+    }
+}
+
+pub fn json_string(text: &str) -> String {
+    let mut json = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if c < ' ' => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
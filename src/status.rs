@@ -0,0 +1,76 @@
+use std::env;
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use tracing::Level;
+
+// Width assumed for the terminal when COLUMNS does not give it.
+const DEFAULT_COLUMNS: usize = 80;
+
+// Set when stdout carries the downloaded data, so that progress and results
+// go to stderr instead of mixing into it.
+static TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+// Set when messages go to a terminal, where downloads draw a progress line.
+static PROGRESS: AtomicBool = AtomicBool::new(false);
+
+// Whether a progress line is on screen, to be cleared before the next
+// message. Holding it also keeps messages from landing inside the line.
+static DRAWN: Mutex<bool> = Mutex::new(false);
+
+pub fn to_stderr() {
+    TO_STDERR.store(true, Ordering::Relaxed);
+}
+
+// Where the log's lines go: warnings and errors to stderr, the rest to the
+// message stream. A progress line on screen is cleared first.
+pub fn log(level: Level, line: &str) {
+    let mut drawn = DRAWN.lock().unwrap();
+    if std::mem::take(&mut *drawn) {
+        write("\r\x1b[K");
+    }
+    if level > Level::WARN {
+        write(&format!("{}\n", line));
+    } else {
+        let _ = writeln!(io::stderr(), "{}", line);
+    }
+}
+
+// Switches to progress lines if messages go to a terminal.
+pub fn start_progress() {
+    let terminal = if TO_STDERR.load(Ordering::Relaxed) { io::stderr().is_terminal() } else { io::stdout().is_terminal() };
+    PROGRESS.store(terminal, Ordering::Relaxed);
+}
+
+pub fn showing_progress() -> bool {
+    PROGRESS.load(Ordering::Relaxed)
+}
+
+// Redraws the progress line in place, cut to the terminal width so that it
+// never wraps.
+pub fn show_progress(line: &str) {
+    let columns = env::var("COLUMNS").ok().and_then(|columns| columns.parse().ok()).unwrap_or(DEFAULT_COLUMNS);
+    let line: String = line.chars().take(columns.saturating_sub(1)).collect();
+    let mut drawn = DRAWN.lock().unwrap();
+    write(&format!("\r\x1b[K{}", line));
+    *drawn = true;
+}
+
+// Removes the progress line once the download it shows is done.
+pub fn clear_progress() {
+    let mut drawn = DRAWN.lock().unwrap();
+    if std::mem::take(&mut *drawn) {
+        write("\r\x1b[K");
+    }
+}
+
+// A closed stdout or stderr is no reason to stop the download.
+fn write(text: &str) {
+    let _ = if TO_STDERR.load(Ordering::Relaxed) {
+        io::stderr().write_all(text.as_bytes())
+    } else {
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(text.as_bytes()).and_then(|()| stdout.flush())
+    };
+}
@@ -0,0 +1,559 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use tracing::trace;
+
+use crate::{pool, process_chunks};
+
+// Largest range one request asks for when chunks go to the output file, so
+// memory holds a bounded amount per thread and progress can be saved while a
+// thread's range is still coming in.
+pub const PIECE_SIZE: usize = 1024 * 1024;
+
+// How far past the hashing position chunks held in memory may reach before
+// the threads that bring them wait, unless --max-buffer says otherwise.
+pub const DEFAULT_BUFFER_LIMIT: usize = 64 * 1024 * 1024;
+
+// How much of the file the main thread reads back at a time for hashing.
+const READ_SIZE: usize = 64 * 1024;
+
+// Tells apart the spill files of downloads running at the same time.
+static SPILL_FILES: AtomicUsize = AtomicUsize::new(0);
+
+// Where the threads put what they download, by offset, until the main thread
+// hashes it in order.
+pub enum Chunks {
+    // Held in memory: nothing is saved, or the file gets decoded bytes at
+    // offsets the ranges do not address.
+    Memory(MemoryChunks),
+    // Written at their offset in the output file, which is preallocated to
+    // the full size.
+    File(FileChunks),
+}
+
+// The threads send each chunk to the main thread as it arrives, and the main
+// thread, which hashes, waits for them in its Inbox rather than polling a
+// shared map.
+pub struct MemoryChunks {
+    sender: Sender<(usize, Vec<u8>)>,
+    // Behind one slow range, the others would pile up in the inbox up to the
+    // whole file; a thread whose chunk ends more than `limit` bytes past the
+    // hashing position waits for it to move on instead.
+    limit: usize,
+    window: Mutex<Window>,
+    advanced: Condvar,
+}
+
+// The main thread's own end of the memory store: it alone receives the
+// chunks and keeps those not in order yet, so no lock guards them.
+#[derive(Default)]
+pub struct Inbox {
+    // None for the file store, whose chunks are not sent anywhere.
+    receiver: Option<Receiver<(usize, Vec<u8>)>>,
+    buffer: BTreeMap<usize, Vec<u8>>,
+    // With --spill, what does not fit in memory.
+    spill: Option<Spill>,
+    // The hashing position as of the last drain.
+    hashed: usize,
+}
+
+// Chunks moved out of the inbox to a temporary file, furthest ahead first,
+// to keep it under `limit` bytes. Each comes back once hashing reaches it.
+struct Spill {
+    limit: usize,
+    // Created with the first chunk that does not fit, and removed on drop.
+    file: Option<(PathBuf, File)>,
+    // Offset in the download to position and length in the file.
+    chunks: BTreeMap<usize, (u64, usize)>,
+    // Where the next chunk goes; back to the start once the file is empty.
+    end: u64,
+}
+
+struct Window {
+    hashed: usize,
+    // Set once waiting could no longer end, e.g. because the thread with the
+    // next range has stopped.
+    released: bool,
+}
+
+pub struct FileChunks {
+    path: PathBuf,
+    file: File,
+    total_size: usize,
+    progress: Mutex<Progress>,
+    // With --mmap, chunks are copied into a shared mapping of the file and
+    // hashed straight from it.
+    #[cfg(all(unix, feature = "mmap"))]
+    mapping: Option<Mapping>,
+}
+
+struct Progress {
+    // Written ranges, start to end (exclusive), merged.
+    ranges: BTreeMap<usize, usize>,
+    // Everything below has been handed to the hasher; later writes there
+    // are dropped.
+    hashed: usize,
+}
+
+impl Chunks {
+    // `limit` bounds how far ahead of the hashing position chunks are held.
+    pub fn memory(limit: usize) -> (Chunks, Inbox) {
+        let (sender, receiver) = mpsc::channel();
+        let chunks = Chunks::Memory(MemoryChunks {
+            sender,
+            limit,
+            window: Mutex::new(Window { hashed: 0, released: false }),
+            advanced: Condvar::new(),
+        });
+        (chunks, Inbox { receiver: Some(receiver), ..Inbox::default() })
+    }
+
+    // Opens `path` for a download of `total_size` bytes. `written` are ranges
+    // an interrupted run left in the file; without them it starts out empty.
+    pub fn file(path: &Path, total_size: usize, written: &[(usize, usize)]) -> io::Result<Chunks> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("Cannot create {}: {}", path.display(), e)))?;
+        if written.is_empty() {
+            file.set_len(0)?;
+        }
+        file.set_len(total_size as u64)?;
+        let mut progress = Progress { ranges: BTreeMap::new(), hashed: 0 };
+        for &(start, end) in written {
+            progress.add(start, end);
+        }
+        Ok(Chunks::File(FileChunks {
+            path: path.to_path_buf(),
+            file,
+            total_size,
+            progress: Mutex::new(progress),
+            #[cfg(all(unix, feature = "mmap"))]
+            mapping: None,
+        }))
+    }
+
+    // Like `file`, with the file mapped into memory for --mmap.
+    #[cfg(all(unix, feature = "mmap"))]
+    pub fn mapped(path: &Path, total_size: usize, written: &[(usize, usize)]) -> io::Result<Chunks> {
+        let mut chunks = Chunks::file(path, total_size, written)?;
+        if let Chunks::File(file) = &mut chunks {
+            // An empty file cannot be mapped, and has nothing to write.
+            if total_size > 0 {
+                file.mapping = Some(Mapping::new(&file.file, total_size)?);
+            }
+        }
+        Ok(chunks)
+    }
+
+    pub fn insert(&self, start: usize, chunk: Vec<u8>) -> io::Result<()> {
+        match self {
+            Chunks::Memory(memory) => memory.insert(start, chunk),
+            Chunks::File(file) => {
+                file.insert(start, &chunk)?;
+                pool::POOL.give(chunk);
+                Ok(())
+            }
+        }
+    }
+
+    // Hashes whatever is in order from `bytes_hashed` on.
+    pub fn drain<W: Write>(
+        &self,
+        inbox: &mut Inbox,
+        output: &mut W,
+        bytes_hashed: &mut usize,
+        total_size: usize,
+    ) -> io::Result<()> {
+        match self {
+            Chunks::Memory(memory) => {
+                let result = inbox.drain(output, bytes_hashed, total_size);
+                memory.advance(*bytes_hashed);
+                result
+            }
+            Chunks::File(file) => file.drain(output, bytes_hashed, total_size),
+        }
+    }
+
+    // Stops holding threads back for --max-buffer.
+    pub fn release(&self) {
+        if let Chunks::Memory(memory) = self {
+            memory.window.lock().unwrap().released = true;
+            memory.advanced.notify_all();
+        }
+    }
+
+    // The parts of start..=end still to download, inclusive like the
+    // threads' ranges.
+    pub fn missing(&self, start: usize, end: usize) -> Vec<(usize, usize)> {
+        match self {
+            Chunks::Memory(_) => vec![(start, end)],
+            Chunks::File(file) => file.missing(start, end),
+        }
+    }
+
+    // The last byte of the gap at `start`, the hashing position: just before
+    // the next chunk that has arrived, or the end of the file.
+    pub fn gap_end(&self, inbox: &Inbox, start: usize, total_size: usize) -> usize {
+        match self {
+            Chunks::Memory(_) => inbox.next_chunk(start).unwrap_or(total_size) - 1,
+            Chunks::File(file) => file.missing(start, total_size - 1).first().map_or(total_size - 1, |&(_, end)| end),
+        }
+    }
+
+    pub fn piece_size(&self) -> usize {
+        match self {
+            Chunks::Memory(_) => usize::MAX,
+            Chunks::File(_) => PIECE_SIZE,
+        }
+    }
+}
+
+impl MemoryChunks {
+    // A chunk that starts at or before the hashing position is always taken,
+    // so the position can always move.
+    fn insert(&self, start: usize, chunk: Vec<u8>) -> io::Result<()> {
+        let mut window = self.window.lock().unwrap();
+        while !window.released && start > window.hashed && start + chunk.len() > window.hashed.saturating_add(self.limit) {
+            window = self.advanced.wait(window).unwrap();
+        }
+        drop(window);
+        self.sender.send((start, chunk)).map_err(|e| io::Error::other(e.to_string()))
+    }
+
+    // Lets through the threads the new hashing position makes room for.
+    fn advance(&self, hashed: usize) {
+        let mut window = self.window.lock().unwrap();
+        if hashed > window.hashed {
+            window.hashed = hashed;
+            self.advanced.notify_all();
+        }
+    }
+}
+
+impl Inbox {
+    // Holds at most `limit` bytes of chunks in memory, spilling the rest to
+    // a temporary file.
+    pub fn spill_over(mut self, limit: Option<usize>) -> Inbox {
+        self.spill = limit.map(|limit| Spill { limit, file: None, chunks: BTreeMap::new(), end: 0 });
+        self
+    }
+
+    // Returns once a chunk has arrived, or after `timeout`. Chunks in the
+    // file are not announced, so that just sleeps.
+    pub fn wait(&mut self, timeout: Duration) -> io::Result<()> {
+        match &self.receiver {
+            Some(receiver) => {
+                if let Ok((start, chunk)) = receiver.recv_timeout(timeout) {
+                    self.keep(start, chunk)?;
+                }
+            }
+            None => thread::sleep(timeout),
+        }
+        Ok(())
+    }
+
+    // Hashes what is in order, bringing back spilled chunks as the hashing
+    // position reaches them.
+    fn drain<W: Write>(&mut self, output: &mut W, bytes_hashed: &mut usize, total_size: usize) -> io::Result<()> {
+        let received: Vec<_> = self.receiver.iter().flat_map(Receiver::try_iter).collect();
+        for (start, chunk) in received {
+            self.keep(start, chunk)?;
+        }
+        loop {
+            process_chunks(output, bytes_hashed, &mut self.buffer, total_size)?;
+            self.hashed = *bytes_hashed;
+            let loaded = match &mut self.spill {
+                Some(spill) => spill.load(self.hashed, &mut self.buffer)?,
+                None => false,
+            };
+            if !loaded {
+                return Ok(());
+            }
+        }
+    }
+
+    fn keep(&mut self, start: usize, chunk: Vec<u8>) -> io::Result<()> {
+        self.buffer.insert(start, chunk);
+        let Some(spill) = &mut self.spill else { return Ok(()) };
+        let mut held: usize = self.buffer.values().map(Vec::len).sum();
+        while held > spill.limit {
+            // A chunk at the hashing position stays, as it is hashed next.
+            match self.buffer.last_entry() {
+                Some(last) if *last.key() > self.hashed => {
+                    let (start, chunk) = last.remove_entry();
+                    held -= chunk.len();
+                    spill.write(start, &chunk)?;
+                    pool::POOL.give(chunk);
+                }
+                _ => break,
+            }
+        }
+        Ok(())
+    }
+
+    // Where the first chunk at or after `start` begins, in memory or spilled.
+    fn next_chunk(&self, start: usize) -> Option<usize> {
+        let spilled = self.spill.as_ref().and_then(|spill| spill.chunks.range(start..).next());
+        [self.buffer.range(start..).next().map(|(&next, _)| next), spilled.map(|(&next, _)| next)].into_iter().flatten().min()
+    }
+}
+
+impl Spill {
+    fn write(&mut self, start: usize, chunk: &[u8]) -> io::Result<()> {
+        if self.file.is_none() {
+            let name = format!("http_client_{}_{}.spill", std::process::id(), SPILL_FILES.fetch_add(1, Ordering::Relaxed));
+            let path = std::env::temp_dir().join(name);
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)
+                .map_err(|e| io::Error::new(e.kind(), format!("Cannot create {}: {}", path.display(), e)))?;
+            self.file = Some((path, file));
+        }
+        let Some((path, file)) = &self.file else { unreachable!() };
+        write_at(file, chunk, self.end)?;
+        self.chunks.insert(start, (self.end, chunk.len()));
+        self.end += chunk.len() as u64;
+        trace!("Spilled chunk starting at {}, size {}, to {}", start, chunk.len(), path.display());
+        Ok(())
+    }
+
+    // Moves the chunks that start at or before `hashed` back into `buffer`;
+    // false if there were none.
+    fn load(&mut self, hashed: usize, buffer: &mut BTreeMap<usize, Vec<u8>>) -> io::Result<bool> {
+        let Some((_, file)) = &self.file else { return Ok(false) };
+        let mut loaded = false;
+        while let Some(entry) = self.chunks.first_entry().filter(|entry| *entry.key() <= hashed) {
+            let (start, (position, len)) = entry.remove_entry();
+            let mut chunk = pool::POOL.take();
+            chunk.resize(len, 0);
+            read_at(file, &mut chunk, position)?;
+            buffer.insert(start, chunk);
+            loaded = true;
+        }
+        if self.chunks.is_empty() && self.end > 0 {
+            file.set_len(0)?;
+            self.end = 0;
+        }
+        Ok(loaded)
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        if let Some((path, _)) = &self.file {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+impl FileChunks {
+    fn insert(&self, start: usize, chunk: &[u8]) -> io::Result<()> {
+        if start + chunk.len() > self.total_size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Chunk exceeds total size"));
+        }
+        let mut progress = self.progress.lock().unwrap();
+        let skip = progress.hashed.saturating_sub(start).min(chunk.len());
+        if skip == chunk.len() {
+            return Ok(());
+        }
+        #[cfg(all(unix, feature = "mmap"))]
+        if let Some(mapping) = &self.mapping {
+            mapping.write(start + skip, &chunk[skip..]);
+            progress.add(start + skip, start + chunk.len());
+            return Ok(());
+        }
+        write_at(&self.file, &chunk[skip..], (start + skip) as u64)?;
+        progress.add(start + skip, start + chunk.len());
+        Ok(())
+    }
+
+    // Reads back and hashes the written range starting at `bytes_hashed`, up
+    // to `limit`.
+    pub fn drain<W: Write>(&self, output: &mut W, bytes_hashed: &mut usize, limit: usize) -> io::Result<()> {
+        let end = {
+            let mut progress = self.progress.lock().unwrap();
+            let end = match progress.ranges.range(..=*bytes_hashed).next_back() {
+                Some((_, &end)) if end > *bytes_hashed => end.min(limit),
+                _ => return Ok(()),
+            };
+            progress.hashed = end;
+            end
+        };
+        #[cfg(all(unix, feature = "mmap"))]
+        if let Some(mapping) = &self.mapping {
+            output.write_all(mapping.read(*bytes_hashed, end))?;
+            *bytes_hashed = end;
+            trace!("Hashed up to {} from the mapping of {}", end, self.path.display());
+            return Ok(());
+        }
+        let mut buffer = vec![0; READ_SIZE.min(end - *bytes_hashed)];
+        while *bytes_hashed < end {
+            let len = buffer.len().min(end - *bytes_hashed);
+            read_at(&self.file, &mut buffer[..len], *bytes_hashed as u64)?;
+            output.write_all(&buffer[..len])?;
+            *bytes_hashed += len;
+        }
+        trace!("Hashed up to {} from {}", end, self.path.display());
+        Ok(())
+    }
+
+    fn missing(&self, start: usize, end: usize) -> Vec<(usize, usize)> {
+        let progress = self.progress.lock().unwrap();
+        let mut missing = Vec::new();
+        let mut next = start;
+        for (&written_start, &written_end) in &progress.ranges {
+            if written_end <= next {
+                continue;
+            }
+            if written_start > end {
+                break;
+            }
+            if written_start > next {
+                missing.push((next, written_start - 1));
+            }
+            next = written_end;
+        }
+        if next <= end {
+            missing.push((next, end));
+        }
+        missing
+    }
+
+    // The written ranges, for the state file.
+    pub fn written(&self) -> Vec<(usize, usize)> {
+        self.progress.lock().unwrap().ranges.iter().map(|(&start, &end)| (start, end)).collect()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Progress {
+    // Records start..end as written, merging it with the ranges it touches.
+    fn add(&mut self, mut start: usize, mut end: usize) {
+        if let Some((&before, &before_end)) = self.ranges.range(..=start).next_back() {
+            if before_end >= start {
+                start = before;
+                end = end.max(before_end);
+            }
+        }
+        let touching: Vec<usize> = self.ranges.range(start..=end).map(|(&key, _)| key).collect();
+        for key in touching {
+            end = end.max(self.ranges.remove(&key).unwrap_or(end));
+        }
+        self.ranges.insert(start, end);
+    }
+}
+
+#[cfg(unix)]
+fn write_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, offset)
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn write_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match std::os::windows::fs::FileExt::seek_write(file, buf, offset)? {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            written => {
+                buf = &buf[written..];
+                offset += written as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match std::os::windows::fs::FileExt::seek_read(file, buf, offset)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            read => {
+                buf = &mut buf[read..];
+                offset += read as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+// A read-write shared mapping of the whole output file. Threads copy into
+// disjoint ranges while holding the progress lock, and the main thread only
+// reads ranges that are written and below `Progress::hashed`, which no later
+// write touches.
+#[cfg(all(unix, feature = "mmap"))]
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+#[cfg(all(unix, feature = "mmap"))]
+unsafe impl Send for Mapping {}
+#[cfg(all(unix, feature = "mmap"))]
+unsafe impl Sync for Mapping {}
+
+#[cfg(all(unix, feature = "mmap"))]
+impl Mapping {
+    fn new(file: &File, len: usize) -> io::Result<Mapping> {
+        use std::os::unix::io::AsRawFd;
+        // SAFETY: maps `len` bytes of a file that was just extended to that
+        // size; the result is checked before use.
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping { ptr: ptr.cast(), len })
+    }
+
+    fn write(&self, offset: usize, buf: &[u8]) {
+        assert!(offset + buf.len() <= self.len);
+        // SAFETY: in bounds, and callers serialize writes and never write
+        // where the main thread reads.
+        unsafe { std::ptr::copy_nonoverlapping(buf.as_ptr(), self.ptr.add(offset), buf.len()) }
+    }
+
+    fn read(&self, start: usize, end: usize) -> &[u8] {
+        assert!(start <= end && end <= self.len);
+        // SAFETY: in bounds, and no write reaches a range once it is hashed.
+        unsafe { std::slice::from_raw_parts(self.ptr.add(start), end - start) }
+    }
+}
+
+#[cfg(all(unix, feature = "mmap"))]
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: unmaps exactly what `new` mapped.
+        unsafe {
+            libc::munmap(self.ptr.cast(), self.len);
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::io::{self, Write};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::task::Waker;
+use std::thread::{self, JoinHandle};
+
+use bytes::Bytes;
+
+// Writes the download may have waiting for the reader before it holds back.
+const DEPTH: usize = 16;
+
+// The download's end of a ByteStream, which its Sink writes the ordered,
+// decoded bytes to. A write blocks while the reader is DEPTH writes behind,
+// and fails once the stream has been dropped, which ends the download.
+#[derive(Clone)]
+pub struct Sender {
+    // Only None while being dropped.
+    data: Option<SyncSender<io::Result<Bytes>>>,
+    waker: Arc<Mutex<Option<Waker>>>,
+}
+
+// A download's data in order as it is hashed, for an embedder to use as it
+// arrives rather than only getting the final digest. It ends after the last
+// byte, or with the error that stopped the download.
+pub struct ByteStream {
+    data: Receiver<io::Result<Bytes>>,
+    #[cfg(feature = "async")]
+    waker: Arc<Mutex<Option<Waker>>>,
+    download: JoinHandle<io::Result<String>>,
+}
+
+impl ByteStream {
+    // Runs `download` on a thread of its own, which gets the Sender to pass
+    // on to its Sink and returns the hex digest.
+    pub fn spawn(download: impl FnOnce(Sender) -> io::Result<String> + Send + 'static) -> ByteStream {
+        let (data, receiver) = mpsc::sync_channel(DEPTH);
+        let sender = Sender { data: Some(data), waker: Arc::new(Mutex::new(None)) };
+        #[cfg(feature = "async")]
+        let waker = Arc::clone(&sender.waker);
+        let span = tracing::Span::current();
+        let download = thread::spawn(move || {
+            let _download = span.entered();
+            let result = download(sender.clone());
+            if let Err(e) = result {
+                // The stream gets the error itself; finish() a copy.
+                let copy = io::Error::new(e.kind(), e.to_string());
+                sender.send(Err(e));
+                return Err(copy);
+            }
+            result
+        });
+        ByteStream {
+            data: receiver,
+            #[cfg(feature = "async")]
+            waker,
+            download,
+        }
+    }
+
+    // The hex digest of the data once the download is over, or why it
+    // failed. Stops it first if the stream was not read to the end.
+    pub fn finish(self) -> io::Result<String> {
+        drop(self.data);
+        self.download.join().unwrap_or_else(|_| Err(io::Error::other("the download thread panicked")))
+    }
+}
+
+impl Iterator for ByteStream {
+    type Item = io::Result<Bytes>;
+
+    fn next(&mut self) -> Option<io::Result<Bytes>> {
+        self.data.recv().ok()
+    }
+}
+
+// With --async the bytes can be awaited instead. The download still runs on
+// its own thread, which wakes the task polling the stream as it sends.
+#[cfg(feature = "async")]
+impl futures_core::Stream for ByteStream {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Option<Self::Item>> {
+        use std::sync::mpsc::TryRecvError;
+        use std::task::Poll;
+
+        // Checked again once the waker is in place, so a send in between is
+        // not missed.
+        for registered in [false, true] {
+            match self.data.try_recv() {
+                Ok(item) => return Poll::Ready(Some(item)),
+                Err(TryRecvError::Disconnected) => return Poll::Ready(None),
+                Err(TryRecvError::Empty) if !registered => *self.waker.lock().unwrap() = Some(cx.waker().clone()),
+                Err(TryRecvError::Empty) => {}
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl Sender {
+    // False once the stream has been dropped.
+    fn send(&self, item: io::Result<Bytes>) -> bool {
+        let sent = self.data.as_ref().is_some_and(|data| data.send(item).is_ok());
+        self.wake();
+        sent
+    }
+
+    fn wake(&self) {
+        if let Some(waker) = self.waker.lock().unwrap().take() {
+            waker.wake();
+        }
+    }
+}
+
+impl Write for Sender {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.send(Ok(Bytes::copy_from_slice(buf))) {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "the byte stream was dropped"));
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// The last Sender going away ends the stream, which a waiting task must hear
+// once it has.
+impl Drop for Sender {
+    fn drop(&mut self) {
+        self.data.take();
+        self.wake();
+    }
+}
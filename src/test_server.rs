@@ -0,0 +1,229 @@
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::ops::Range;
+use std::thread;
+use std::time::Duration;
+
+use crate::headers::HeaderMap;
+use crate::read_head;
+use crate::url::Url;
+
+// The same bytes each time for a given length, and not periodic at any power
+// of two, so a chunk put at the wrong offset changes the digest.
+pub fn payload(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+// An HTTP/1.1 server on a loopback port for tests to download from over real
+// sockets: HEAD and GET of one resource, single byte ranges answered with 206
+// and Content-Range, If-Range honoured against its ETag, and connections kept
+// alive until the client closes them. Each connection gets a thread; the
+// server stops when dropped.
+pub struct TestServer {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<String>>>,
+    stopped: Arc<AtomicBool>,
+}
+
+#[derive(Clone)]
+pub struct TestServerBuilder {
+    data: Arc<Vec<u8>>,
+    ranges: bool,
+    etag: String,
+    // Where replies go wrong: the first byte they start at, how, and how
+    // many more times.
+    faults: Arc<Mutex<Vec<(usize, Fault, usize)>>>,
+}
+
+// A way for a GET's reply to go wrong, so the retry and repair paths can be
+// driven on purpose.
+#[derive(Debug, Clone, Copy)]
+pub enum Fault {
+    // The connection closes halfway through the body.
+    Disconnect,
+    // A well-framed 206 with only the first half of the range.
+    Truncate,
+    // The bytes and Content-Range of the range one byte further on.
+    WrongRange,
+    // This status with an empty body.
+    Status(u16),
+    // The right bytes, a few at a time with this pause after each.
+    Trickle(Duration),
+}
+
+struct Reply {
+    status: String,
+    fields: String,
+    // The slice of the data sent, whose start is where faults are keyed.
+    body: Range<usize>,
+}
+
+// How much a trickle sends at a time.
+const TRICKLE_SIZE: usize = 1024;
+
+impl TestServer {
+    // Serves `data` at every path.
+    pub fn builder(data: Vec<u8>) -> TestServerBuilder {
+        TestServerBuilder { data: Arc::new(data), ranges: true, etag: "\"v1\"".to_string(), faults: Arc::default() }
+    }
+
+    pub fn url(&self, path: &str) -> Url {
+        Url::parse(&format!("http://{}{}", self.addr, path)).unwrap()
+    }
+
+    // The request lines received so far, e.g. "GET /file HTTP/1.1", with the
+    // Range asked for after them.
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl TestServerBuilder {
+    // Ignores Range and sends the whole resource, without Accept-Ranges.
+    pub fn without_ranges(mut self) -> TestServerBuilder {
+        self.ranges = false;
+        self
+    }
+
+    pub fn etag(mut self, etag: &str) -> TestServerBuilder {
+        self.etag = etag.to_string();
+        self
+    }
+
+    // Makes the next `times` replies starting at byte `at` go wrong with
+    // `fault`; a GET without a Range starts at 0.
+    pub fn fault(self, at: usize, fault: Fault, times: usize) -> TestServerBuilder {
+        self.faults.lock().unwrap().push((at, fault, times));
+        self
+    }
+
+    pub fn start(self) -> io::Result<TestServer> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let (log, stop) = (Arc::clone(&requests), Arc::clone(&stopped));
+        thread::spawn(move || {
+            for socket in listener.incoming() {
+                if stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                let Ok(socket) = socket else { continue };
+                let (server, log) = (self.clone(), Arc::clone(&log));
+                thread::spawn(move || server.serve(socket, &log));
+            }
+        });
+        Ok(TestServer { addr, requests, stopped })
+    }
+
+    fn serve(&self, mut socket: TcpStream, log: &Mutex<Vec<String>>) {
+        while let Ok((head, _)) = read_head(&mut socket) {
+            let head = String::from_utf8_lossy(&head).into_owned();
+            let (line, fields) = head.split_once("\r\n").unwrap_or((&head, ""));
+            let headers = HeaderMap::parse(fields.as_bytes()).unwrap_or_default();
+            let range = headers.get("range").filter(|_| self.ranges);
+            log.lock().unwrap().push(match range {
+                Some(range) => format!("{} {}", line, range),
+                None => line.to_string(),
+            });
+            let head_only = line.starts_with("HEAD ");
+            let reply = self.respond(range, headers.get("if-range"));
+            let fault = if head_only { None } else { self.take_fault(reply.body.start) };
+            let close = headers.get_joined("connection").is_some_and(|value| value.eq_ignore_ascii_case("close"));
+            match self.send(&mut socket, reply, head_only, fault) {
+                Ok(true) if !close => {}
+                _ => break,
+            }
+        }
+    }
+
+    fn respond(&self, range: Option<&str>, if_range: Option<&str>) -> Reply {
+        let total = self.data.len();
+        // A validator other than the current one asks for the whole resource.
+        let range = range.filter(|_| if_range.is_none_or(|validator| validator == self.etag));
+        let mut fields = format!("ETag: {}\r\n", self.etag);
+        if self.ranges {
+            fields.push_str("Accept-Ranges: bytes\r\n");
+        }
+        match range.map(|range| parse_range(range, total)) {
+            None => Reply { status: "200 OK".to_string(), fields, body: 0..total },
+            Some(Some((first, last))) => Reply { status: "206 Partial Content".to_string(), fields, body: first..last + 1 },
+            Some(None) => {
+                fields.push_str(&format!("Content-Range: bytes */{}\r\n", total));
+                Reply { status: "416 Range Not Satisfiable".to_string(), fields, body: 0..0 }
+            }
+        }
+    }
+
+    // The first fault left for replies starting at `start`, used up once.
+    fn take_fault(&self, start: usize) -> Option<Fault> {
+        let mut faults = self.faults.lock().unwrap();
+        let (_, fault, times) = faults.iter_mut().find(|(at, _, times)| *at == start && *times > 0)?;
+        *times -= 1;
+        Some(*fault)
+    }
+
+    // Writes `reply`, as `fault` has it go wrong; false once the connection
+    // is to be closed.
+    fn send(&self, socket: &mut TcpStream, mut reply: Reply, head_only: bool, fault: Option<Fault>) -> io::Result<bool> {
+        let half = reply.body.start + reply.body.len() / 2;
+        match fault {
+            Some(Fault::Status(code)) => {
+                reply = Reply { status: format!("{} Injected Fault", code), fields: String::new(), body: 0..0 };
+            }
+            Some(Fault::Truncate) => reply.body.end = half.max(reply.body.start + 1).min(reply.body.end),
+            Some(Fault::WrongRange) => reply.body = reply.body.start + 1..(reply.body.end + 1).min(self.data.len()),
+            _ => {}
+        }
+        let partial = reply.status.starts_with("206");
+        if partial {
+            let (first, last) = (reply.body.start, reply.body.end.saturating_sub(1));
+            reply.fields.push_str(&format!("Content-Range: bytes {}-{}/{}\r\n", first, last, self.data.len()));
+        }
+        let head = format!("HTTP/1.1 {}\r\n{}Content-Length: {}\r\n\r\n", reply.status, reply.fields, reply.body.len());
+        socket.write_all(head.as_bytes())?;
+        if head_only {
+            return Ok(true);
+        }
+        let body = &self.data[reply.body.clone()];
+        match fault {
+            Some(Fault::Disconnect) => {
+                socket.write_all(&body[..body.len() / 2])?;
+                Ok(false)
+            }
+            Some(Fault::Trickle(pause)) => {
+                for piece in body.chunks(TRICKLE_SIZE) {
+                    socket.write_all(piece)?;
+                    thread::sleep(pause);
+                }
+                Ok(true)
+            }
+            _ => {
+                socket.write_all(body)?;
+                Ok(true)
+            }
+        }
+    }
+}
+
+// "bytes=first-last" or "bytes=first-", cut to the resource; None when it
+// starts past the end or is not one range of that form.
+fn parse_range(range: &str, total: usize) -> Option<(usize, usize)> {
+    let (first, last) = range.strip_prefix("bytes=")?.split_once('-')?;
+    let first: usize = first.parse().ok()?;
+    let last = match last {
+        "" => total.checked_sub(1)?,
+        last => last.parse::<usize>().ok()?.min(total.checked_sub(1)?),
+    };
+    (first <= last).then_some((first, last))
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wakes the accept loop so it sees the flag.
+        let _ = TcpStream::connect(self.addr);
+    }
+}
@@ -0,0 +1,182 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+
+use crate::cancel::CancellationToken;
+use crate::connector::{Connector, Stream};
+use crate::url::Url;
+
+// How often a read waiting on the server wakes up to see whether the
+// download was cancelled.
+const CANCEL_POLL: Duration = Duration::from_millis(100);
+
+// A byte stream to a server that requests are written to and responses read
+// from: plain TCP, a Unix socket, TLS over either, or a mock in the tests.
+pub trait Connection: Read + Write {
+    // Closes both directions, so the server stops sending what is not read.
+    fn shutdown(&mut self) -> io::Result<()>;
+
+    // Has a read that waits longer than `timeout` fail with WouldBlock or
+    // TimedOut. Connections that never wait, like the tests' mocks, ignore it.
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Opens connections to the origin of a URL. The Connector is the real one,
+// going through proxies, the resolver and TLS as configured.
+pub trait Transport {
+    type Connection: Connection;
+
+    fn connect(&self, url: &Url) -> io::Result<Self::Connection>;
+
+    // How long a read on its connections may wait; None for ever.
+    fn read_timeout(&self) -> Option<Duration> {
+        None
+    }
+}
+
+impl Transport for Connector {
+    type Connection = Stream;
+
+    fn connect(&self, url: &Url) -> io::Result<Stream> {
+        Connector::connect(self, url)
+    }
+
+    fn read_timeout(&self) -> Option<Duration> {
+        Connector::read_timeout(self)
+    }
+}
+
+// Another transport whose connections fail with DownloadError::Cancelled
+// once `token` is cancelled, checked before connecting and before each read
+// and write. A read waiting on the server wakes up every CANCEL_POLL to check
+// it too, and keeps waiting up to the transport's own read timeout.
+pub struct Cancellable<'a, T> {
+    transport: &'a T,
+    token: &'a CancellationToken,
+}
+
+impl<'a, T: Transport> Cancellable<'a, T> {
+    pub fn new(transport: &'a T, token: &'a CancellationToken) -> Cancellable<'a, T> {
+        Cancellable { transport, token }
+    }
+}
+
+impl<T: Transport> Transport for Cancellable<'_, T> {
+    type Connection = CancellableConnection<T::Connection>;
+
+    fn connect(&self, url: &Url) -> io::Result<Self::Connection> {
+        self.token.check("connecting")?;
+        let connection = self.transport.connect(url)?;
+        let read_timeout = self.transport.read_timeout();
+        connection.set_read_timeout(Some(read_timeout.map_or(CANCEL_POLL, |timeout| timeout.min(CANCEL_POLL))))?;
+        Ok(CancellableConnection { connection, token: self.token.clone(), read_timeout })
+    }
+}
+
+pub struct CancellableConnection<C> {
+    connection: C,
+    token: CancellationToken,
+    read_timeout: Option<Duration>,
+}
+
+impl<C: Connection> CancellableConnection<C> {
+    // Retries `io` each time the connection's short read timeout runs out,
+    // until the token is cancelled or the transport's read timeout is over.
+    // TLS reads the handshake while writing, so writes wait the same way.
+    fn waiting<R>(&mut self, what: &str, mut io: impl FnMut(&mut C) -> io::Result<R>) -> io::Result<R> {
+        let started = Instant::now();
+        loop {
+            self.token.check(what)?;
+            match io(&mut self.connection) {
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                    if let Some(timeout) = self.read_timeout.filter(|&timeout| started.elapsed() >= timeout) {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!("No data from the server for {} s while {}", timeout.as_secs_f64(), what),
+                        ));
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<C: Connection> Read for CancellableConnection<C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.waiting("reading the response", |connection| connection.read(buf))
+    }
+}
+
+impl<C: Connection> Write for CancellableConnection<C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.waiting("sending the request", |connection| connection.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.connection.flush()
+    }
+}
+
+impl<C: Connection> Connection for CancellableConnection<C> {
+    fn shutdown(&mut self) -> io::Result<()> {
+        self.connection.shutdown()
+    }
+}
+
+impl Connection for TcpStream {
+    fn shutdown(&mut self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn shutdown(&mut self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+// Sends close_notify first, so the server can tell the close from a
+// truncation.
+#[cfg(feature = "tls")]
+impl Connection for rustls::StreamOwned<rustls::ClientConnection, TcpStream> {
+    fn shutdown(&mut self) -> io::Result<()> {
+        self.conn.send_close_notify();
+        self.flush()?;
+        self.sock.shutdown(Shutdown::Both)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+}
+
+impl Connection for Stream {
+    fn shutdown(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => Connection::shutdown(stream),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Connection::shutdown(stream),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => Connection::shutdown(stream.as_mut()),
+            Stream::Cassette(stream) => stream.shutdown(),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        Stream::set_read_timeout(self, timeout)
+    }
+}
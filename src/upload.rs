@@ -0,0 +1,143 @@
+use std::io::{self, Cursor, Read, Write};
+use std::time::Duration;
+
+use crate::chunked;
+use crate::client::{Client, DEFAULT_READ_SIZE};
+use crate::connector::Stream;
+use crate::response::Response;
+use crate::url::Url;
+use crate::{build_request, parse_status_line, read_head, read_message};
+
+// How long to wait for 100 Continue before sending the body anyway; servers
+// that predate RFC 7231 never answer Expect. curl waits the same second.
+const CONTINUE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// A request body and how it is framed.
+pub enum Body<'a> {
+    Empty,
+    // Sent with Content-Length.
+    Sized(&'a mut dyn Read, u64),
+    // Sent with Transfer-Encoding: chunked, up to the reader's end.
+    Chunked(&'a mut dyn Read),
+}
+
+// Sends a `method` request with `body` and returns the final response, whatever
+// its status. A body is announced with Expect: 100-continue, so a server that
+// refuses it (401, 413, ...) does so before any of it is transferred.
+// Redirects are not followed: the body cannot be read a second time.
+pub fn send(url: &Url, client: &Client, method: &str, mut body: Body) -> io::Result<Response> {
+    let mut expect = !matches!(body, Body::Empty);
+    let mut authenticated = false;
+    loop {
+        let mut stream = client.connector.connect(url)?;
+        stream.write_all(request_head(method, url, client, &body, expect).as_bytes())?;
+        let early = match body {
+            // HEAD responses describe a body that never comes.
+            Body::Empty if method == "HEAD" => Some(Response::parse(&read_head(&mut stream)?.0, Vec::new())?),
+            Body::Empty => Some(read_final_response(&mut stream, Vec::new())?),
+            _ if expect => await_continue(&mut stream, CONTINUE_TIMEOUT, client.connector.read_timeout())?,
+            _ => None,
+        };
+        if let Some(response) = early {
+            client.handle_response(url, &response);
+            // The body is still unsent, so both can simply be retried: 417
+            // without the expectation, 401 with credentials.
+            if response.status == 417 && expect {
+                expect = false;
+                continue;
+            }
+            if response.status == 401
+                && !authenticated
+                && client.auth.challenge(url, &response.headers.get_all("www-authenticate"))
+            {
+                authenticated = true;
+                continue;
+            }
+            return Ok(response);
+        }
+        match body {
+            Body::Empty => {}
+            Body::Sized(ref mut reader, length) => {
+                let sent = io::copy(&mut reader.take(length), &mut stream)?;
+                if sent < length {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("Upload body ended after {} of {} bytes", sent, length),
+                    ));
+                }
+            }
+            Body::Chunked(ref mut reader) => {
+                chunked::encode(*reader, &mut stream)?;
+            }
+        }
+        let response = read_final_response(&mut stream, Vec::new())?;
+        client.handle_response(url, &response);
+        return Ok(response);
+    }
+}
+
+// The usual request head with the body's framing and, unless the server
+// rejected it, the expectation.
+fn request_head(method: &str, url: &Url, client: &Client, body: &Body, expect: bool) -> String {
+    let mut request = build_request(method, url, client, None, false).body(body);
+    if expect {
+        request = request.header("Expect", "100-continue");
+    }
+    request.build()
+}
+
+// Waits for the answer to Expect: 100-continue. Returns None when the body
+// should be sent, after a 100 or when nothing arrives within `timeout`, and
+// the complete response when the server answers early with a final status.
+// Either way the connection's own `read_timeout` applies again after.
+fn await_continue(stream: &mut Stream, timeout: Duration, read_timeout: Option<Duration>) -> io::Result<Option<Response>> {
+    stream.set_read_timeout(Some(timeout))?;
+    let mut buffer = Vec::new();
+    loop {
+        if let Some(pos) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            let code = parse_status_line(&buffer)?.code;
+            if code == 100 {
+                stream.set_read_timeout(read_timeout)?;
+                return Ok(None);
+            }
+            if (101..200).contains(&code) {
+                // Other interim responses, such as 103 Early Hints.
+                buffer.drain(..pos + 4);
+                continue;
+            }
+            stream.set_read_timeout(read_timeout)?;
+            return read_final_response(stream, buffer).map(Some);
+        }
+        let mut temp = [0; 4096];
+        match stream.read(&mut temp) {
+            Ok(0) => {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed before the upload"));
+            }
+            // Once a response has begun, the rest of it is waited for.
+            Ok(n) => {
+                buffer.extend_from_slice(&temp[..n]);
+                stream.set_read_timeout(read_timeout)?;
+            }
+            Err(e) if buffer.is_empty() && matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                stream.set_read_timeout(read_timeout)?;
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Reads the final response that follows `buffered`, skipping interim 1xx
+// responses such as a 100 Continue that arrived after the body was sent.
+pub fn read_final_response<R: Read>(stream: &mut R, mut buffered: Vec<u8>) -> io::Result<Response> {
+    loop {
+        let mut reader = Cursor::new(buffered).chain(&mut *stream);
+        let (head, rest) = read_head(&mut reader)?;
+        if parse_status_line(&head)?.code / 100 != 1 {
+            return read_message(&mut Cursor::new([head, rest].concat()).chain(reader), DEFAULT_READ_SIZE).map(|(response, _)| response);
+        }
+        let (unread, _) = reader.into_inner();
+        let position = unread.position() as usize;
+        buffered = [rest, unread.into_inner().split_off(position)].concat();
+    }
+}
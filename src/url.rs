@@ -0,0 +1,173 @@
+use std::fmt;
+use std::io;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Url {
+    pub scheme: String,
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+    pub query: Option<String>,
+}
+
+impl Url {
+    pub fn parse(input: &str) -> io::Result<Url> {
+        let (scheme, rest) = input.split_once("://").ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("URL is missing a scheme: {}", input))
+        })?;
+        let scheme = scheme.to_lowercase();
+        let default_port = default_port_for(&scheme).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("Unsupported URL scheme: {}", scheme))
+        })?;
+
+        // Fragments are never sent to the server.
+        let rest = rest.split('#').next().unwrap_or("");
+        let authority_end = rest.find(['/', '?']).unwrap_or(rest.len());
+        let (authority, target) = rest.split_at(authority_end);
+
+        let (host, port) = split_host_port(authority)?;
+        let port = port.unwrap_or(default_port);
+        if host.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("URL is missing a host: {}", input)));
+        }
+
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, Some(query.to_string())),
+            None => (target, None),
+        };
+        let path = if path.is_empty() { "/".to_string() } else { path.to_string() };
+
+        Ok(Url { scheme, host: host.to_string(), port, path, query })
+    }
+
+    pub fn default_port(&self) -> u16 {
+        default_port_for(&self.scheme).unwrap_or(0)
+    }
+
+    // The host as written in a URL authority: IPv6 literals are bracketed.
+    pub fn authority_host(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        }
+    }
+
+    // host:port, as in CONNECT requests and connection keys.
+    pub fn socket_addr(&self) -> String {
+        format!("{}:{}", self.authority_host(), self.port)
+    }
+
+    // Value of the Host header; the port is omitted when it is the scheme default.
+    pub fn host_header(&self) -> String {
+        if self.port == self.default_port() {
+            self.authority_host()
+        } else {
+            self.socket_addr()
+        }
+    }
+
+    // scheme://host:port, identifying which connections can be shared.
+    pub fn origin(&self) -> String {
+        format!("{}://{}", self.scheme, self.socket_addr())
+    }
+
+    // Resolves a Location header value against this URL (RFC 3986 section 5,
+    // without dot-segment removal).
+    pub fn join(&self, reference: &str) -> io::Result<Url> {
+        let reference = reference.split('#').next().unwrap_or("");
+        if reference.contains("://") {
+            return Url::parse(reference);
+        }
+        if let Some(rest) = reference.strip_prefix("//") {
+            return Url::parse(&format!("{}://{}", self.scheme, rest));
+        }
+
+        let (path, query) = match reference.split_once('?') {
+            Some((path, query)) => (path, Some(query.to_string())),
+            None => (reference, None),
+        };
+        let path = if path.is_empty() {
+            self.path.clone()
+        } else if path.starts_with('/') {
+            path.to_string()
+        } else {
+            let directory = &self.path[..self.path.rfind('/').map_or(0, |i| i + 1)];
+            format!("{}{}", directory, path)
+        };
+        let query = if reference.is_empty() { self.query.clone() } else { query };
+        Ok(Url { path, query, ..self.clone() })
+    }
+
+    // Path and query as sent on the request line.
+    pub fn request_target(&self) -> String {
+        match &self.query {
+            Some(query) => format!("{}?{}", self.path, query),
+            None => self.path.clone(),
+        }
+    }
+}
+
+// Splits "host[:port]" where the host may be a bracketed IPv6 literal, which
+// is returned without its brackets.
+pub fn split_host_port(authority: &str) -> io::Result<(&str, Option<u16>)> {
+    let invalid_port = |port: &str| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid port in URL: {}", port))
+    };
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(bracketed) => {
+            let (host, rest) = bracketed.split_once(']').ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("Unterminated IPv6 address: {}", authority))
+            })?;
+            host.parse::<std::net::Ipv6Addr>().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid IPv6 address: {}", host))
+            })?;
+            match rest {
+                "" => (host, None),
+                _ => (host, Some(rest.strip_prefix(':').ok_or_else(|| invalid_port(rest))?)),
+            }
+        }
+        None => match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        },
+    };
+    let port = port.map(|port| port.parse::<u16>().map_err(|_| invalid_port(port))).transpose()?;
+    Ok((host, port))
+}
+
+// Undoes %XX escapes, as in proxy userinfo and path segments; a '%' not
+// followed by two hex digits is kept as is.
+pub fn percent_decode(input: &str) -> Vec<u8> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = input.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
+
+fn default_port_for(scheme: &str) -> Option<u16> {
+    match scheme {
+        "http" => Some(80),
+        "https" => Some(443),
+        _ => None,
+    }
+}
+
+impl fmt::Display for Url {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}://{}{}", self.scheme, self.host_header(), self.request_target())
+    }
+}
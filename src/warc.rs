@@ -0,0 +1,138 @@
+use std::collections::hash_map::RandomState;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+// A WARC 1.1 archive of a run for --warc: a warcinfo record, then a request
+// and a response record for every exchange, range requests included, as they
+// went over the wire. Ending the path in .gz compresses each record as a gzip
+// member of its own, as .warc.gz readers expect.
+pub struct Warc {
+    file: BufWriter<File>,
+    gzip: bool,
+}
+
+impl Warc {
+    pub fn create(path: &Path) -> io::Result<Warc> {
+        let file = File::create(path).map_err(|e| io::Error::new(e.kind(), format!("Cannot create {}: {}", path.display(), e)))?;
+        let gzip = path.extension().is_some_and(|extension| extension == "gz");
+        let mut warc = Warc { file: BufWriter::new(file), gzip };
+        let info = format!("software: http_client/{}\r\nformat: WARC File Format 1.1\r\n", env!("CARGO_PKG_VERSION"));
+        warc.record(&[("WARC-Type", "warcinfo"), ("Content-Type", "application/warc-fields")], info.as_bytes())?;
+        warc.file.flush()?;
+        Ok(warc)
+    }
+
+    // The request and response records of one exchange with `origin`, named
+    // by the target of its first request line.
+    pub fn exchange(&mut self, origin: &str, request: &[u8], response: &[u8]) -> io::Result<()> {
+        let uri = target_uri(origin, request);
+        let request_id = self.record(
+            &[("WARC-Type", "request"), ("WARC-Target-URI", &uri), ("Content-Type", "application/http;msgtype=request")],
+            request,
+        )?;
+        if !response.is_empty() {
+            self.record(
+                &[
+                    ("WARC-Type", "response"),
+                    ("WARC-Target-URI", &uri),
+                    ("WARC-Concurrent-To", &request_id),
+                    ("Content-Type", "application/http;msgtype=response"),
+                ],
+                response,
+            )?;
+        }
+        self.file.flush()
+    }
+
+    // Writes a record with `fields` after the ones every record has, and
+    // returns its ID.
+    fn record(&mut self, fields: &[(&str, &str)], block: &[u8]) -> io::Result<String> {
+        let id = record_id();
+        let mut head = format!("WARC/1.1\r\nWARC-Record-ID: {}\r\nWARC-Date: {}\r\n", id, warc_date(SystemTime::now()));
+        for (name, value) in fields {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str(&format!("Content-Length: {}\r\n\r\n", block.len()));
+        if self.gzip {
+            let mut member = GzEncoder::new(&mut self.file, Compression::default());
+            write_record(&mut member, &head, block)?;
+            member.finish()?;
+        } else {
+            write_record(&mut self.file, &head, block)?;
+        }
+        Ok(id)
+    }
+}
+
+fn write_record(out: &mut impl Write, head: &str, block: &[u8]) -> io::Result<()> {
+    out.write_all(head.as_bytes())?;
+    out.write_all(block)?;
+    out.write_all(b"\r\n\r\n")
+}
+
+// The URI the first request line of `request` asks `origin` for; an absolute
+// target, as sent to a forward proxy, is already one.
+fn target_uri(origin: &str, request: &[u8]) -> String {
+    let request = String::from_utf8_lossy(request);
+    let target = request.lines().next().and_then(|line| line.split(' ').nth(1)).unwrap_or("/");
+    if target.starts_with('/') {
+        format!("{}{}", origin, target)
+    } else if target.contains("://") {
+        target.to_string()
+    } else {
+        format!("{}/", origin)
+    }
+}
+
+// A random (version 4) UUID URN.
+fn record_id() -> String {
+    let state = RandomState::new();
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos());
+    let mut bytes = [0u8; 16];
+    for (i, half) in bytes.chunks_mut(8).enumerate() {
+        let mut hasher = state.build_hasher();
+        hasher.write_u128(nanos);
+        hasher.write_usize(i);
+        half.copy_from_slice(&hasher.finish().to_be_bytes());
+    }
+    bytes[6] = bytes[6] & 0x0f | 0x40;
+    bytes[8] = bytes[8] & 0x3f | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("<urn:uuid:{}-{}-{}-{}-{}>", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+// `time` as WARC-Date has it, e.g. 2026-10-16T09:30:00Z.
+pub fn warc_date(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()) as i64;
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+    let second_of_day = seconds.rem_euclid(86400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        second_of_day / 3600,
+        second_of_day / 60 % 60,
+        second_of_day % 60
+    )
+}
+
+// The proleptic Gregorian date `days` after 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
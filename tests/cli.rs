@@ -0,0 +1,228 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::process::Command;
+use std::thread;
+
+// Serves `body` to one request, then closes.
+fn serve_once(body: &'static [u8]) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+            line.clear();
+        }
+        let mut stream = reader.into_inner();
+        write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len()).unwrap();
+        stream.write_all(body).unwrap();
+    });
+    format!("http://{}/file", addr)
+}
+
+// Serves `body` with byte ranges to any number of requests, a connection
+// each.
+fn serve_ranges(body: &'static [u8]) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            thread::spawn(move || {
+                let mut reader = BufReader::new(stream);
+                let mut request = String::new();
+                reader.read_line(&mut request).unwrap();
+                let mut range = None;
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+                    if let Some(value) = line.to_ascii_lowercase().strip_prefix("range: bytes=") {
+                        let (first, last) = value.trim().split_once('-').unwrap();
+                        let first: usize = first.parse().unwrap();
+                        let last = last.parse().map_or(body.len() - 1, |last: usize| last.min(body.len() - 1));
+                        range = Some((first, last));
+                    }
+                    line.clear();
+                }
+                let mut stream = reader.into_inner();
+                let (status, bytes) = match range {
+                    Some((first, last)) => (format!("206 Partial Content\r\nContent-Range: bytes {}-{}/{}", first, last, body.len()), &body[first..=last]),
+                    None => ("200 OK".to_string(), body),
+                };
+                write!(stream, "HTTP/1.1 {}\r\nContent-Length: {}\r\nAccept-Ranges: bytes\r\nConnection: close\r\n\r\n", status, bytes.len()).unwrap();
+                if !request.starts_with("HEAD") {
+                    stream.write_all(bytes).unwrap();
+                }
+            });
+        }
+    });
+    format!("http://{}/file", addr)
+}
+
+// The body is all `get` writes to stdout; the status line and the rest of
+// the log go to stderr.
+#[test]
+fn get_writes_only_the_body_to_stdout() {
+    let body: &[u8] = b"line one\nline two\n";
+    let url = serve_once(body);
+    let output = Command::new(env!("CARGO_BIN_EXE_http_client")).args(["get", &url]).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(output.stdout, body);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("200 OK"));
+}
+
+// With -o - the data goes to stdout in order, and only the data, however many
+// threads fetch it.
+#[test]
+fn download_to_stdout_writes_only_the_data() {
+    let body: &'static [u8] = Box::leak((0..200_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>().into_boxed_slice());
+    let url = serve_ranges(body);
+    let output = Command::new(env!("CARGO_BIN_EXE_http_client"))
+        .args(["download", "--chunk-size", "16K", "-o", "-", &url, "4"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(output.stdout, body);
+}
+
+// Just enough of JSON to check that stdout is one valid object, and to read
+// fields from it.
+#[derive(Debug, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn parse(text: &str) -> Result<Json, String> {
+        let mut rest = text.trim_start();
+        let value = Json::value(&mut rest)?;
+        match rest.trim() {
+            "" => Ok(value),
+            extra => Err(format!("trailing {:?}", extra)),
+        }
+    }
+
+    fn get(&self, key: &str) -> &Json {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(name, _)| name == key).map(|(_, value)| value).unwrap_or(&Json::Null),
+            _ => &Json::Null,
+        }
+    }
+
+    fn value(rest: &mut &str) -> Result<Json, String> {
+        *rest = rest.trim_start();
+        let value = if let Some(after) = rest.strip_prefix('{') {
+            *rest = after;
+            let mut fields = Vec::new();
+            while !Json::eat(rest, '}') {
+                if !fields.is_empty() && !Json::eat(rest, ',') {
+                    return Err(format!("expected , at {:?}", rest));
+                }
+                let Json::String(name) = Json::value(rest)? else { return Err("expected a key".to_string()) };
+                if !Json::eat(rest, ':') {
+                    return Err(format!("expected : at {:?}", rest));
+                }
+                fields.push((name, Json::value(rest)?));
+            }
+            Json::Object(fields)
+        } else if let Some(after) = rest.strip_prefix('[') {
+            *rest = after;
+            let mut items = Vec::new();
+            while !Json::eat(rest, ']') {
+                if !items.is_empty() && !Json::eat(rest, ',') {
+                    return Err(format!("expected , at {:?}", rest));
+                }
+                items.push(Json::value(rest)?);
+            }
+            Json::Array(items)
+        } else if let Some(after) = rest.strip_prefix('"') {
+            let mut text = String::new();
+            let mut chars = after.char_indices();
+            loop {
+                match chars.next().ok_or("unterminated string")? {
+                    (end, '"') => {
+                        *rest = &after[end + 1..];
+                        break;
+                    }
+                    (_, '\\') => match chars.next().ok_or("unterminated escape")?.1 {
+                        'n' => text.push('\n'),
+                        't' => text.push('\t'),
+                        'r' => text.push('\r'),
+                        'u' => {
+                            let hex: String = (0..4).filter_map(|_| chars.next().map(|(_, c)| c)).collect();
+                            let code = u32::from_str_radix(&hex, 16).map_err(|e| e.to_string())?;
+                            text.push(char::from_u32(code).ok_or("bad escape")?);
+                        }
+                        c @ ('"' | '\\' | '/') => text.push(c),
+                        c => return Err(format!("bad escape \\{}", c)),
+                    },
+                    (_, c) if c < ' ' => return Err("control character in string".to_string()),
+                    (_, c) => text.push(c),
+                }
+            }
+            Json::String(text)
+        } else {
+            let end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || "+-.".contains(c))).unwrap_or(rest.len());
+            let (word, after) = rest.split_at(end);
+            *rest = after;
+            match word {
+                "null" => Json::Null,
+                "true" => Json::Bool(true),
+                "false" => Json::Bool(false),
+                number => Json::Number(number.parse().map_err(|_| format!("unexpected {:?}", number))?),
+            }
+        };
+        Ok(value)
+    }
+
+    fn eat(rest: &mut &str, c: char) -> bool {
+        *rest = rest.trim_start();
+        match rest.strip_prefix(c) {
+            Some(after) => {
+                *rest = after;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// --json keeps stdout one JSON object with --piece-hashes, which lists the
+// pieces in it as well as in the manifest next to the file.
+#[test]
+fn json_summary_lists_the_pieces() {
+    let body: &'static [u8] = Box::leak((0..10_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>().into_boxed_slice());
+    let url = serve_ranges(body);
+    let path = std::env::temp_dir().join(format!("http_client_cli_{}.json_pieces", std::process::id()));
+    let output = Command::new(env!("CARGO_BIN_EXE_http_client"))
+        .args(["download", "--json", "--piece-hashes", "4K", "-o", path.to_str().unwrap(), &url, "2"])
+        .output()
+        .unwrap();
+    let manifest = std::fs::read_to_string(format!("{}.pieces", path.display()));
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(format!("{}.pieces", path.display()));
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let summary = Json::parse(&String::from_utf8(output.stdout).unwrap()).unwrap();
+    assert_eq!(summary.get("total_size"), &Json::Number(10_000.0));
+    let Json::Array(pieces) = summary.get("pieces") else { panic!("no pieces in {:?}", summary) };
+    let lengths: Vec<_> = pieces.iter().map(|piece| (piece.get("offset"), piece.get("length"))).collect();
+    assert_eq!(
+        lengths,
+        [
+            (&Json::Number(0.0), &Json::Number(4096.0)),
+            (&Json::Number(4096.0), &Json::Number(4096.0)),
+            (&Json::Number(8192.0), &Json::Number(1808.0)),
+        ]
+    );
+    // The same digests as the manifest.
+    for (piece, line) in pieces.iter().zip(manifest.unwrap().lines().skip(1)) {
+        let Json::String(sha256) = piece.get("sha256") else { panic!("no sha256 in {:?}", piece) };
+        assert!(line.ends_with(sha256.as_str()), "{} vs {}", line, sha256);
+    }
+}